@@ -1,36 +1,176 @@
 use crate::{
     auth,
     data::current,
-    db,
+    db, deidentify,
     email::{self, Email},
-    error, redcap,
+    error,
+    openapi::{self, Route},
+    ratelimit::Limiter,
+    redcap,
 };
+use schemars::JsonSchema;
+use serde::Serialize;
 use serde_derive::Deserialize;
-use std::convert::Infallible;
+use std::convert::{Infallible, TryInto};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use warp::{
     http::{Method, StatusCode},
     Filter, Rejection, Reply,
 };
 
-type Db = Arc<Mutex<db::Db>>;
+type Db = Arc<db::shared::SharedDb>;
 type Mailer = Arc<email::Mailer>;
 type Opt = Arc<crate::Opt>;
 
+/// Window the limits on sending tokens apply to
+const TOKEN_SEND_WINDOW: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Every route under `/api`, `routes` serves them and `openapi::spec` describes them
+pub(crate) static ROUTES: &[Route] = &[
+    // Users
+    Route::new("get", "/users", "Every user").json::<Vec<current::User>>(),
+    Route::new("post", "/users", "Add manual users").body::<Vec<ManualUser>>(),
+    Route::new(
+        "put",
+        "/users",
+        "Change a user, only capabilities of REDCap users",
+    )
+    .body::<ManualUser>(),
+    Route::new("delete", "/users", "Remove a manual user").query::<RemoveUserQuery>(),
+    Route::new("put", "/users/redcap/sync", "Sync users with REDCap").json::<current::SyncRecord>(),
+    // Records
+    Route::new("get", "/sync-history", "Every sync").json::<Vec<current::SyncRecord>>(),
+    Route::new("get", "/history/{table}/{pk}", "Changes to one row")
+        .json::<Vec<current::HistoryEntry>>(),
+    Route::new("get", "/snapshots", "Snapshots that can be restored")
+        .json::<Vec<db::snapshot::SnapshotInfo>>(),
+    Route::new("get", "/snapshots/{id}/diff", "Changes since a snapshot")
+        .json::<Vec<db::diff::TableDiff>>(),
+    Route::new(
+        "put",
+        "/snapshots/{id}/restore",
+        "Restore tables from a snapshot",
+    )
+    .query::<SnapshotRestoreQuery>(),
+    Route::new("get", "/check-quality", "Data quality issues").json::<db::TableIssues>(),
+    Route::new("get", "/integrity", "Integrity check from startup").json::<db::integrity::Report>(),
+    Route::new("get", "/openapi.json", "This document")
+        .public()
+        .json::<serde_json::Value>(),
+    // Tables
+    Route::new("get", "/participants", "Participants").json::<Vec<current::Participant>>(),
+    Route::new(
+        "put",
+        "/participants/redcap/sync",
+        "Sync participants with REDCap",
+    )
+    .json::<current::SyncRecord>(),
+    Route::new("get", "/vaccination", "Vaccination history")
+        .json::<Vec<current::VaccinationHistory>>(),
+    Route::new(
+        "put",
+        "/vaccination/redcap/sync",
+        "Sync vaccination history with REDCap",
+    )
+    .json::<current::SyncRecord>(),
+    Route::new("get", "/schedule", "Schedule").json::<Vec<current::Schedule>>(),
+    Route::new("put", "/schedule/redcap/sync", "Sync schedule with REDCap")
+        .json::<current::SyncRecord>(),
+    Route::new("get", "/weekly-survey", "Weekly surveys").json::<Vec<current::WeeklySurvey>>(),
+    Route::new(
+        "put",
+        "/weekly-survey/redcap/sync",
+        "Sync weekly surveys with REDCap",
+    )
+    .json::<current::SyncRecord>(),
+    Route::new("get", "/withdrawn", "Withdrawals").json::<Vec<current::Withdrawn>>(),
+    Route::new(
+        "put",
+        "/withdrawn/redcap/sync",
+        "Sync withdrawals with REDCap",
+    )
+    .json::<current::SyncRecord>(),
+    Route::new("get", "/virus", "Viruses").json::<Vec<current::Virus>>(),
+    Route::new("get", "/serology", "Serology").json::<Vec<current::Serology>>(),
+    Route::new("get", "/consent", "Consent").json::<Vec<current::Consent>>(),
+    Route::new("put", "/consent/redcap/sync", "Sync consent with REDCap")
+        .json::<current::SyncRecord>(),
+    Route::new("get", "/year-change", "Year changes").json::<Vec<current::YearChange>>(),
+    Route::new(
+        "put",
+        "/year-change/redcap/sync",
+        "Sync year changes with REDCap",
+    )
+    .json::<current::SyncRecord>(),
+    Route::new("get", "/bleed", "Bleeds").json::<Vec<current::Bleed>>(),
+    Route::new("put", "/bleed/redcap/sync", "Sync bleeds with REDCap")
+        .json::<current::SyncRecord>(),
+    // Auth
+    Route::new("get", "/auth/token/verify", "User the token belongs to").json::<current::User>(),
+    Route::new(
+        "post",
+        "/auth/token/send",
+        "Email a login link or an API token",
+    )
+    .public()
+    .query::<TokenSendQuery>(),
+    Route::new(
+        "post",
+        "/auth/token/exchange",
+        "Start a session with the login code as the bearer token",
+    )
+    .text(),
+    Route::new("put", "/auth/token", "Replace a session token").text(),
+    Route::new("delete", "/auth/token", "Log out the token"),
+    Route::new(
+        "delete",
+        "/auth/token/user/session",
+        "Log out every session of a user",
+    )
+    .query::<TokenOwner>(),
+    Route::new(
+        "get",
+        "/auth/tokens",
+        "Tokens of a user that haven't expired",
+    )
+    .query::<TokenOwner>()
+    .json::<Vec<db::TokenInfo>>(),
+    Route::new(
+        "post",
+        "/auth/tokens/purge",
+        "Remove expired and orphaned tokens",
+    )
+    .json::<db::TokenPurge>(),
+    Route::new("delete", "/auth/tokens/{id}", "Revoke a token"),
+];
+
 pub fn routes(
     db: Db,
     opt: Opt,
     mailer: Mailer,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let base_routes = get_users(db.clone())
-        .or(get_bleed(db.clone()))
+    // Grouped so that the filter types don't nest deeper than the compiler allows
+    let user_routes = get_users(db.clone())
+        .or(add_users(db.clone()))
+        .or(update_user(db.clone()))
+        .or(remove_user(db.clone()))
+        .or(users_redcap_sync(db.clone(), opt.clone()));
+
+    let record_routes = get_sync_history(db.clone())
+        .or(get_history(db.clone()))
+        .or(get_snapshots(db.clone()))
+        .or(snapshot_diff(db.clone()))
+        .or(snapshot_restore(db.clone()))
+        .or(check_quality(db.clone()))
+        .or(get_integrity(db.clone()))
+        .or(get_openapi());
+
+    let table_routes = get_bleed(db.clone())
         .or(bleed_redcap_sync(db.clone(), opt.clone()))
         .or(get_year_change(db.clone()))
         .or(year_change_redcap_sync(db.clone(), opt.clone()))
         .or(get_consent(db.clone()))
         .or(consent_redcap_sync(db.clone(), opt.clone()))
-        .or(check_quality(db.clone()))
         .or(get_virus(db.clone()))
         .or(get_serology(db.clone()))
         .or(get_withdrawn(db.clone()))
@@ -42,11 +182,22 @@ pub fn routes(
         .or(get_vaccination_history(db.clone()))
         .or(vaccination_history_redcap_sync(db.clone(), opt.clone()))
         .or(get_schedule(db.clone()))
-        .or(schedule_redcap_sync(db.clone(), opt.clone()))
-        .or(users_redcap_sync(db.clone(), opt.clone()))
-        .or(auth_token_verify(db.clone()))
+        .or(schedule_redcap_sync(db.clone(), opt.clone()));
+
+    let auth_routes = auth_token_verify(db.clone())
         .or(auth_token_send(db.clone(), opt.clone(), mailer))
-        .or(auth_token_refresh(db, opt));
+        .or(auth_token_exchange(db.clone(), opt.clone()))
+        .or(auth_token_refresh(db.clone(), opt))
+        .or(auth_token_remove(db.clone()))
+        .or(auth_token_user_sessions_remove(db.clone()))
+        .or(get_user_tokens(db.clone()))
+        .or(auth_tokens_purge(db.clone()))
+        .or(auth_token_revoke(db));
+
+    let base_routes = user_routes
+        .or(record_routes)
+        .or(table_routes)
+        .or(auth_routes);
 
     let base_routes_with_prefix = warp::path("api").and(base_routes);
 
@@ -63,6 +214,58 @@ pub fn routes(
         .with(log)
 }
 
+/// Requests for the route in `ROUTES` with the method and path,
+/// extracts the path parameters in order
+fn route_params<const N: usize>(
+    method: &'static str,
+    path: &'static str,
+) -> impl Filter<Extract = ([String; N],), Error = Rejection> + Clone {
+    let route = ROUTES
+        .iter()
+        .find(|r| r.method == method && r.path == path)
+        .unwrap_or_else(|| panic!("{} {} is missing from ROUTES", method, path));
+    let segments: Arc<Vec<&'static str>> = Arc::new(route.path[1..].split('/').collect());
+    let params = segments.iter().filter(|s| s.starts_with('{')).count();
+    assert_eq!(params, N, "{} {} has {} parameters", method, path, params);
+    let method = match route.method {
+        "get" => warp::get().boxed(),
+        "post" => warp::post().boxed(),
+        "put" => warp::put().boxed(),
+        "delete" => warp::delete().boxed(),
+        other => panic!("{} {} has an unknown method", other, path),
+    };
+    warp::path::tail()
+        .and_then(move |tail: warp::path::Tail| {
+            let segments = segments.clone();
+            async move {
+                let given: Vec<&str> = tail.as_str().split('/').collect();
+                if given.len() != segments.len() {
+                    return Err(warp::reject::not_found());
+                }
+                let mut params = Vec::with_capacity(N);
+                for (segment, given) in segments.iter().zip(given) {
+                    if segment.starts_with('{') {
+                        params.push(given.to_string());
+                    } else if *segment != given {
+                        return Err(warp::reject::not_found());
+                    }
+                }
+                params.try_into().map_err(|_| warp::reject::not_found())
+            }
+        })
+        .and(method)
+}
+
+/// Requests for the route in `ROUTES` without path parameters
+fn route(
+    method: &'static str,
+    path: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    route_params::<0>(method, path)
+        .map(|_: [String; 0]| ())
+        .untuple_one()
+}
+
 fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
@@ -75,6 +278,32 @@ fn with_opt(opt: Opt) -> impl Filter<Extract = (Opt,), Error = Infallible> + Clo
     warp::any().map(move || opt.clone())
 }
 
+/// Address of whoever made the request. The last address in
+/// `X-Forwarded-For` is only used when the request comes from one of the
+/// trusted proxies, anyone else could put whatever they like there.
+fn client_address(
+    trusted_proxies: Vec<std::net::IpAddr>,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("X-Forwarded-For")
+        .and(warp::addr::remote())
+        .map(
+            move |forwarded: Option<String>, remote: Option<std::net::SocketAddr>| {
+                let remote = remote.map(|r| r.ip());
+                let forwarded = forwarded
+                    .filter(|_| remote.is_some_and(|r| trusted_proxies.contains(&r)))
+                    .as_deref()
+                    .and_then(|f| f.rsplit(',').next())
+                    .map(|a| a.trim().to_string())
+                    .filter(|a| !a.is_empty());
+                match (forwarded, remote) {
+                    (Some(forwarded), _) => forwarded,
+                    (None, Some(remote)) => remote.to_string(),
+                    (None, None) => "unknown".to_string(),
+                }
+            },
+        )
+}
+
 fn auth_header() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
     warp::header::<String>("Authorization").and_then(move |tok_raw: String| async move {
         match auth::parse_bearer_header(tok_raw.as_str()) {
@@ -84,34 +313,81 @@ fn auth_header() -> impl Filter<Extract = (String,), Error = Rejection> + Clone
     })
 }
 
+/// Rejects requests for tables quarantined by the integrity check
+fn available_table(
+    db: Db,
+    table: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_db(db)
+        .and_then(move |db: Db| async move {
+            match db.integrity().check_available(table) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(reject(e)),
+            }
+        })
+        .untuple_one()
+}
+
 fn reply_no_content() -> impl warp::Reply {
     warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT)
 }
 
-/// Makes sure all rejections are HttpApiProblem
+/// Rows deidentified if there are pseudonyms to do it with, see `db::shared::SharedDb::pseudonyms_for`
+fn reply_rows<T: Serialize>(
+    pseudonyms: Option<&deidentify::Pseudonyms>,
+    rows: &[T],
+) -> Result<warp::reply::Json, Rejection> {
+    match pseudonyms.map(|p| p.rows(rows)) {
+        None => Ok(warp::reply::json(&rows)),
+        Some(Ok(rows)) => Ok(warp::reply::json(&rows)),
+        Some(Err(e)) => Err(reject(e)),
+    }
+}
+
+/// Sync record with the keys deidentified if there are pseudonyms to do it with
+fn reply_sync_record(
+    pseudonyms: Option<&deidentify::Pseudonyms>,
+    record: &current::SyncRecord,
+) -> warp::reply::Json {
+    match pseudonyms {
+        Some(p) => warp::reply::json(&p.sync_record(record)),
+        None => warp::reply::json(record),
+    }
+}
+
+/// Makes sure all rejections are ApiProblem
 fn reject(e: anyhow::Error) -> Rejection {
     warp::reject::custom(error::from_anyhow(e))
 }
 
-/// Expect all rejections to be HttpApiProblem
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+/// Every error goes out as problem+json, see `error::ApiProblem`
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     log::debug!("recover filter error: {:?}", err);
 
-    if let Some(e) = err.find::<error::ApiProblem>() {
-        let reply = warp::reply::with_status(e.detail.clone(), e.status);
-        return Ok(reply);
-    }
-
-    Err(err)
+    let problem = match err.find::<error::ApiProblem>() {
+        Some(problem) => problem,
+        None => &error::from_rejection(&err),
+    };
+    let reply = warp::reply::with_header(
+        warp::reply::json(problem),
+        "Content-Type",
+        "application/problem+json",
+    );
+    Ok(warp::reply::with_status(reply, problem.status))
 }
 
-fn user_from_token(
+/// User the token belongs to if the token has the scope
+fn user_with_scope(
     db: Db,
+    scope: current::Scope,
 ) -> impl Filter<Extract = (current::User,), Error = warp::Rejection> + Clone {
     auth_header()
         .and(with_db(db))
         .and_then(move |tok: String, db: Db| async move {
-            match db.lock().await.token_verify(tok.as_str()) {
+            match db
+                .read()
+                .token_verify_scope(db.token_hasher(), tok.as_str(), scope)
+            {
                 Ok(u) => Ok(u),
                 Err(e) => Err(reject(e)),
             }
@@ -121,8 +397,9 @@ fn user_from_token(
 fn sufficient_access(
     db: Db,
     req_access: current::AccessGroup,
+    scope: current::Scope,
 ) -> impl Filter<Extract = (current::User,), Error = warp::Rejection> + Clone {
-    user_from_token(db).and_then(move |u: current::User| async move {
+    user_with_scope(db, scope).and_then(move |u: current::User| async move {
         if u.access_group < req_access {
             return Err(reject(anyhow::Error::new(
                 error::Unauthorized::InsufficientAccess(u.access_group, req_access),
@@ -132,19 +409,34 @@ fn sufficient_access(
     })
 }
 
+/// User the token belongs to if the user has the capability and the token the scope
+fn capable(
+    db: Db,
+    scope: current::Scope,
+    capability: current::Capability,
+) -> impl Filter<Extract = (current::User,), Error = warp::Rejection> + Clone {
+    user_with_scope(db, scope).and_then(move |u: current::User| async move {
+        if !u.can(capability) {
+            return Err(reject(anyhow::Error::new(
+                error::Unauthorized::MissingCapability(capability),
+            )));
+        }
+        Ok(u)
+    })
+}
+
 // Tokens =========================================================================================
 
 fn auth_token_refresh(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("auth" / "token")
-        .and(warp::put())
+    route("put", "/auth/token")
         .and(auth_header())
         .and(with_db(db))
         .and(with_opt(opt))
         .and_then(move |old_token: String, db: Db, opt: Opt| async move {
-            match db.lock().await.token_refresh(
+            match db.write().await.token_refresh(
                 old_token.as_str(),
                 opt.auth_token_length,
                 opt.auth_token_days_to_live,
@@ -155,80 +447,321 @@ fn auth_token_refresh(
         })
 }
 
+/// Starts a session with the login code from an access link
+fn auth_token_exchange(
+    db: Db,
+    opt: Opt,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("post", "/auth/token/exchange")
+        .and(auth_header())
+        .and(with_db(db))
+        .and(with_opt(opt))
+        .and_then(move |code: String, db: Db, opt: Opt| async move {
+            match db.write().await.login_code_exchange(
+                code.as_str(),
+                opt.auth_token_length,
+                opt.auth_token_days_to_live,
+                opt.auth_max_sessions,
+            ) {
+                Ok(token) => Ok(token),
+                Err(e) => Err(reject(e)),
+            }
+        })
+}
+
+/// What to send by email
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct TokenSendQuery {
+    email: String,
+    kind: current::TokenKind,
+    /// API tokens don't expire if this is missing. Sessions get a login code
+    /// that expires in `auth_login_code_minutes` minutes instead.
+    days_to_live: Option<i64>,
+    /// Comma-separated scopes of an API token, every scope if missing.
+    /// Sessions always have every scope.
+    scopes: Option<String>,
+}
+
+impl TokenSendQuery {
+    fn scopes(&self) -> crate::Result<Vec<current::Scope>> {
+        match &self.scopes {
+            Some(scopes) => scopes
+                .split(',')
+                .map(|scope| {
+                    serde_json::from_value(serde_json::Value::from(scope.trim())).map_err(|_| {
+                        anyhow::Error::new(error::BadRequest::UnknownScope(scope.into()))
+                    })
+                })
+                .collect(),
+            None => Ok(current::Scope::ALL.to_vec()),
+        }
+    }
+}
+
 fn auth_token_send(
     db: Db,
     opt: Opt,
     mailer: Mailer,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    #[derive(Deserialize)]
-    struct Query {
-        email: String,
-        kind: current::TokenKind,
-    }
-    warp::path!("auth" / "token" / "send")
-        .and(warp::post())
+    // Kept for as long as the server runs, routes are only made once
+    let per_email = Arc::new(Limiter::new(
+        opt.auth_token_send_per_email,
+        TOKEN_SEND_WINDOW,
+    ));
+    let per_address = Arc::new(Limiter::new(
+        opt.auth_token_send_per_address,
+        TOKEN_SEND_WINDOW,
+    ));
+    route("post", "/auth/token/send")
         .and(warp::query())
+        .and(client_address(opt.trusted_proxies.clone()))
         .and(with_db(db))
         .and(with_opt(opt))
         .and(with_mailer(mailer))
         .and_then(
-            move |query: Query, db: Db, opt: Opt, mailer: Mailer| async move {
-                let (before_hash, token) = current::Token::new(
-                    query.email.to_lowercase().as_str(),
-                    query.kind,
-                    opt.auth_token_length,
-                    opt.auth_token_days_to_live,
-                );
-                match db.lock().await.insert_token(token) {
-                    Ok(()) => {}
-                    Err(e) => return Err(reject(e)),
-                }
-                let content = match query.kind {
-                    current::TokenKind::Session => {
-                        let link = format!("{}/?token={}", opt.frontend_root, before_hash);
-                        format!("<a href={0}>{0}</a>", link)
+            move |query: TokenSendQuery, address: String, db: Db, opt: Opt, mailer: Mailer| {
+                let per_email = per_email.clone();
+                let per_address = per_address.clone();
+                async move {
+                    let scopes = match query.scopes() {
+                        Ok(scopes) => scopes,
+                        Err(e) => return Err(reject(e)),
+                    };
+                    let email = query.email.to_lowercase();
+                    if !per_address.hit(address.as_str()) {
+                        return Err(reject(anyhow::Error::new(error::TooManyRequests::Address(
+                            address,
+                        ))));
                     }
-                    current::TokenKind::Api => before_hash,
-                };
-                let title = match query.kind {
-                    current::TokenKind::Session => "access link",
-                    current::TokenKind::Api => "API token",
-                };
-                let email = Email {
-                    to: query.email,
-                    subject: format!("NIH HCW Study {}", title),
-                    body: format!("<p>NIH HCW Flu study {}:</p><br/>{}", title, content),
-                };
-                match email.send(mailer).await {
-                    Ok(()) => Ok(reply_no_content()),
-                    Err(e) => Err(reject(e)),
+                    if !per_email.hit(email.as_str()) {
+                        return Err(reject(anyhow::Error::new(error::TooManyRequests::Email(
+                            email,
+                        ))));
+                    }
+                    // Replied to before anything is looked up so that users can't be
+                    // found by how long it takes to ask for their tokens
+                    tokio::spawn(async move {
+                        let to = email.clone();
+                        let sent = send_token(
+                            db,
+                            opt,
+                            mailer,
+                            email,
+                            query.kind,
+                            query.days_to_live,
+                            scopes,
+                        );
+                        if let Err(e) = sent.await {
+                            log::error!("failed to send token to {}: {:#}", to, e);
+                        }
+                    });
+                    Ok(reply_no_content())
                 }
             },
         )
 }
 
+/// Makes the token and emails it, does nothing for unknown users
+async fn send_token(
+    db: Db,
+    opt: Opt,
+    mailer: Mailer,
+    email: String,
+    kind: current::TokenKind,
+    days_to_live: Option<i64>,
+    scopes: Vec<current::Scope>,
+) -> crate::Result<()> {
+    if db.read().users.lookup(&email).is_none() {
+        log::info!("token requested for unknown user {}", email);
+        return Ok(());
+    }
+    // Sessions start from an emailed login code, the session token
+    // itself is only handed out when the code is exchanged
+    let kind = match kind {
+        current::TokenKind::Api => current::TokenKind::Api,
+        current::TokenKind::Session | current::TokenKind::Login => current::TokenKind::Login,
+    };
+    let days_to_live = match kind {
+        current::TokenKind::Api => days_to_live,
+        _ => None,
+    };
+    let (before_hash, mut token) = current::Token::new(
+        email.as_str(),
+        kind,
+        opt.auth_token_length,
+        days_to_live,
+        &scopes,
+        db.token_hasher(),
+    );
+    if kind == current::TokenKind::Login {
+        token.expires =
+            Some(chrono::Utc::now() + chrono::Duration::minutes(opt.auth_login_code_minutes));
+    }
+    match kind {
+        current::TokenKind::Login => db
+            .write()
+            .await
+            .insert_login_code(token, opt.auth_max_sessions)?,
+        _ => db.write().await.insert_token(token)?,
+    }
+    let (title, content) = match kind {
+        current::TokenKind::Api => ("API token", before_hash),
+        _ => {
+            let link = format!("{}/?code={}", opt.frontend_root, before_hash);
+            (
+                "access link",
+                format!(
+                    "<a href={0}>{0}</a><br/>The link works once within {1} minutes.",
+                    link, opt.auth_login_code_minutes
+                ),
+            )
+        }
+    };
+    let email = Email {
+        to: email,
+        subject: format!("NIH HCW Study {}", title),
+        body: format!("<p>NIH HCW Flu study {}:</p><br/>{}", title, content),
+    };
+    email.send(mailer).await
+}
+
 fn auth_token_verify(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("auth" / "token" / "verify")
-        .and(warp::get())
+    route("get", "/auth/token/verify")
         .and(auth_header())
         .and(with_db(db))
         .and_then(move |token: String, db: Db| async move {
-            match db.lock().await.token_verify(token.as_str()) {
+            match db.read().token_verify(db.token_hasher(), token.as_str()) {
                 Ok(u) => Ok(warp::reply::json(&u)),
                 Err(e) => Err(reject(e)),
             }
         })
 }
 
+/// Logs out the token in the header
+fn auth_token_remove(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("delete", "/auth/token")
+        .and(auth_header())
+        .and(with_db(db))
+        .and_then(move |token: String, db: Db| async move {
+            match db.write().await.remove_token(token.as_str()) {
+                Ok(()) => Ok(reply_no_content()),
+                Err(e) => Err(reject(e)),
+            }
+        })
+}
+
+/// Logs out every session of the user, admins can name another user
+fn auth_token_user_sessions_remove(
+    db: Db,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("delete", "/auth/token/user/session")
+        .and(warp::query())
+        .and(user_with_scope(db.clone(), current::Scope::Admin))
+        .and(with_db(db))
+        .and_then(
+            move |query: TokenOwner, u: current::User, db: Db| async move {
+                let email = match query.email(&u) {
+                    Ok(email) => email,
+                    Err(e) => return Err(reject(e)),
+                };
+                match db
+                    .write()
+                    .await
+                    .remove_tokens(email.as_str(), Some(current::TokenKind::Session))
+                {
+                    Ok(_) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+/// Tokens of the user that haven't expired, admins can name another user
+fn get_user_tokens(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("get", "/auth/tokens")
+        .and(warp::query())
+        .and(user_with_scope(db.clone(), current::Scope::Admin))
+        .and(with_db(db))
+        .and_then(
+            move |query: TokenOwner, u: current::User, db: Db| async move {
+                match query.email(&u) {
+                    Ok(email) => Ok(warp::reply::json(&db.read().user_tokens(email.as_str()))),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+/// Admins can remove the expired tokens and the ones of users that don't exist
+/// anymore, replies with how many of each were removed
+fn auth_tokens_purge(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("post", "/auth/tokens/purge")
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
+        .and(with_db(db))
+        .and_then(move |_u: current::User, db: Db| async move {
+            match db.write().await.purge_tokens() {
+                Ok(purge) => Ok(warp::reply::json(&purge)),
+                Err(e) => Err(reject(e)),
+            }
+        })
+}
+
+fn auth_token_revoke(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route_params::<1>("delete", "/auth/tokens/{id}")
+        .and(user_with_scope(db.clone(), current::Scope::Admin))
+        .and(with_db(db))
+        .and_then(
+            move |[id]: [String; 1], u: current::User, db: Db| async move {
+                match db.write().await.revoke_token(&u, id.as_str()) {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+/// Whose tokens a request is about
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct TokenOwner {
+    /// Whoever makes the request if missing, only admins can name someone else
+    email: Option<String>,
+}
+
+impl TokenOwner {
+    fn email(self, u: &current::User) -> crate::Result<String> {
+        match self.email {
+            Some(email)
+                if email.to_lowercase() != u.email
+                    && u.access_group != current::AccessGroup::Admin =>
+            {
+                Err(anyhow::Error::new(error::Unauthorized::InsufficientAccess(
+                    u.access_group,
+                    current::AccessGroup::Admin,
+                )))
+            }
+            Some(email) => Ok(email),
+            None => Ok(u.email.clone()),
+        }
+    }
+}
+
 // Users ==========================================================================================
 
 fn get_users(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     async fn handler(_u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        Ok(warp::reply::json(&db.lock().await.users.current.data))
+        Ok(warp::reply::json(&db.read().users.current.data))
     }
-    warp::path!("users")
-        .and(warp::get())
-        .and(sufficient_access(db.clone(), current::AccessGroup::Admin))
+    route("get", "/users")
+        .and(available_table(db.clone(), "User"))
+        .and(capable(
+            db.clone(),
+            current::Scope::Admin,
+            current::Capability::ManageUsers,
+        ))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -237,42 +770,148 @@ fn users_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("users" / "redcap" / "sync")
-        .and(warp::put())
-        .and(sufficient_access(db.clone(), current::AccessGroup::Admin))
+    route("put", "/users/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Admin,
+            current::Capability::ManageUsers,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
+            // Checked again by the db, this saves the export
+            if u.access_group != current::AccessGroup::Admin {
+                return Err(reject(anyhow::Error::new(
+                    error::Unauthorized::InsufficientAccess(
+                        u.access_group,
+                        current::AccessGroup::Admin,
+                    ),
+                )));
+            }
             let redcap_users = match redcap::export_users(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_users(redcap_users) {
-                Ok(()) => Ok(reply_no_content()),
+            match db.write().await.sync_redcap_users(&u, redcap_users) {
+                Ok(record) => Ok(warp::reply::json(&record)),
                 Err(e) => Err(reject(e)),
             }
         })
 }
 
+/// User as user managers add and change them. Added users are manual, REDCap
+/// users only get their capabilities changed.
+/// Managers can't give more access than they have, see `current::User::check_covers`.
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct ManualUser {
+    email: String,
+    access_group: current::AccessGroup,
+    #[serde(default)]
+    deidentified_export: bool,
+    /// Defaults for the access group if missing, see `current::Capability::defaults`
+    capabilities: Option<Vec<current::Capability>>,
+}
+
+impl From<ManualUser> for current::User {
+    fn from(u: ManualUser) -> Self {
+        let (access_group, deidentified_export) = (u.access_group, u.deidentified_export);
+        Self {
+            email: u.email,
+            access_group,
+            kind: current::UserKind::Manual,
+            deidentified_export,
+            capabilities: u.capabilities.unwrap_or_else(|| {
+                current::Capability::defaults(access_group, deidentified_export)
+            }),
+        }
+    }
+}
+
+fn add_users(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("post", "/users")
+        .and(capable(
+            db.clone(),
+            current::Scope::Admin,
+            current::Capability::ManageUsers,
+        ))
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(
+            move |u: current::User, new_users: Vec<ManualUser>, db: Db| async move {
+                let new_users = new_users.into_iter().map(current::User::from).collect();
+                match db.write().await.add_users(&u, new_users) {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+fn update_user(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("put", "/users")
+        .and(capable(
+            db.clone(),
+            current::Scope::Admin,
+            current::Capability::ManageUsers,
+        ))
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(
+            move |u: current::User, update: ManualUser, db: Db| async move {
+                match db.write().await.update_user(&u, update.into()) {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+/// Manual user to remove
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct RemoveUserQuery {
+    email: String,
+}
+
+fn remove_user(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("delete", "/users")
+        .and(warp::query())
+        .and(capable(
+            db.clone(),
+            current::Scope::Admin,
+            current::Capability::ManageUsers,
+        ))
+        .and(with_db(db))
+        .and_then(
+            move |query: RemoveUserQuery, u: current::User, db: Db| async move {
+                match db.write().await.remove_user(&u, query.email.as_str()) {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
 // Particiapants ==================================================================================
 
 fn get_participants(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let data = &db.lock().await.participants.current.data;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let data = &db.read().participants.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            Ok(warp::reply::json(
+            reply_rows(
+                pseudonyms,
                 &data
                     .iter()
                     .filter(|p| p.site == site)
                     .collect::<Vec<&current::Participant>>(),
-            ))
+            )
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("participants")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/participants")
+        .and(available_table(db.clone(), "Participant"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -281,22 +920,25 @@ fn participants_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("participants" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/participants/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let redcap_participants = match redcap::export_participants(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
             match db
-                .lock()
+                .write()
                 .await
-                .sync_redcap_participants(redcap_participants)
+                .sync_redcap_participants(u.email.as_str(), redcap_participants)
             {
-                Ok(()) => Ok(reply_no_content()),
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -305,24 +947,22 @@ fn participants_redcap_sync(
 // Vaccination history ============================================================================
 
 fn get_vaccination_history(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.vaccination_history.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::VaccinationHistory>>(),
-            ))
+            reply_rows(
+                pseudonyms,
+                &db.get_site_subset(&db.vaccination_history, site),
+            )
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("vaccination")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/vaccination")
+        .and(available_table(db.clone(), "VaccinationHistory"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -331,12 +971,15 @@ fn vaccination_history_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("vaccination" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/vaccination/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let pid_map = match redcap::export_record_id_pid_map(&opt).await {
                 Ok(map) => map,
                 Err(e) => return Err(reject(e)),
@@ -347,11 +990,11 @@ fn vaccination_history_redcap_sync(
                     Err(e) => return Err(reject(e)),
                 };
             match db
-                .lock()
+                .write()
                 .await
-                .sync_redcap_vaccination_history(redcap_vaccination_history)
+                .sync_redcap_vaccination_history(u.email.as_str(), redcap_vaccination_history)
             {
-                Ok(()) => Ok(reply_no_content()),
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -360,24 +1003,19 @@ fn vaccination_history_redcap_sync(
 // Schedule =======================================================================================
 
 fn get_schedule(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.schedule.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::Schedule>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.schedule, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("schedule")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/schedule")
+        .and(available_table(db.clone(), "Schedule"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -386,18 +1024,25 @@ fn schedule_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("schedule" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/schedule/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let redcap_schedule = match redcap::export_schedule(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_schedule(redcap_schedule) {
-                Ok(()) => Ok(reply_no_content()),
+            match db
+                .write()
+                .await
+                .sync_redcap_schedule(u.email.as_str(), redcap_schedule)
+            {
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -406,24 +1051,19 @@ fn schedule_redcap_sync(
 // Weekly survey ==================================================================================
 
 fn get_weekly_survey(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.weekly_survey.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::WeeklySurvey>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.weekly_survey, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("weekly-survey")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/weekly-survey")
+        .and(available_table(db.clone(), "WeeklySurvey"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -432,12 +1072,15 @@ fn weekly_survey_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("weekly-survey" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/weekly-survey/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let pid_map = match redcap::export_record_id_pid_map(&opt).await {
                 Ok(map) => map,
                 Err(e) => return Err(reject(e)),
@@ -447,11 +1090,11 @@ fn weekly_survey_redcap_sync(
                 Err(e) => return Err(reject(e)),
             };
             match db
-                .lock()
+                .write()
                 .await
-                .sync_redcap_weekly_survey(redcap_weekly_survey)
+                .sync_redcap_weekly_survey(u.email.as_str(), redcap_weekly_survey)
             {
-                Ok(()) => Ok(reply_no_content()),
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -460,24 +1103,19 @@ fn weekly_survey_redcap_sync(
 // Withdrawn ======================================================================================
 
 fn get_withdrawn(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.withdrawn.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::Withdrawn>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.withdrawn, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("withdrawn")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/withdrawn")
+        .and(available_table(db.clone(), "Withdrawn"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -486,12 +1124,15 @@ fn withdrawn_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("withdrawn" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/withdrawn/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let pid_map = match redcap::export_record_id_pid_map(&opt).await {
                 Ok(map) => map,
                 Err(e) => return Err(reject(e)),
@@ -500,8 +1141,12 @@ fn withdrawn_redcap_sync(
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_withdrawn(redcap_withdrawn) {
-                Ok(()) => Ok(reply_no_content()),
+            match db
+                .write()
+                .await
+                .sync_redcap_withdrawn(u.email.as_str(), redcap_withdrawn)
+            {
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -511,13 +1156,13 @@ fn withdrawn_redcap_sync(
 
 fn get_virus(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     async fn handler(_u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+        let db = db.read();
         let data = &db.virus.current.data;
         Ok(warp::reply::json(data))
     }
-    warp::path!("virus")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/virus")
+        .and(available_table(db.clone(), "Virus"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -525,24 +1170,19 @@ fn get_virus(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + C
 // Serology =======================================================================================
 
 fn get_serology(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.serology.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::Serology>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.serology, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("serology")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/serology")
+        .and(available_table(db.clone(), "Serology"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -550,39 +1190,64 @@ fn get_serology(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection>
 // Data quality ====================================================================================
 
 fn check_quality(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(user: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let mut db = db.lock().await;
-        let issues = db.find_table_issues(user.access_group);
-        Ok(warp::reply::json(&issues))
+    async fn handler(user: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        // Issues are about particular participants and can't be fixed without knowing who
+        if db.pseudonyms_for(&user).is_some() {
+            return Err(reject(anyhow::Error::new(
+                error::Unauthorized::DeidentifiedOnly,
+            )));
+        }
+        match db.read().find_table_issues(user.access_group) {
+            Ok(issues) => Ok(warp::reply::json(&issues)),
+            Err(e) => Err(reject(e)),
+        }
+    }
+    route("get", "/check-quality")
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
+        .and(with_db(db))
+        .and_then(handler)
+}
+
+// Integrity ======================================================================================
+
+fn get_integrity(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    async fn handler(_u: current::User, db: Db) -> Result<impl Reply, Infallible> {
+        Ok(warp::reply::json(db.integrity()))
     }
-    warp::path!("check-quality")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/integrity")
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
         .and(with_db(db))
         .and_then(handler)
 }
 
+// OpenAPI ========================================================================================
+
+fn get_openapi() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Doesn't change while the server runs
+    let spec = Arc::new(openapi::spec());
+    route("get", "/openapi.json").map(move || warp::reply::json(&*spec))
+}
+
 // Year change ======================================================================================
 
 fn get_consent(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.consent.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::Consent>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.consent, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("consent")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/consent")
+        .and(available_table(db.clone(), "Consent"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -591,18 +1256,25 @@ fn consent_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("consent" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/consent/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let redcap_consent = match redcap::export_consent(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_consent(redcap_consent) {
-                Ok(()) => Ok(reply_no_content()),
+            match db
+                .write()
+                .await
+                .sync_redcap_consent(u.email.as_str(), redcap_consent)
+            {
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -611,28 +1283,19 @@ fn consent_redcap_sync(
 // Year change ======================================================================================
 
 fn get_year_change(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.year_change.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| {
-                        participants
-                            .iter()
-                            .any(|p| p.pid == v.pid.as_ref().map(|s| s.as_ref()).unwrap_or(""))
-                    })
-                    .collect::<Vec<&current::YearChange>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.year_change, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("year-change")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/year-change")
+        .and(available_table(db.clone(), "YearChange"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -641,18 +1304,25 @@ fn year_change_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("year-change" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/year-change/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let redcap_year_change = match redcap::export_year_change(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_year_change(redcap_year_change) {
-                Ok(()) => Ok(reply_no_content()),
+            match db
+                .write()
+                .await
+                .sync_redcap_year_change(u.email.as_str(), redcap_year_change)
+            {
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
@@ -661,24 +1331,19 @@ fn year_change_redcap_sync(
 // Year change ======================================================================================
 
 fn get_bleed(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
-        let db = db.lock().await;
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Rejection> {
+        let pseudonyms = db.pseudonyms_for(&u);
+        let db = db.read();
         let data = &db.bleed.current.data;
         if let current::AccessGroup::Site(site) = u.access_group {
-            let participants = db.get_participants_subset(site);
-            Ok(warp::reply::json(
-                &data
-                    .iter()
-                    .filter(|v| participants.iter().any(|p| p.pid == v.pid))
-                    .collect::<Vec<&current::Bleed>>(),
-            ))
+            reply_rows(pseudonyms, &db.get_site_subset(&db.bleed, site))
         } else {
-            Ok(warp::reply::json(data))
+            reply_rows(pseudonyms, data)
         }
     }
-    warp::path!("bleed")
-        .and(warp::get())
-        .and(user_from_token(db.clone()))
+    route("get", "/bleed")
+        .and(available_table(db.clone(), "Bleed"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
         .and(with_db(db))
         .and_then(handler)
 }
@@ -687,19 +1352,233 @@ fn bleed_redcap_sync(
     db: Db,
     opt: Opt,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path!("bleed" / "redcap" / "sync")
-        .and(warp::put())
-        .and(user_from_token(db.clone()))
+    route("put", "/bleed/redcap/sync")
+        .and(capable(
+            db.clone(),
+            current::Scope::Sync,
+            current::Capability::Sync,
+        ))
         .and(with_db(db))
         .and(with_opt(opt))
-        .and_then(move |_u: current::User, db: Db, opt: Opt| async move {
+        .and_then(move |u: current::User, db: Db, opt: Opt| async move {
             let redcap_bleed = match redcap::export_bleeds(&opt).await {
                 Ok(u) => u,
                 Err(e) => return Err(reject(e)),
             };
-            match db.lock().await.sync_redcap_bleed(redcap_bleed) {
-                Ok(()) => Ok(reply_no_content()),
+            match db
+                .write()
+                .await
+                .sync_redcap_bleed(u.email.as_str(), redcap_bleed)
+            {
+                Ok(record) => Ok(reply_sync_record(db.pseudonyms_for(&u), &record)),
                 Err(e) => Err(reject(e)),
             }
         })
 }
+
+// Sync history ===================================================================================
+
+fn get_sync_history(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    async fn handler(u: current::User, db: Db) -> Result<impl Reply, Infallible> {
+        let history = &db.read().sync_history.current.data;
+        match db.pseudonyms_for(&u) {
+            Some(p) => Ok(warp::reply::json(
+                &history
+                    .iter()
+                    .map(|record| p.sync_record(record))
+                    .collect::<Vec<current::SyncRecord>>(),
+            )),
+            None => Ok(warp::reply::json(history)),
+        }
+    }
+    route("get", "/sync-history")
+        .and(available_table(db.clone(), "SyncHistory"))
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
+        .and(with_db(db))
+        .and_then(handler)
+}
+
+// Row history ====================================================================================
+
+fn get_history(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route_params::<2>("get", "/history/{table}/{pk}")
+        .and(available_table(db.clone(), "History"))
+        .and(user_with_scope(db.clone(), current::Scope::ReadTables))
+        .and(with_db(db))
+        .and_then(
+            move |[table, pk]: [String; 2], u: current::User, db: Db| async move {
+                // Rows are looked up by their real keys
+                if db.pseudonyms_for(&u).is_some() {
+                    return Err(reject(anyhow::Error::new(
+                        error::Unauthorized::DeidentifiedOnly,
+                    )));
+                }
+                match db
+                    .read()
+                    .get_history(table.as_str(), pk.as_str(), u.access_group)
+                {
+                    Ok(history) => Ok(warp::reply::json(&history)),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+// Snapshots ======================================================================================
+
+fn get_snapshots(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route("get", "/snapshots")
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
+        .and(with_db(db))
+        .and_then(move |_u: current::User, db: Db| async move {
+            match db.list_snapshots() {
+                Ok(snapshots) => Ok(warp::reply::json(&snapshots)),
+                Err(e) => Err(reject(e)),
+            }
+        })
+}
+
+fn snapshot_diff(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route_params::<1>("get", "/snapshots/{id}/diff")
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
+        .and(with_db(db))
+        .and_then(
+            move |[id]: [String; 1], _u: current::User, db: Db| async move {
+                match db.diff_snapshot(id.as_str()) {
+                    Ok(diffs) => Ok(warp::reply::json(&diffs)),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+/// What to restore from a snapshot
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct SnapshotRestoreQuery {
+    /// Every table but users if missing, users are only restored when named.
+    /// Tokens are never restored.
+    table: Option<String>,
+}
+
+fn snapshot_restore(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route_params::<1>("put", "/snapshots/{id}/restore")
+        .and(warp::query())
+        .and(sufficient_access(
+            db.clone(),
+            current::AccessGroup::Admin,
+            current::Scope::Admin,
+        ))
+        .and(with_db(db))
+        .and_then(
+            move |[id]: [String; 1], query: SnapshotRestoreQuery, u: current::User, db: Db| async move {
+                match db.write().await.restore_snapshot(
+                    u.email.as_str(),
+                    id.as_str(),
+                    query.table.as_deref(),
+                ) {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(reject(e)),
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::{AsyncSmtpTransport, Tokio1Executor};
+
+    fn filter(
+        root: &std::path::Path,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let db = db::Db::new(
+            root,
+            db::storage::StorageKind::Json.into(),
+            "admin@example.com",
+            auth::TokenHasher::new(None).unwrap(),
+        )
+        .unwrap();
+        let opt: crate::Opt = toml::from_str(
+            r#"
+            root_dir = "unused"
+            port = 0
+            auth_token_length = 16
+            auth_token_days_to_live = 1
+            default_admin_email = "admin@example.com"
+            email_host = "localhost"
+            email_username = ""
+            email_password = ""
+            frontend_root = ""
+            redcap_token_2020 = ""
+            redcap_token_2021 = ""
+            redcap_api_url = ""
+            "#,
+        )
+        .unwrap();
+        let mailer = email::Mailer {
+            from: "admin@example.com".to_string(),
+            transport: AsyncSmtpTransport::<Tokio1Executor>::unencrypted_localhost(),
+        };
+        routes(
+            Arc::new(db::shared::SharedDb::new(db)),
+            Arc::new(opt),
+            Arc::new(mailer),
+        )
+    }
+
+    async fn status(
+        filter: &(impl Filter<Extract = impl Reply, Error = Rejection> + Clone + 'static),
+        method: &str,
+        path: &str,
+    ) -> StatusCode {
+        warp::test::request()
+            .method(method.to_uppercase().as_str())
+            .path(format!("/api{}", path).as_str())
+            .reply(filter)
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn every_route_is_served() {
+        let root = tempfile::tempdir().unwrap();
+        let filter = filter(root.path());
+        for route in ROUTES {
+            let path = route
+                .path
+                .split('/')
+                .map(|s| if s.starts_with('{') { "x" } else { s })
+                .collect::<Vec<&str>>()
+                .join("/");
+            let status = status(&filter, route.method, path.as_str()).await;
+            assert!(
+                status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                "{} {} replied {}",
+                route.method,
+                route.path,
+                status
+            );
+        }
+        assert_eq!(status(&filter, "get", "/nope").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(&filter, "get", "/history/x").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&filter, "post", "/integrity").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
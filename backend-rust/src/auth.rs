@@ -1,4 +1,23 @@
 use crate::{error, Result};
+use anyhow::{bail, Context};
+use hmac::{Hmac, Mac, NewMac};
+use subtle::ConstantTimeEq;
+
+/// Marks hashes made with a pepper, the ones without it are plain SHA-512
+const PEPPERED_PREFIX: &str = "hmac-sha512:";
+
+/// Shortest pepper accepted, in bytes
+const MIN_PEPPER_LENGTH: usize = 32;
+
+/// Hashes tokens for storage. With a pepper the stored hash is an HMAC of the
+/// plain hash keyed with the pepper, so a copy of the token table is no use
+/// without the config. Hashes stored before there was a pepper can be peppered
+/// without knowing their tokens, see `pepper_hash`. Changing the pepper
+/// invalidates every token.
+#[derive(Clone)]
+pub struct TokenHasher {
+    pepper: Option<Vec<u8>>,
+}
 
 pub fn parse_bearer_header(raw: &str) -> Result<&str> {
     let header: Vec<&str> = raw.splitn(2, ' ').collect();
@@ -19,10 +38,133 @@ pub fn random_string(len: usize) -> String {
         .collect()
 }
 
-pub fn hash(s: &str) -> String {
+impl TokenHasher {
+    /// Pepper is hex, tokens are hashed without one if there isn't any
+    pub fn new(pepper: Option<&str>) -> Result<Self> {
+        let pepper = match pepper {
+            Some(pepper) => {
+                let bytes = hex::decode(pepper.trim()).context("token pepper is not hex")?;
+                if bytes.len() < MIN_PEPPER_LENGTH {
+                    bail!(
+                        "token pepper must be at least {} bytes, got {}",
+                        MIN_PEPPER_LENGTH,
+                        bytes.len()
+                    );
+                }
+                Some(bytes)
+            }
+            None => None,
+        };
+        Ok(Self { pepper })
+    }
+    pub fn has_pepper(&self) -> bool {
+        self.pepper.is_some()
+    }
+    pub fn hash(&self, token: &str) -> String {
+        let plain = hash(token);
+        self.pepper_hash(plain.as_str()).unwrap_or(plain)
+    }
+    /// Peppered version of a hash stored without a pepper. Nothing if there's
+    /// no pepper or the hash is already peppered.
+    pub fn pepper_hash(&self, stored: &str) -> Option<String> {
+        let pepper = self.pepper.as_ref()?;
+        if is_peppered(stored) {
+            return None;
+        }
+        let mut mac = Hmac::<sha2::Sha512>::new_from_slice(pepper).expect("any key length works");
+        mac.update(stored.as_bytes());
+        Some(format!(
+            "{}{}",
+            PEPPERED_PREFIX,
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+    /// Whether the stored hash is the hash of the token
+    pub fn matches(&self, token: &str, stored: &str) -> bool {
+        hashes_match(self.hash(token).as_str(), stored)
+    }
+}
+
+pub fn is_peppered(hash: &str) -> bool {
+    hash.starts_with(PEPPERED_PREFIX)
+}
+
+/// Takes the same time wherever hashes of the same length differ
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Plain SHA-512, what tokens were stored as before there was a pepper
+fn hash(s: &str) -> String {
     use sha2::Digest;
     let mut hasher = sha2::Sha512::new();
     hasher.update(s.as_bytes());
     let hash_result = hasher.finalize();
-    hex::encode(&hash_result)
+    hex::encode(hash_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEPPER: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn peppered() -> TokenHasher {
+        TokenHasher::new(Some(PEPPER)).unwrap()
+    }
+
+    #[test]
+    fn bearer_header() {
+        assert_eq!(parse_bearer_header("Bearer abc").unwrap(), "abc");
+        assert!(parse_bearer_header("Basic abc").is_err());
+    }
+
+    #[test]
+    fn unpeppered_hash_is_plain_sha512() {
+        let hasher = TokenHasher::new(None).unwrap();
+        let hashed = hasher.hash("token");
+        assert_eq!(hashed, hash("token"));
+        assert_eq!(hashed.len(), 128);
+        assert!(!is_peppered(hashed.as_str()));
+        assert!(hasher.pepper_hash(hashed.as_str()).is_none());
+    }
+
+    #[test]
+    fn peppered_hash_depends_on_pepper() {
+        let hashed = peppered().hash("token");
+        assert!(is_peppered(hashed.as_str()));
+        assert_ne!(hashed, hash("token"));
+        assert_eq!(hashed, peppered().hash("token"));
+        assert_ne!(hashed, peppered().hash("other"));
+        let other = TokenHasher::new(Some(&"ff".repeat(32))).unwrap();
+        assert_ne!(hashed, other.hash("token"));
+    }
+
+    #[test]
+    fn stored_hashes_can_be_peppered() {
+        let hasher = peppered();
+        let stored = hash("token");
+        let migrated = hasher.pepper_hash(stored.as_str()).unwrap();
+        assert_eq!(migrated, hasher.hash("token"));
+        assert!(hasher.matches("token", migrated.as_str()));
+        assert!(hasher.pepper_hash(migrated.as_str()).is_none());
+    }
+
+    #[test]
+    fn matches() {
+        let hasher = peppered();
+        let stored = hasher.hash("token");
+        assert!(hasher.matches("token", stored.as_str()));
+        assert!(!hasher.matches("tokem", stored.as_str()));
+        assert!(!hasher.matches("token", hash("token").as_str()));
+        assert!(!hashes_match("abc", "abcd"));
+        assert!(hashes_match("abc", "abc"));
+    }
+
+    #[test]
+    fn pepper_has_to_be_long_hex() {
+        assert!(TokenHasher::new(Some("not hex")).is_err());
+        assert!(TokenHasher::new(Some("abcd")).is_err());
+        assert!(TokenHasher::new(Some(&format!("  {}\n", PEPPER))).is_ok());
+    }
 }
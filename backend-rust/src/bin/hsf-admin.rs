@@ -0,0 +1,431 @@
+use anyhow::{bail, Context};
+use backend_rust::{
+    data::current,
+    db::{diff, Db, DbDirs},
+    Opt, Result,
+};
+use rustyline::{error::ReadlineError, Editor};
+use std::fs;
+use std::path::Path;
+
+const USAGE: &str = "\
+Offline admin for the db at root_dir in hsf_config.toml, refuses to run while the server is up.
+Runs the command if given one, starts a prompt otherwise.
+
+Commands:
+    tables                                  list tables with their row counts
+    get <table> <pk>                        rows with the primary key, composite keys separated by commas
+    export <table> [file]                   write the rows as JSON to stdout or the file
+    export all <dir>                        write every table into the directory
+    add-user <email> <group> [deidentified] group is admin, unrestricted or a site
+    remove-user <email>                     remove the manual user along with their tokens
+    revoke-tokens <email>                   remove every token of the user
+    issues                                  find issues in the tables
+    convert                                 convert previous data if there's no current data
+    help                                    show this
+    quit                                    leave the prompt";
+
+/// Who user changes are recorded as made by in row history
+const ACTOR: &str = "hsf-admin";
+
+/// Makes user changes, can change any user
+fn actor() -> current::User {
+    current::User {
+        email: ACTOR.to_string(),
+        access_group: current::AccessGroup::Admin,
+        kind: current::UserKind::Manual,
+        deidentified_export: false,
+        capabilities: current::Capability::ALL.to_vec(),
+    }
+}
+
+fn main() -> Result<()> {
+    pretty_env_logger::init();
+
+    let opt = Opt::new()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["help"] | ["--help"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        // Has to look at the directories before the db is opened
+        ["convert"] => {
+            println!("{}", convert(&opt)?);
+            Ok(())
+        }
+        [] => repl(&mut open(&opt)?),
+        args => {
+            println!("{}", run(&mut open(&opt)?, args)?);
+            Ok(())
+        }
+    }
+}
+
+fn open(opt: &Opt) -> Result<Db> {
+    Db::new(
+        opt.root_dir.as_path(),
+        opt.storage_config()?,
+        opt.default_admin_email.as_str(),
+        opt.token_hasher()?,
+    )
+}
+
+fn repl(db: &mut Db) -> Result<()> {
+    println!("type help for the list of commands");
+    let mut editor = Editor::<()>::new();
+    loop {
+        let line = match editor.readline("hsf> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => continue,
+            ["quit"] | ["exit"] => break,
+            args => match run(db, args) {
+                Ok(output) => println!("{}", output),
+                Err(e) => println!("error: {:#}", e),
+            },
+        }
+        editor.add_history_entry(line.as_str());
+    }
+    Ok(())
+}
+
+/// Runs the command, replies with what to print
+fn run(db: &mut Db, args: &[&str]) -> Result<String> {
+    let output = match args {
+        ["help"] => USAGE.to_string(),
+        ["tables"] => list_tables(db),
+        ["get", table, pk] => {
+            let table = db.tables.table(table)?;
+            let rows = table
+                .rows()?
+                .into_iter()
+                .filter(|row| diff::key_text(&diff::row_key(table.key_columns(), row)) == *pk)
+                .collect::<Vec<serde_json::Value>>();
+            serde_json::to_string_pretty(&rows)?
+        }
+        ["export", "all", dir] => {
+            fs::create_dir_all(dir).context(format!("failed to create {}", dir))?;
+            let mut written = Vec::new();
+            for table in db.tables.all() {
+                let path = Path::new(dir).join(format!("{}.json", table.name()));
+                written.push(export(table.rows()?, Some(path.as_path()))?);
+            }
+            written.join("\n")
+        }
+        ["export", table] => export(db.tables.table(table)?.rows()?, None)?,
+        ["export", table, file] => export(db.tables.table(table)?.rows()?, Some(Path::new(file)))?,
+        ["add-user", email, group] => add_user(db, email, group, false)?,
+        ["add-user", email, group, "deidentified"] => add_user(db, email, group, true)?,
+        ["remove-user", email] => {
+            db.remove_user(&actor(), email)?;
+            format!("removed user {}", email)
+        }
+        ["revoke-tokens", email] => {
+            let removed = db.remove_tokens(email, None)?;
+            format!("removed {} tokens of {}", removed, email)
+        }
+        ["issues"] => {
+            let issues = db.tables.find_table_issues(current::AccessGroup::Admin)?;
+            serde_json::to_string_pretty(&issues)?
+        }
+        ["convert"] => "current data is already open, nothing to convert".to_string(),
+        _ => bail!(
+            "unrecognized command {:?}, type help for the list",
+            args.join(" ")
+        ),
+    };
+    Ok(output)
+}
+
+fn list_tables(db: &Db) -> String {
+    let quarantined = db.integrity.quarantined();
+    db.tables
+        .all()
+        .iter()
+        .map(|table| {
+            let mark = if quarantined.contains(&table.name()) {
+                " (quarantined)"
+            } else {
+                ""
+            };
+            format!("{:<20} {:>8} rows{}", table.name(), table.row_count(), mark)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Rows as JSON if there's no file to write them to
+fn export(rows: Vec<serde_json::Value>, path: Option<&Path>) -> Result<String> {
+    let contents = serde_json::to_string_pretty(&rows)?;
+    match path {
+        Some(path) => {
+            fs::write(path, contents).context(format!("failed to write {:?}", path))?;
+            Ok(format!("wrote {} rows to {:?}", rows.len(), path))
+        }
+        None => Ok(contents),
+    }
+}
+
+fn add_user(db: &mut Db, email: &str, group: &str, deidentified_export: bool) -> Result<String> {
+    let access_group = parse_access_group(group)?;
+    let user = current::User {
+        email: email.to_string(),
+        access_group,
+        kind: current::UserKind::Manual,
+        deidentified_export,
+        capabilities: current::Capability::defaults(access_group, deidentified_export),
+    };
+    db.add_users(&actor(), vec![user])?;
+    Ok(format!(
+        "added user {} with access {:?}",
+        email, access_group
+    ))
+}
+
+/// Admin, unrestricted or the name of a site whatever the case
+fn parse_access_group(group: &str) -> Result<current::AccessGroup> {
+    let lowercase = group.to_lowercase();
+    match lowercase.as_str() {
+        "admin" => return Ok(current::AccessGroup::Admin),
+        "unrestricted" => return Ok(current::AccessGroup::Unrestricted),
+        _ => {}
+    }
+    // Sites are spelled the way they're serialized
+    let mut chars = lowercase.chars();
+    let name = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+        None => String::new(),
+    };
+    match serde_json::from_value::<current::Site>(serde_json::Value::from(name)) {
+        Ok(site) => Ok(current::AccessGroup::Site(site)),
+        Err(_) => bail!("unrecognized access group {}", group),
+    }
+}
+
+/// Opening the db converts the previous data when there's no current data
+fn convert(opt: &Opt) -> Result<String> {
+    let dirs = DbDirs::locate(opt.root_dir.as_path());
+    if dirs.current.is_dir() {
+        return Ok(format!(
+            "current data found at {:?}, nothing to convert",
+            dirs.current
+        ));
+    }
+    if !dirs.previous.is_dir() {
+        bail!("no previous data at {:?}", dirs.previous);
+    }
+    let db = open(opt)?;
+    Ok(format!(
+        "converted {:?} into {:?}\n{}",
+        dirs.previous,
+        dirs.current,
+        list_tables(&db)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const ADMIN: &str = "admin@example.com";
+
+    fn opt(root: &Path) -> Opt {
+        toml::from_str(
+            format!(
+                r#"
+                root_dir = {:?}
+                port = 0
+                auth_token_length = 16
+                auth_token_days_to_live = 1
+                default_admin_email = "{}"
+                email_host = "localhost"
+                email_username = ""
+                email_password = ""
+                frontend_root = ""
+                redcap_token_2020 = ""
+                redcap_token_2021 = ""
+                redcap_api_url = ""
+                "#,
+                root.to_str().unwrap(),
+                ADMIN
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    fn emails(db: &Db) -> Vec<&str> {
+        db.tables
+            .users
+            .current
+            .data
+            .iter()
+            .map(|u| u.email.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn access_groups_parse_whatever_the_case() {
+        use current::{AccessGroup, Site};
+        assert_eq!(parse_access_group("admin").unwrap(), AccessGroup::Admin);
+        assert_eq!(
+            parse_access_group("Unrestricted").unwrap(),
+            AccessGroup::Unrestricted
+        );
+        assert_eq!(
+            parse_access_group("perth").unwrap(),
+            AccessGroup::Site(Site::Perth)
+        );
+        assert_eq!(
+            parse_access_group("NEWCASTLE").unwrap(),
+            AccessGroup::Site(Site::Newcastle)
+        );
+        assert!(parse_access_group("darwin").is_err());
+        assert!(parse_access_group("").is_err());
+    }
+
+    #[test]
+    fn unrecognized_commands_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(&opt(root.path())).unwrap();
+        assert!(run(&mut db, &["help"]).unwrap().contains("Commands:"));
+        for args in [
+            &["nope"][..],
+            &["get", "User"],
+            &["add-user", "a@example.com"],
+            &["add-user", "a@example.com", "perth", "identifiable"],
+            &["revoke-tokens"],
+        ] {
+            let e = run(&mut db, args).unwrap_err();
+            assert!(e.to_string().contains("unrecognized command"), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn users_are_added_and_removed() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(&opt(root.path())).unwrap();
+        run(
+            &mut db,
+            &["add-user", "a@example.com", "Perth", "deidentified"],
+        )
+        .unwrap();
+        run(&mut db, &["add-user", "b@example.com", "unrestricted"]).unwrap();
+        assert!(run(&mut db, &["add-user", "c@example.com", "darwin"]).is_err());
+        let a = db
+            .tables
+            .users
+            .lookup(&"a@example.com".to_string())
+            .unwrap();
+        assert_eq!(
+            a.access_group,
+            current::AccessGroup::Site(current::Site::Perth)
+        );
+        assert!(a.deidentified_export);
+        assert!(
+            !db.tables
+                .users
+                .lookup(&"b@example.com".to_string())
+                .unwrap()
+                .deidentified_export
+        );
+
+        run(&mut db, &["remove-user", "a@example.com"]).unwrap();
+        assert_eq!(emails(&db), vec![ADMIN, "b@example.com"]);
+        assert!(run(&mut db, &["remove-user", "a@example.com"]).is_err());
+    }
+
+    #[test]
+    fn tokens_are_revoked() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(&opt(root.path())).unwrap();
+        for kind in [current::TokenKind::Session, current::TokenKind::Api] {
+            let (_, token) = current::Token::new(ADMIN, kind, 16, Some(1), &[], &db.token_hasher);
+            db.insert_token(token).unwrap();
+        }
+        assert_eq!(
+            run(&mut db, &["revoke-tokens", ADMIN]).unwrap(),
+            format!("removed 2 tokens of {}", ADMIN)
+        );
+        assert!(db.tables.tokens.current.data.is_empty());
+    }
+
+    #[test]
+    fn tables_are_listed_read_and_exported() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(&opt(root.path())).unwrap();
+        let tables = run(&mut db, &["tables"]).unwrap();
+        assert_eq!(tables.lines().count(), db.tables.all().len());
+        assert!(tables
+            .lines()
+            .any(|l| l.starts_with("User ") && l.ends_with(" 1 rows")));
+
+        let rows: Vec<current::User> =
+            serde_json::from_str(&run(&mut db, &["get", "User", ADMIN]).unwrap()).unwrap();
+        assert_eq!(rows[0].email, ADMIN);
+        assert_eq!(run(&mut db, &["get", "User", "nobody"]).unwrap(), "[]");
+        assert!(run(&mut db, &["get", "Nope", ADMIN]).is_err());
+
+        let exported: Vec<current::User> =
+            serde_json::from_str(&run(&mut db, &["export", "User"]).unwrap()).unwrap();
+        assert_eq!(exported.len(), 1);
+        let file = root.path().join("users.json");
+        run(&mut db, &["export", "User", file.to_str().unwrap()]).unwrap();
+        let exported: Vec<current::User> =
+            serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap();
+        assert_eq!(exported.len(), 1);
+
+        let dir: PathBuf = root.path().join("export");
+        run(&mut db, &["export", "all", dir.to_str().unwrap()]).unwrap();
+        for table in db.tables.all() {
+            assert!(dir.join(format!("{}.json", table.name())).is_file());
+        }
+    }
+
+    #[test]
+    fn issues_are_json() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(&opt(root.path())).unwrap();
+        let issues: serde_json::Value =
+            serde_json::from_str(&run(&mut db, &["issues"]).unwrap()).unwrap();
+        assert!(issues.is_object());
+    }
+
+    #[test]
+    fn previous_data_is_converted_once() {
+        let root = tempfile::tempdir().unwrap();
+        let opt = opt(root.path());
+        assert!(convert(&opt).is_err());
+
+        let dirs = DbDirs::locate(root.path());
+        fs::create_dir(dirs.previous.as_path()).unwrap();
+        fs::write(
+            dirs.previous.join("User.json"),
+            serde_json::json!([{
+                "email": "perth@example.com",
+                "access_group": {"Site": "Perth"},
+                "kind": "Manual",
+                "deidentified_export": false,
+            }])
+            .to_string(),
+        )
+        .unwrap();
+        assert!(convert(&opt).unwrap().starts_with("converted"));
+        assert!(dirs.current.is_dir());
+        assert!(convert(&opt).unwrap().contains("nothing to convert"));
+
+        let mut db = open(&opt).unwrap();
+        assert_eq!(emails(&db), vec!["perth@example.com"]);
+        assert!(run(&mut db, &["convert"])
+            .unwrap()
+            .contains("nothing to convert"));
+    }
+}
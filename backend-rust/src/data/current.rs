@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Copy, JsonSchema)]
 pub enum Site {
     Melbourne,
     Sydney,
@@ -11,49 +12,85 @@ pub enum Site {
     Perth,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Copy, JsonSchema)]
 pub enum AccessGroup {
     Site(Site),
     Unrestricted,
     Admin,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub enum UserKind {
     Redcap,
     Manual,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// What a user is allowed to do regardless of their access group
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
+pub enum Capability {
+    /// Trigger REDCap syncs
+    Sync,
+    /// See emails, phone numbers and dates of birth of participants
+    ViewIdentifiable,
+    /// Upload serology and virus results
+    UploadLabResults,
+    /// Add, change and remove users and sync them from REDCap
+    ManageUsers,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct User {
     pub email: String,
     pub access_group: AccessGroup,
     pub kind: UserKind,
     pub deidentified_export: bool,
+    pub capabilities: Vec<Capability>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
 pub enum TokenKind {
     Session,
     Api,
+    /// Emailed one-time code that's exchanged for a session
+    Login,
+}
+
+/// What a token can be used for on top of managing the tokens of its user
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, JsonSchema)]
+pub enum Scope {
+    /// Read the tables the user has access to
+    ReadTables,
+    /// Trigger REDCap syncs
+    Sync,
+    /// Act as an admin if the user is one, manage users if the user can
+    /// and manage the user's own tokens
+    Admin,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Token {
+    /// Refers to the token without giving it away
+    pub id: String,
     pub user: String,
     pub hash: String,
     pub kind: TokenKind,
+    /// Unknown for tokens created before it was recorded
+    pub created: Option<DateTime<Utc>>,
     pub expires: Option<DateTime<Utc>>,
+    /// Session tokens have every scope
+    pub scopes: Vec<Scope>,
+    /// When a login code was exchanged for a session, codes only work once
+    pub used: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, JsonSchema)]
 pub enum Gender {
     Female,
     Male,
     Other,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub enum Occupation {
     Nursing,
     Medical,
@@ -65,7 +102,7 @@ pub enum Occupation {
     Other(String),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Participant {
     pub pid: String,
     pub site: Site,
@@ -81,7 +118,7 @@ pub struct Participant {
     pub occupation: Option<Occupation>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, JsonSchema)]
 pub enum VaccinationStatus {
     Australia,
     Overseas,
@@ -89,14 +126,14 @@ pub enum VaccinationStatus {
     No,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct VaccinationHistory {
     pub pid: String,
     pub year: u32,
     pub status: Option<VaccinationStatus>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Schedule {
     pub pid: String,
     pub year: u32,
@@ -104,7 +141,7 @@ pub struct Schedule {
     pub date: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub enum SwabResult {
     InfluenzaAUnsubtyped,
     InfluenzaAh3,
@@ -123,7 +160,7 @@ pub enum SwabResult {
     Negative,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct WeeklySurvey {
     pub pid: String,
     pub year: u32,
@@ -134,7 +171,7 @@ pub struct WeeklySurvey {
     pub swab_result: Vec<SwabResult>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Withdrawn {
     pub pid: String,
     pub year: u32,
@@ -142,14 +179,14 @@ pub struct Withdrawn {
     pub reason: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Virus {
     pub name: String,
     pub short_name: String,
     pub clade: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Serology {
     pub pid: String,
     pub year: u32,
@@ -158,25 +195,27 @@ pub struct Serology {
     pub titre: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, JsonSchema)]
 pub enum StudyGroup {
     MainOnly,
     MainAndNested,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
 pub enum ConsentDisease {
     Flu,
     Covid,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, PartialEq, JsonSchema)]
 pub enum ConsentForm {
     Paper,
     Electronic,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Consent {
     pub pid: String,
     pub year: u32,
@@ -185,7 +224,7 @@ pub struct Consent {
     pub group: Option<StudyGroup>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct YearChange {
     pub record_id: String,
     pub year: u32,
@@ -193,10 +232,45 @@ pub struct YearChange {
     pub pid_preformat: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Bleed {
     pub pid: String,
     pub year: u32,
     pub day: u32,
     pub date: Option<DateTime<Utc>>,
 }
+
+/// What a REDCap sync changed in a table. Rows are identified by their
+/// primary key, or by their whole contents if the table has none.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct SyncRecord {
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub table: String,
+    pub added: Vec<serde_json::Value>,
+    pub removed: Vec<serde_json::Value>,
+    pub changed: Vec<serde_json::Value>,
+}
+
+/// What caused a change to the data
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum Actor {
+    /// REDCap sync triggered by the user with this email
+    RedcapSync(String),
+    /// Edit made through the API by the user with this email
+    Manual(String),
+    /// Migration of the data to this version
+    Migration(u32),
+}
+
+/// One row of a table before and after a change.
+/// `before` is missing for added rows, `after` is missing for removed rows.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub actor: Actor,
+    pub table: String,
+    pub pk: serde_json::Value,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
@@ -1,13 +1,24 @@
 use crate::{
     auth,
-    db::{PrimaryKey, ToCurrent},
+    db::{ParticipantRow, PrimaryKey, ToCurrent},
+    error, Result,
 };
+use anyhow::bail;
 
 pub mod current;
 pub mod previous;
 
+// Rows as they were at each data version, only for the migrations. These never
+// change, changing the schema adds a version instead.
+pub mod v2;
+pub mod v3;
+pub mod v4;
+pub mod v5;
+pub mod v6;
+
 impl PrimaryKey for current::Participant {
     type K = String;
+    const COLUMNS: &'static [&'static str] = &["pid"];
     fn get_pk(&self) -> Self::K {
         self.pid.clone()
     }
@@ -15,6 +26,7 @@ impl PrimaryKey for current::Participant {
 
 impl PrimaryKey for current::User {
     type K = String;
+    const COLUMNS: &'static [&'static str] = &["email"];
     fn get_pk(&self) -> Self::K {
         self.email.clone()
     }
@@ -22,6 +34,7 @@ impl PrimaryKey for current::User {
 
 impl PrimaryKey for current::Token {
     type K = String;
+    const COLUMNS: &'static [&'static str] = &["hash"];
     fn get_pk(&self) -> Self::K {
         self.hash.clone()
     }
@@ -29,6 +42,7 @@ impl PrimaryKey for current::Token {
 
 impl PrimaryKey for current::VaccinationHistory {
     type K = (String, u32);
+    const COLUMNS: &'static [&'static str] = &["pid", "year"];
     fn get_pk(&self) -> Self::K {
         (self.pid.clone(), self.year)
     }
@@ -36,6 +50,7 @@ impl PrimaryKey for current::VaccinationHistory {
 
 impl PrimaryKey for current::Schedule {
     type K = (String, u32, u32);
+    const COLUMNS: &'static [&'static str] = &["pid", "year", "day"];
     fn get_pk(&self) -> Self::K {
         (self.pid.clone(), self.year, self.day)
     }
@@ -43,6 +58,7 @@ impl PrimaryKey for current::Schedule {
 
 impl PrimaryKey for current::WeeklySurvey {
     type K = (String, u32, u32);
+    const COLUMNS: &'static [&'static str] = &["pid", "year", "index"];
     fn get_pk(&self) -> Self::K {
         (self.pid.clone(), self.year, self.index)
     }
@@ -50,6 +66,7 @@ impl PrimaryKey for current::WeeklySurvey {
 
 impl PrimaryKey for current::Withdrawn {
     type K = String;
+    const COLUMNS: &'static [&'static str] = &["pid"];
     fn get_pk(&self) -> Self::K {
         self.pid.clone()
     }
@@ -57,6 +74,7 @@ impl PrimaryKey for current::Withdrawn {
 
 impl PrimaryKey for current::Virus {
     type K = String;
+    const COLUMNS: &'static [&'static str] = &["name"];
     fn get_pk(&self) -> Self::K {
         self.name.clone()
     }
@@ -64,33 +82,157 @@ impl PrimaryKey for current::Virus {
 
 impl PrimaryKey for current::Serology {
     type K = (String, u32, u32, String);
+    const COLUMNS: &'static [&'static str] = &["pid", "year", "day", "virus"];
     fn get_pk(&self) -> Self::K {
         (self.pid.clone(), self.year, self.day, self.virus.clone())
     }
 }
 
+impl ParticipantRow for current::VaccinationHistory {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::Schedule {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::WeeklySurvey {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::Withdrawn {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::Serology {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::Consent {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::Bleed {
+    fn pid(&self) -> Option<&str> {
+        Some(self.pid.as_str())
+    }
+}
+
+impl ParticipantRow for current::YearChange {
+    fn pid(&self) -> Option<&str> {
+        self.pid.as_deref()
+    }
+}
+
+impl current::User {
+    pub fn can(&self, capability: current::Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+    /// Fails unless this user has all the access `user` has. Users who manage
+    /// other users can't grant more than they have or change users who have more.
+    pub fn check_covers(&self, user: &current::User) -> Result<()> {
+        if !self.access_group.covers(user.access_group) {
+            bail!(error::Unauthorized::InsufficientAccess(
+                self.access_group,
+                user.access_group
+            ));
+        }
+        if let Some(&capability) = user.capabilities.iter().find(|&&c| !self.can(c)) {
+            bail!(error::Unauthorized::MissingCapability(capability));
+        }
+        if self.deidentified_export && !user.deidentified_export {
+            bail!(error::Unauthorized::DeidentifiedOnly);
+        }
+        Ok(())
+    }
+}
+
+impl current::AccessGroup {
+    /// Whether this group sees everything `other` does. Sites only see themselves.
+    pub fn covers(self, other: current::AccessGroup) -> bool {
+        match (self, other) {
+            (current::AccessGroup::Site(site), current::AccessGroup::Site(other)) => site == other,
+            _ => self >= other,
+        }
+    }
+}
+
+impl current::Capability {
+    pub const ALL: &'static [current::Capability] = &[
+        current::Capability::Sync,
+        current::Capability::ViewIdentifiable,
+        current::Capability::UploadLabResults,
+        current::Capability::ManageUsers,
+    ];
+    /// What users can do unless they are given something else.
+    /// Admins can do everything, others can only see identifiable data
+    /// unless they are restricted to de-identified exports.
+    pub fn defaults(
+        access_group: current::AccessGroup,
+        deidentified_export: bool,
+    ) -> Vec<current::Capability> {
+        match access_group {
+            current::AccessGroup::Admin => current::Capability::ALL.to_vec(),
+            _ if deidentified_export => Vec::new(),
+            _ => vec![current::Capability::ViewIdentifiable],
+        }
+    }
+}
+
+impl current::Scope {
+    pub const ALL: &'static [current::Scope] = &[
+        current::Scope::ReadTables,
+        current::Scope::Sync,
+        current::Scope::Admin,
+    ];
+}
+
 impl current::Token {
+    pub const ID_LENGTH: usize = 16;
+    /// Session tokens expire in `days_to_live` days and have every scope.
+    /// API tokens expire only if given `days_to_live` and have only the given scopes.
+    /// Login codes have no scopes, they can only be exchanged for a session.
     pub fn new(
         email: &str,
         kind: current::TokenKind,
         len: usize,
-        days_to_live: i64,
+        days_to_live: Option<i64>,
+        scopes: &[current::Scope],
+        hasher: &auth::TokenHasher,
     ) -> (String, Self) {
         let before_hash = auth::random_string(len);
-        let expires = match kind {
-            current::TokenKind::Session => {
-                Some(chrono::Utc::now() + chrono::Duration::days(days_to_live))
-            }
-            current::TokenKind::Api => None,
+        let scopes = match kind {
+            current::TokenKind::Session => current::Scope::ALL,
+            current::TokenKind::Api => scopes,
+            current::TokenKind::Login => &[],
         };
         let token = Self {
+            id: auth::random_string(Self::ID_LENGTH),
             user: email.to_string(),
-            hash: auth::hash(before_hash.as_str()),
+            hash: hasher.hash(before_hash.as_str()),
             kind,
-            expires,
+            created: Some(chrono::Utc::now()),
+            expires: days_to_live.map(|days| chrono::Utc::now() + chrono::Duration::days(days)),
+            scopes: scopes.to_vec(),
+            used: None,
         };
         (before_hash, token)
     }
+    pub fn has_scope(&self, scope: current::Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
     pub fn is_expired(&self) -> bool {
         if self.expires.is_none() {
             return false;
@@ -101,65 +243,69 @@ impl current::Token {
 
 // ================================================================================================
 
-impl ToCurrent<current::UserKind> for previous::UserKind {
-    fn to_current(&self) -> current::UserKind {
+// Conversions from the previous directory, version 1, to the first version of
+// the current directory. Later versions are reached through the migrations.
+
+impl ToCurrent<v2::UserKind> for previous::UserKind {
+    fn to_current(&self) -> v2::UserKind {
         use previous::UserKind::*;
         match self {
-            Redcap => current::UserKind::Redcap,
-            Manual => current::UserKind::Manual,
+            Redcap => v2::UserKind::Redcap,
+            Manual => v2::UserKind::Manual,
         }
     }
 }
 
-impl ToCurrent<current::Site> for previous::Site {
-    fn to_current(&self) -> current::Site {
+impl ToCurrent<v2::Site> for previous::Site {
+    fn to_current(&self) -> v2::Site {
         use previous::Site::*;
         match self {
-            Melbourne => current::Site::Melbourne,
-            Sydney => current::Site::Sydney,
-            Adelaide => current::Site::Adelaide,
-            Brisbane => current::Site::Brisbane,
-            Newcastle => current::Site::Newcastle,
-            Perth => current::Site::Perth,
+            Melbourne => v2::Site::Melbourne,
+            Sydney => v2::Site::Sydney,
+            Adelaide => v2::Site::Adelaide,
+            Brisbane => v2::Site::Brisbane,
+            Newcastle => v2::Site::Newcastle,
+            Perth => v2::Site::Perth,
         }
     }
 }
 
-impl ToCurrent<current::AccessGroup> for previous::AccessGroup {
-    fn to_current(&self) -> current::AccessGroup {
+impl ToCurrent<v2::AccessGroup> for previous::AccessGroup {
+    fn to_current(&self) -> v2::AccessGroup {
         use previous::AccessGroup::*;
         match self {
-            Site(site) => current::AccessGroup::Site(site.to_current()),
-            Unrestricted => current::AccessGroup::Unrestricted,
-            Admin => current::AccessGroup::Admin,
+            Site(site) => v2::AccessGroup::Site(site.to_current()),
+            Unrestricted => v2::AccessGroup::Unrestricted,
+            Admin => v2::AccessGroup::Admin,
         }
     }
 }
 
-impl ToCurrent<current::User> for previous::User {
-    fn to_current(&self) -> current::User {
-        current::User {
+impl ToCurrent<v2::User> for previous::User {
+    fn to_current(&self) -> v2::User {
+        let access_group = self.access_group.to_current();
+        v2::User {
             email: self.email.to_lowercase(),
-            access_group: self.access_group.to_current(),
+            access_group,
             kind: self.kind.to_current(),
             deidentified_export: self.deidentified_export,
         }
     }
 }
 
-impl ToCurrent<current::TokenKind> for previous::TokenKind {
-    fn to_current(&self) -> current::TokenKind {
+impl ToCurrent<v2::TokenKind> for previous::TokenKind {
+    fn to_current(&self) -> v2::TokenKind {
         use previous::TokenKind::*;
         match self {
-            Session => current::TokenKind::Session,
-            Api => current::TokenKind::Api,
+            Session => v2::TokenKind::Session,
+            Api => v2::TokenKind::Api,
         }
     }
 }
 
-impl ToCurrent<current::Token> for previous::Token {
-    fn to_current(&self) -> current::Token {
-        current::Token {
+impl ToCurrent<v2::Token> for previous::Token {
+    fn to_current(&self) -> v2::Token {
+        v2::Token {
             user: self.user.to_lowercase(),
             hash: self.hash.clone(),
             kind: self.kind.to_current(),
@@ -168,36 +314,36 @@ impl ToCurrent<current::Token> for previous::Token {
     }
 }
 
-impl ToCurrent<current::Gender> for previous::Gender {
-    fn to_current(&self) -> current::Gender {
+impl ToCurrent<v2::Gender> for previous::Gender {
+    fn to_current(&self) -> v2::Gender {
         use previous::Gender::*;
         match self {
-            Female => current::Gender::Female,
-            Male => current::Gender::Male,
-            Other => current::Gender::Other,
+            Female => v2::Gender::Female,
+            Male => v2::Gender::Male,
+            Other => v2::Gender::Other,
         }
     }
 }
 
-impl ToCurrent<current::Occupation> for previous::Occupation {
-    fn to_current(&self) -> current::Occupation {
+impl ToCurrent<v2::Occupation> for previous::Occupation {
+    fn to_current(&self) -> v2::Occupation {
         use previous::Occupation::*;
         match self {
-            Nursing => current::Occupation::Nursing,
-            Medical => current::Occupation::Medical,
-            Administrative => current::Occupation::Administrative,
-            AlliedHealth => current::Occupation::AlliedHealth,
-            Laboratory => current::Occupation::Laboratory,
-            Ancillary => current::Occupation::Ancillary,
-            Research => current::Occupation::Research,
-            Other(s) => current::Occupation::Other(s.clone()),
+            Nursing => v2::Occupation::Nursing,
+            Medical => v2::Occupation::Medical,
+            Administrative => v2::Occupation::Administrative,
+            AlliedHealth => v2::Occupation::AlliedHealth,
+            Laboratory => v2::Occupation::Laboratory,
+            Ancillary => v2::Occupation::Ancillary,
+            Research => v2::Occupation::Research,
+            Other(s) => v2::Occupation::Other(s.clone()),
         }
     }
 }
 
-impl ToCurrent<current::Participant> for previous::Participant {
-    fn to_current(&self) -> current::Participant {
-        current::Participant {
+impl ToCurrent<v2::Participant> for previous::Participant {
+    fn to_current(&self) -> v2::Participant {
+        v2::Participant {
             pid: self.pid.clone(),
             site: self.site.to_current(),
             email: self.email.clone().map(|x| x.to_lowercase()),
@@ -206,11 +352,10 @@ impl ToCurrent<current::Participant> for previous::Participant {
             date_birth: self.date_birth,
             age_recruitment: self
                 .date_birth
-                .map(|dob| {
+                .and_then(|dob| {
                     self.date_screening
                         .map(|date_screening| (date_screening - dob).num_days() as f64 / 365.25)
-                })
-                .flatten(),
+                }),
             height: self.height,
             weight: self.weight,
             bmi: self.bmi,
@@ -220,21 +365,21 @@ impl ToCurrent<current::Participant> for previous::Participant {
     }
 }
 
-impl ToCurrent<current::VaccinationStatus> for previous::VaccinationStatus {
-    fn to_current(&self) -> current::VaccinationStatus {
+impl ToCurrent<v2::VaccinationStatus> for previous::VaccinationStatus {
+    fn to_current(&self) -> v2::VaccinationStatus {
         use previous::VaccinationStatus::*;
         match self {
-            Australia => current::VaccinationStatus::Australia,
-            Overseas => current::VaccinationStatus::Overseas,
-            Unknown => current::VaccinationStatus::Unknown,
-            No => current::VaccinationStatus::No,
+            Australia => v2::VaccinationStatus::Australia,
+            Overseas => v2::VaccinationStatus::Overseas,
+            Unknown => v2::VaccinationStatus::Unknown,
+            No => v2::VaccinationStatus::No,
         }
     }
 }
 
-impl ToCurrent<current::VaccinationHistory> for previous::VaccinationHistory {
-    fn to_current(&self) -> current::VaccinationHistory {
-        current::VaccinationHistory {
+impl ToCurrent<v2::VaccinationHistory> for previous::VaccinationHistory {
+    fn to_current(&self) -> v2::VaccinationHistory {
+        v2::VaccinationHistory {
             pid: self.pid.clone(),
             year: self.year,
             status: self.status.map(|s| s.to_current()),
@@ -242,9 +387,9 @@ impl ToCurrent<current::VaccinationHistory> for previous::VaccinationHistory {
     }
 }
 
-impl ToCurrent<current::Schedule> for previous::Schedule {
-    fn to_current(&self) -> current::Schedule {
-        current::Schedule {
+impl ToCurrent<v2::Schedule> for previous::Schedule {
+    fn to_current(&self) -> v2::Schedule {
+        v2::Schedule {
             pid: self.pid.clone(),
             year: self.year,
             day: self.day,
@@ -253,32 +398,32 @@ impl ToCurrent<current::Schedule> for previous::Schedule {
     }
 }
 
-impl ToCurrent<current::SwabResult> for previous::SwabResult {
-    fn to_current(&self) -> current::SwabResult {
+impl ToCurrent<v2::SwabResult> for previous::SwabResult {
+    fn to_current(&self) -> v2::SwabResult {
         use previous::SwabResult::*;
         match self {
-            InfluenzaAUnsubtyped => current::SwabResult::InfluenzaAUnsubtyped,
-            InfluenzaAh3 => current::SwabResult::InfluenzaAh3,
-            InfluenzaAh1 => current::SwabResult::InfluenzaAh1,
-            InfluenzaBNoLineage => current::SwabResult::InfluenzaBNoLineage,
-            InfluenzaBVic => current::SwabResult::InfluenzaBVic,
-            InfluenzaBYam => current::SwabResult::InfluenzaBYam,
-            InfluenzaC => current::SwabResult::InfluenzaC,
-            Parainfluenza => current::SwabResult::Parainfluenza,
-            HumanMetapneumovirus => current::SwabResult::HumanMetapneumovirus,
-            Picornavirus => current::SwabResult::Picornavirus,
-            Adenovirus => current::SwabResult::Adenovirus,
-            CoronavirusSars => current::SwabResult::CoronavirusSars,
-            CoronavirusSarsCoV2 => current::SwabResult::CoronavirusSarsCoV2,
-            Other(s) => current::SwabResult::Other(s.clone()),
-            Negative => current::SwabResult::Negative,
+            InfluenzaAUnsubtyped => v2::SwabResult::InfluenzaAUnsubtyped,
+            InfluenzaAh3 => v2::SwabResult::InfluenzaAh3,
+            InfluenzaAh1 => v2::SwabResult::InfluenzaAh1,
+            InfluenzaBNoLineage => v2::SwabResult::InfluenzaBNoLineage,
+            InfluenzaBVic => v2::SwabResult::InfluenzaBVic,
+            InfluenzaBYam => v2::SwabResult::InfluenzaBYam,
+            InfluenzaC => v2::SwabResult::InfluenzaC,
+            Parainfluenza => v2::SwabResult::Parainfluenza,
+            HumanMetapneumovirus => v2::SwabResult::HumanMetapneumovirus,
+            Picornavirus => v2::SwabResult::Picornavirus,
+            Adenovirus => v2::SwabResult::Adenovirus,
+            CoronavirusSars => v2::SwabResult::CoronavirusSars,
+            CoronavirusSarsCoV2 => v2::SwabResult::CoronavirusSarsCoV2,
+            Other(s) => v2::SwabResult::Other(s.clone()),
+            Negative => v2::SwabResult::Negative,
         }
     }
 }
 
-impl ToCurrent<current::WeeklySurvey> for previous::WeeklySurvey {
-    fn to_current(&self) -> current::WeeklySurvey {
-        current::WeeklySurvey {
+impl ToCurrent<v2::WeeklySurvey> for previous::WeeklySurvey {
+    fn to_current(&self) -> v2::WeeklySurvey {
+        v2::WeeklySurvey {
             pid: self.pid.clone(),
             year: self.year,
             index: self.index,
@@ -295,10 +440,10 @@ impl ToCurrent<current::WeeklySurvey> for previous::WeeklySurvey {
     }
 }
 
-impl ToCurrent<current::Withdrawn> for previous::Withdrawn {
-    fn to_current(&self) -> current::Withdrawn {
+impl ToCurrent<v2::Withdrawn> for previous::Withdrawn {
+    fn to_current(&self) -> v2::Withdrawn {
         use chrono::Datelike;
-        current::Withdrawn {
+        v2::Withdrawn {
             pid: self.pid.clone(),
             year: self.date.map(|date| date.year()).unwrap_or(0) as u32,
             date: self.date,
@@ -307,9 +452,9 @@ impl ToCurrent<current::Withdrawn> for previous::Withdrawn {
     }
 }
 
-impl ToCurrent<current::Virus> for previous::Virus {
-    fn to_current(&self) -> current::Virus {
-        current::Virus {
+impl ToCurrent<v2::Virus> for previous::Virus {
+    fn to_current(&self) -> v2::Virus {
+        v2::Virus {
             name: self.name.clone(),
             short_name: self.short_name.clone(),
             clade: self.clade.clone(),
@@ -317,9 +462,9 @@ impl ToCurrent<current::Virus> for previous::Virus {
     }
 }
 
-impl ToCurrent<current::Serology> for previous::Serology {
-    fn to_current(&self) -> current::Serology {
-        current::Serology {
+impl ToCurrent<v2::Serology> for previous::Serology {
+    fn to_current(&self) -> v2::Serology {
+        v2::Serology {
             pid: self.pid.clone(),
             year: self.year,
             day: self.day,
@@ -329,36 +474,36 @@ impl ToCurrent<current::Serology> for previous::Serology {
     }
 }
 
-impl ToCurrent<current::StudyGroup> for previous::StudyGroup {
-    fn to_current(&self) -> current::StudyGroup {
+impl ToCurrent<v2::StudyGroup> for previous::StudyGroup {
+    fn to_current(&self) -> v2::StudyGroup {
         match self {
-            previous::StudyGroup::MainOnly => current::StudyGroup::MainOnly,
-            previous::StudyGroup::MainAndNested => current::StudyGroup::MainAndNested,
+            previous::StudyGroup::MainOnly => v2::StudyGroup::MainOnly,
+            previous::StudyGroup::MainAndNested => v2::StudyGroup::MainAndNested,
         }
     }
 }
 
-impl ToCurrent<current::ConsentDisease> for previous::ConsentDisease {
-    fn to_current(&self) -> current::ConsentDisease {
+impl ToCurrent<v2::ConsentDisease> for previous::ConsentDisease {
+    fn to_current(&self) -> v2::ConsentDisease {
         match self {
-            previous::ConsentDisease::Flu => current::ConsentDisease::Flu,
-            previous::ConsentDisease::Covid => current::ConsentDisease::Covid,
+            previous::ConsentDisease::Flu => v2::ConsentDisease::Flu,
+            previous::ConsentDisease::Covid => v2::ConsentDisease::Covid,
         }
     }
 }
 
-impl ToCurrent<current::ConsentForm> for previous::ConsentForm {
-    fn to_current(&self) -> current::ConsentForm {
+impl ToCurrent<v2::ConsentForm> for previous::ConsentForm {
+    fn to_current(&self) -> v2::ConsentForm {
         match self {
-            previous::ConsentForm::Paper => current::ConsentForm::Paper,
-            previous::ConsentForm::Electronic => current::ConsentForm::Electronic,
+            previous::ConsentForm::Paper => v2::ConsentForm::Paper,
+            previous::ConsentForm::Electronic => v2::ConsentForm::Electronic,
         }
     }
 }
 
-impl ToCurrent<current::Consent> for previous::Consent {
-    fn to_current(&self) -> current::Consent {
-        current::Consent {
+impl ToCurrent<v2::Consent> for previous::Consent {
+    fn to_current(&self) -> v2::Consent {
+        v2::Consent {
             pid: self.pid.clone(),
             year: self.year,
             disease: self.disease.to_current(),
@@ -368,9 +513,9 @@ impl ToCurrent<current::Consent> for previous::Consent {
     }
 }
 
-impl ToCurrent<current::YearChange> for previous::YearChange {
-    fn to_current(&self) -> current::YearChange {
-        current::YearChange {
+impl ToCurrent<v2::YearChange> for previous::YearChange {
+    fn to_current(&self) -> v2::YearChange {
+        v2::YearChange {
             record_id: self.record_id.clone(),
             year: self.year,
             pid: self.pid.clone(),
@@ -379,9 +524,9 @@ impl ToCurrent<current::YearChange> for previous::YearChange {
     }
 }
 
-impl ToCurrent<current::Bleed> for previous::Bleed {
-    fn to_current(&self) -> current::Bleed {
-        current::Bleed {
+impl ToCurrent<v2::Bleed> for previous::Bleed {
+    fn to_current(&self) -> v2::Bleed {
+        v2::Bleed {
             pid: self.pid.clone(),
             year: self.year,
             day: self.day,
@@ -0,0 +1,21 @@
+use super::v2;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Scope {
+    ReadTables,
+    Sync,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Token {
+    pub id: String,
+    pub user: String,
+    pub hash: String,
+    pub kind: v2::TokenKind,
+    pub created: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+    pub scopes: Vec<Scope>,
+}
@@ -0,0 +1,19 @@
+use super::v2;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Capability {
+    Sync,
+    ViewIdentifiable,
+    UploadLabResults,
+    ManageUsers,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub email: String,
+    pub access_group: v2::AccessGroup,
+    pub kind: v2::UserKind,
+    pub deidentified_export: bool,
+    pub capabilities: Vec<Capability>,
+}
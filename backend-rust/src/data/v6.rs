@@ -0,0 +1,22 @@
+use super::v4;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TokenKind {
+    Session,
+    Api,
+    Login,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Token {
+    pub id: String,
+    pub user: String,
+    pub hash: String,
+    pub kind: TokenKind,
+    pub created: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+    pub scopes: Vec<v4::Scope>,
+    pub used: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,225 @@
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+/// Differences between two versions of the same table. Rows are identified
+/// by their primary key, or by their whole contents if the table has none.
+#[derive(Serialize, schemars::JsonSchema, Debug, Clone)]
+pub struct TableDiff {
+    pub table: String,
+    pub added: Vec<serde_json::Value>,
+    pub removed: Vec<serde_json::Value>,
+    pub changed: Vec<serde_json::Value>,
+}
+
+/// One row that differs between two versions of a table.
+/// `before` is missing for added rows, `after` is missing for removed rows.
+#[derive(Serialize, Debug, Clone)]
+pub struct RowChange {
+    pub key: serde_json::Value,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+    pub fn from_changes(table: &str, changes: &[RowChange]) -> Self {
+        let mut diff = Self {
+            table: table.to_string(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for change in changes {
+            let keys = match (&change.before, &change.after) {
+                (None, _) => &mut diff.added,
+                (_, None) => &mut diff.removed,
+                _ => &mut diff.changed,
+            };
+            keys.push(change.key.clone());
+        }
+        diff
+    }
+}
+
+/// Key of a plain JSON row made of the values of the key columns.
+/// One column gives its value, several give an array, none give the whole row.
+pub fn row_key(key_columns: &[&str], row: &serde_json::Value) -> serde_json::Value {
+    if key_columns.is_empty() {
+        return row.clone();
+    }
+    let values: Vec<serde_json::Value> = key_columns
+        .iter()
+        .map(|c| row.get(c).cloned().unwrap_or(serde_json::Value::Null))
+        .collect();
+    match values.len() {
+        1 => values.into_iter().next().unwrap(),
+        _ => serde_json::Value::Array(values),
+    }
+}
+
+/// Compares plain JSON rows, keyed by the values of the key columns
+pub fn diff_rows(
+    table: &str,
+    key_columns: &[&str],
+    before: &[serde_json::Value],
+    after: &[serde_json::Value],
+) -> TableDiff {
+    TableDiff::from_changes(table, &changed_rows(key_columns, before, after))
+}
+
+/// Rows that differ between plain JSON rows, keyed by the values of the key columns
+pub fn changed_rows(
+    key_columns: &[&str],
+    before: &[serde_json::Value],
+    after: &[serde_json::Value],
+) -> Vec<RowChange> {
+    changes_keyed(
+        before.iter().map(|r| (row_key(key_columns, r), r)),
+        after.iter().map(|r| (row_key(key_columns, r), r)),
+    )
+}
+
+/// Compares rows that already come with their keys. Rows with the same key are
+/// matched up in order, so duplicate keys show up as added or removed.
+pub fn changes_keyed<'a>(
+    before: impl Iterator<Item = (serde_json::Value, &'a serde_json::Value)>,
+    after: impl Iterator<Item = (serde_json::Value, &'a serde_json::Value)>,
+) -> Vec<RowChange> {
+    let mut before_by_key: BTreeMap<String, (serde_json::Value, Vec<&serde_json::Value>)> =
+        BTreeMap::new();
+    for (key, row) in before {
+        before_by_key
+            .entry(key.to_string())
+            .or_insert_with(|| (key, Vec::new()))
+            .1
+            .push(row);
+    }
+
+    let mut changes = Vec::new();
+
+    for (key, row) in after {
+        match before_by_key.get_mut(&key.to_string()) {
+            Some((_, rows)) if !rows.is_empty() => {
+                let old = rows.remove(0);
+                if old != row {
+                    changes.push(RowChange {
+                        key,
+                        before: Some(old.clone()),
+                        after: Some(row.clone()),
+                    });
+                }
+            }
+            _ => changes.push(RowChange {
+                key,
+                before: None,
+                after: Some(row.clone()),
+            }),
+        }
+    }
+
+    for (key, rows) in before_by_key.into_values() {
+        for row in rows {
+            changes.push(RowChange {
+                key: key.clone(),
+                before: Some(row.clone()),
+                after: None,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Key as it's written in a URL. Strings are unquoted, composite key values
+/// are separated by commas.
+pub fn key_text(key: &serde_json::Value) -> String {
+    match key {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(key_text)
+            .collect::<Vec<String>>()
+            .join(","),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keyed_diff_of_added_removed_and_changed_rows() {
+        let before = vec![
+            json!({"pid": "P1", "site": "Perth"}),
+            json!({"pid": "P2", "site": "Perth"}),
+            json!({"pid": "P3", "site": "Perth"}),
+        ];
+        let after = vec![
+            json!({"pid": "P3", "site": "Perth"}),
+            json!({"pid": "P2", "site": "Sydney"}),
+            json!({"pid": "P4", "site": "Perth"}),
+        ];
+        let diff = diff_rows("Participant", &["pid"], &before, &after);
+        assert_eq!(diff.table, "Participant");
+        assert_eq!(diff.added, vec![json!("P4")]);
+        assert_eq!(diff.removed, vec![json!("P1")]);
+        assert_eq!(diff.changed, vec![json!("P2")]);
+    }
+
+    #[test]
+    fn same_rows_in_another_order_are_no_diff() {
+        let rows = vec![json!({"pid": "P1"}), json!({"pid": "P2"})];
+        let reversed: Vec<serde_json::Value> = rows.iter().rev().cloned().collect();
+        assert!(diff_rows("Participant", &["pid"], &rows, &reversed).is_empty());
+    }
+
+    #[test]
+    fn composite_keys() {
+        let before = vec![json!({"pid": "P1", "year": 2020, "day": 0})];
+        let after = vec![
+            json!({"pid": "P1", "year": 2020, "day": 7}),
+            json!({"pid": "P1", "year": 2021, "day": 0}),
+        ];
+        let diff = diff_rows("Bleed", &["pid", "year"], &before, &after);
+        assert_eq!(diff.added, vec![json!(["P1", 2021])]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.changed, vec![json!(["P1", 2020])]);
+        assert_eq!(key_text(&diff.added[0]), "P1,2021");
+    }
+
+    #[test]
+    fn rows_without_key_are_their_own_key() {
+        let before = vec![json!({"pid": "P1", "consent": "a"})];
+        let after = vec![json!({"pid": "P1", "consent": "b"})];
+        let diff = diff_rows("Consent", &[], &before, &after);
+        assert_eq!(diff.added, vec![after[0].clone()]);
+        assert_eq!(diff.removed, vec![before[0].clone()]);
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn duplicate_keys_are_matched_in_order() {
+        let row = json!({"pid": "P1"});
+        let before = vec![row.clone()];
+        let after = vec![row.clone(), row.clone()];
+        let changes = changed_rows(&[], &before, &after);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].before.is_none());
+        assert_eq!(changes[0].after, Some(row));
+    }
+
+    #[test]
+    fn row_changes_carry_both_versions() {
+        let before = vec![json!({"pid": "P1", "site": "Perth"})];
+        let after = vec![json!({"pid": "P1", "site": "Sydney"})];
+        let changes = changed_rows(&["pid"], &before, &after);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, json!("P1"));
+        assert_eq!(changes[0].before, Some(before[0].clone()));
+        assert_eq!(changes[0].after, Some(after[0].clone()));
+    }
+}
@@ -0,0 +1,273 @@
+use super::{json::JsonStorage, lock::DbLock, migration, snapshot, storage::Storage, DbDirs};
+use crate::Result;
+use anyhow::{bail, Context};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::Rng;
+use std::convert::TryFrom;
+use std::path::Path;
+
+/// Length of the random nonce stored in front of every encrypted file
+const NONCE_LENGTH: usize = 24;
+
+/// Key the tables are encrypted with
+#[derive(Clone, Copy)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Key written as 64 hex digits
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        let bytes = hex::decode(hex_key.trim()).context("storage key is not valid hex")?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => bail!("storage key must be 32 bytes, got {}", bytes.len()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Key {
+    /// Never shows the key itself
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Encrypts the contents of a table file with XChaCha20-Poly1305.
+/// The table name is authenticated along with the contents so that
+/// files can't be swapped between tables. The result is the hex encoded
+/// nonce followed by the ciphertext.
+pub fn encrypt(key: &Key, table: &str, contents: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce);
+    let payload = Payload {
+        msg: contents.as_bytes(),
+        aad: table.as_bytes(),
+    };
+    let ciphertext = cipher(key)
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| anyhow::anyhow!("table {} failed to encrypt", table))?;
+    Ok(format!("{}{}", hex::encode(nonce), hex::encode(ciphertext)))
+}
+
+/// Reverses `encrypt`, fails if the file was encrypted with a different key,
+/// for a different table or was modified
+pub fn decrypt(key: &Key, table: &str, contents: &str) -> Result<String> {
+    let bytes = hex::decode(contents.trim()).context(format!("table {} is not hex", table))?;
+    if bytes.len() < NONCE_LENGTH {
+        bail!("table {} is too short to be encrypted", table);
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: table.as_bytes(),
+    };
+    let plaintext = cipher(key)
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "table {} failed to decrypt, wrong key or modified file",
+                table
+            )
+        })?;
+    String::from_utf8(plaintext).context(format!("table {} is not valid UTF-8", table))
+}
+
+fn cipher(key: &Key) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key.0))
+}
+
+/// Rewrites every table under `root` with the new key, both the current data
+/// and the snapshots. `from` is the key the tables are encrypted with now,
+/// `None` if they are plain JSON. Each directory is rewritten atomically and
+/// directories already encrypted with the new key are skipped, so an
+/// interrupted run can be started again with the same keys.
+pub fn reencrypt(root: &Path, from: Option<Key>, to: Key) -> Result<()> {
+    let _lock = DbLock::acquire(root)?;
+    let dirs = DbDirs::locate(root);
+    let mut data_dirs = vec![dirs.current.clone()];
+    for info in snapshot::list(&dirs)? {
+        data_dirs.push(snapshot::find(&dirs, info.id.as_str())?);
+    }
+    let specs = super::table_specs();
+    for dir in data_dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut old = JsonStorage::open(dir.as_path(), from)?;
+        let mut new = JsonStorage::open(dir.as_path(), Some(to))?;
+        let tables = match migration::load(&mut old, &specs) {
+            Ok(tables) => tables,
+            Err(e) => {
+                if migration::load(&mut new, &specs).is_ok() {
+                    log::info!("tables in {:?} already use the new key", dir);
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+        log::info!("re-encrypting tables in {:?}", dir);
+        let version = old.version()?;
+        let writes = migration::table_writes(&specs, tables);
+        match version {
+            Some(version) => new.store_version(&writes, version)?,
+            None => new.store(&writes)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth, data::current, db::storage::StorageConfig, db::storage::StorageKind, db::Db,
+    };
+    use std::fs;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn key(hex: &str) -> Key {
+        Key::from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let contents = r#"[{"pid":"P1","email":"p1@example.com"}]"#;
+        let encrypted = encrypt(&key(KEY), "Participant", contents).unwrap();
+        assert!(!encrypted.contains("p1@example.com"));
+        assert_eq!(
+            decrypt(&key(KEY), "Participant", encrypted.as_str()).unwrap(),
+            contents
+        );
+        // Fresh nonce every time
+        assert_ne!(
+            encrypt(&key(KEY), "Participant", contents).unwrap(),
+            encrypted
+        );
+    }
+
+    #[test]
+    fn wrong_key_table_or_contents_fail() {
+        let encrypted = encrypt(&key(KEY), "Participant", "[]").unwrap();
+        assert!(decrypt(&key(OTHER_KEY), "Participant", encrypted.as_str()).is_err());
+        assert!(decrypt(&key(KEY), "Schedule", encrypted.as_str()).is_err());
+        let mut tampered = encrypted.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(decrypt(&key(KEY), "Participant", tampered.as_str()).is_err());
+        assert!(decrypt(&key(KEY), "Participant", "00").is_err());
+        assert!(decrypt(&key(KEY), "Participant", "[]").is_err());
+    }
+
+    #[test]
+    fn key_has_to_be_32_bytes_of_hex() {
+        assert!(Key::from_hex(&KEY[2..]).is_err());
+        assert!(Key::from_hex(KEY.replace('0', "g").as_str()).is_err());
+        assert!(Key::from_hex(format!(" {}\n", KEY).as_str()).is_ok());
+        assert_eq!(format!("{:?}", key(KEY)), "Key(..)");
+    }
+
+    fn open(root: &Path, key: Option<Key>) -> Result<Db> {
+        let config = StorageConfig {
+            kind: match key {
+                Some(_) => StorageKind::EncryptedJson,
+                None => StorageKind::Json,
+            },
+            key,
+        };
+        Db::new(
+            root,
+            config,
+            "admin@example.com",
+            auth::TokenHasher::new(None).unwrap(),
+        )
+    }
+
+    /// Encrypted participant files of the current data and the snapshots
+    fn participant_file_contents(root: &Path) -> Vec<String> {
+        let dirs = DbDirs::locate(root);
+        let mut dirs_with_tables = vec![dirs.current.clone()];
+        for info in snapshot::list(&dirs).unwrap() {
+            dirs_with_tables.push(dirs.snapshots.join(info.id));
+        }
+        dirs_with_tables
+            .iter()
+            .map(|d| {
+                assert!(!d.join("Participant.json").exists());
+                fs::read_to_string(d.join("Participant.json.enc")).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reencrypt_current_data_and_snapshots() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(root.path(), None).unwrap();
+        let mut participant: current::Participant = serde_json::from_value(serde_json::json!({
+            "pid": "P1",
+            "site": "Perth",
+            "email": "p1@example.com",
+        }))
+        .unwrap();
+        db.transaction(|db| db.tables.participants.insert(participant.clone()))
+            .unwrap();
+        db.snapshot("test").unwrap();
+        drop(db);
+
+        reencrypt(root.path(), None, key(KEY)).unwrap();
+        let files = participant_file_contents(root.path());
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|f| !f.contains("p1@example.com")));
+        let db = open(root.path(), Some(key(KEY))).unwrap();
+        assert_eq!(db.tables.participants.current.data.len(), 1);
+        drop(db);
+
+        // Running it again after it's done changes nothing
+        reencrypt(root.path(), None, key(KEY)).unwrap();
+        assert_eq!(participant_file_contents(root.path()), files);
+
+        reencrypt(root.path(), Some(key(KEY)), key(OTHER_KEY)).unwrap();
+        let db = open(root.path(), Some(key(KEY))).unwrap();
+        assert!(db.integrity.quarantined().contains(&"Participant"));
+        drop(db);
+        let db = open(root.path(), Some(key(OTHER_KEY))).unwrap();
+        participant.email = Some("p1@example.com".to_string());
+        assert_eq!(
+            db.tables.participants.current.data[0].email,
+            participant.email
+        );
+    }
+
+    #[test]
+    fn switching_on_encryption_leaves_no_plain_files() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(root.path(), None).unwrap();
+        let participant: current::Participant = serde_json::from_value(serde_json::json!({
+            "pid": "P1",
+            "site": "Perth",
+            "email": "p1@example.com",
+        }))
+        .unwrap();
+        db.transaction(|db| db.tables.participants.insert(participant))
+            .unwrap();
+        db.snapshot("test").unwrap();
+        drop(db);
+
+        // Nothing is written to Participant, its plain files go all the same
+        let db = open(root.path(), Some(key(KEY))).unwrap();
+        assert_eq!(db.tables.participants.current.data.len(), 1);
+        let files = participant_file_contents(root.path());
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|f| !f.contains("p1@example.com")));
+        let dirs = DbDirs::locate(root.path());
+        for info in snapshot::list(&dirs).unwrap() {
+            for dir in [dirs.current.clone(), dirs.snapshots.join(info.id)] {
+                for spec in crate::db::table_specs() {
+                    assert!(!dir.join(format!("{}.json", spec.name)).exists());
+                }
+            }
+        }
+    }
+}
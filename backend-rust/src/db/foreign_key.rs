@@ -0,0 +1,463 @@
+use super::{PrimaryKey, Table, Tables};
+use crate::{data::current, error, Result};
+use anyhow::bail;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+/// What happens when a change leaves rows referring to a missing parent row.
+/// Rows added or changed to refer to a missing parent are rejected
+/// unless the policy is `AllowAndReport`.
+#[derive(Serialize, schemars::JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Refuse to remove a parent that still has rows referring to it
+    Reject,
+    /// Remove the rows referring to a removed parent
+    Cascade,
+    /// Keep the rows and report them
+    AllowAndReport,
+}
+
+/// Relationship between the rows of a table and the primary key of another
+pub struct ForeignKey {
+    pub column: &'static str,
+    pub policy: Policy,
+    check: fn(&mut Tables, &ForeignKey, Check) -> Result<Vec<Issue>>,
+}
+
+/// When a relationship is being checked
+#[derive(Clone, Copy, PartialEq)]
+enum Check {
+    /// Transaction commit, only what the transaction changed is checked
+    /// and the policy is applied
+    Commit,
+    /// Everything is checked, nothing is changed
+    All,
+}
+
+/// Rows referring to a parent row that isn't there
+#[derive(Serialize, schemars::JsonSchema, Debug, Clone)]
+pub struct Issue {
+    pub table: String,
+    pub parent: String,
+    pub column: &'static str,
+    pub policy: Policy,
+    pub value: String,
+    pub rows: usize,
+}
+
+/// Rows that refer to rows of the table with rows `P` by their primary key
+pub trait References<P> {
+    fn reference(&self) -> Option<&str>;
+}
+
+impl<C: super::ParticipantRow> References<current::Participant> for C {
+    fn reference(&self) -> Option<&str> {
+        self.pid()
+    }
+}
+
+impl References<current::User> for current::Token {
+    fn reference(&self) -> Option<&str> {
+        Some(self.user.as_str())
+    }
+}
+
+impl References<current::Virus> for current::Serology {
+    fn reference(&self) -> Option<&str> {
+        Some(self.virus.as_str())
+    }
+}
+
+/// Every relationship between tables. REDCap tables are synced one at a time, so
+/// when REDCap renames or removes a participant their rows refer to a missing
+/// participant until the rest of the tables are synced. Rejecting them would
+/// keep the tables from ever catching up, so those are reported instead, as are
+/// lab results that arrive before the participant does.
+pub const FOREIGN_KEYS: &[ForeignKey] = &[
+    ForeignKey {
+        column: "user",
+        policy: Policy::Cascade,
+        check: |db, fk, check| check_foreign_key(db, fk, check, |db| (&mut db.tokens, &db.users)),
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| {
+                (&mut db.vaccination_history, &db.participants)
+            })
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.schedule, &db.participants))
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| {
+                (&mut db.weekly_survey, &db.participants)
+            })
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.withdrawn, &db.participants))
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.serology, &db.participants))
+        },
+    },
+    ForeignKey {
+        column: "virus",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| check_foreign_key(db, fk, check, |db| (&mut db.serology, &db.virus)),
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.consent, &db.participants))
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.year_change, &db.participants))
+        },
+    },
+    ForeignKey {
+        column: "pid",
+        policy: Policy::AllowAndReport,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.bleed, &db.participants))
+        },
+    },
+];
+
+/// Applies the policies to what the open transaction changed.
+/// Returns the rows that were allowed to refer to missing parents.
+pub fn enforce(db: &mut Tables) -> Result<Vec<Issue>> {
+    enforce_keys(db, FOREIGN_KEYS)
+}
+
+fn enforce_keys(db: &mut Tables, foreign_keys: &[ForeignKey]) -> Result<Vec<Issue>> {
+    let mut issues = Vec::new();
+    for fk in foreign_keys {
+        issues.append(&mut (fk.check)(db, fk, Check::Commit)?);
+    }
+    Ok(issues)
+}
+
+/// All rows referring to missing parents, whatever the policy
+pub fn find_issues(db: &mut Tables) -> Result<Vec<Issue>> {
+    let mut issues = Vec::new();
+    for fk in FOREIGN_KEYS {
+        issues.append(&mut (fk.check)(db, fk, Check::All)?);
+    }
+    Ok(issues)
+}
+
+fn check_foreign_key<C, P>(
+    db: &mut Tables,
+    fk: &ForeignKey,
+    check: Check,
+    tables: fn(&mut Tables) -> (&mut Table<C>, &Table<P>),
+) -> Result<Vec<Issue>>
+where
+    C: References<P> + Clone,
+    P: PrimaryKey<K = String>,
+{
+    let (child, parent) = tables(db);
+    if check == Check::Commit && !child.is_modified() && !parent.is_modified() {
+        return Ok(Vec::new());
+    }
+
+    let parent_keys: HashSet<String> = parent.current.data.iter().map(|p| p.get_pk()).collect();
+    let parent_keys_before: HashSet<String> = match check {
+        Check::Commit => parent.data_before().iter().map(|p| p.get_pk()).collect(),
+        Check::All => HashSet::new(),
+    };
+    let missing_before: HashSet<&str> = match check {
+        Check::Commit => child
+            .data_before()
+            .iter()
+            .filter_map(|r| r.reference())
+            .filter(|r| !parent_keys_before.contains(*r))
+            .collect(),
+        Check::All => HashSet::new(),
+    };
+
+    let mut reported: BTreeMap<String, usize> = BTreeMap::new();
+    let mut cascaded: HashSet<String> = HashSet::new();
+    for reference in child.current.data.iter().filter_map(|r| r.reference()) {
+        if parent_keys.contains(reference) || missing_before.contains(reference) {
+            continue;
+        }
+        let parent_removed = parent_keys_before.contains(reference);
+        match (check, fk.policy, parent_removed) {
+            (Check::All, _, _) | (_, Policy::AllowAndReport, _) => {
+                *reported.entry(reference.to_string()).or_default() += 1
+            }
+            (_, Policy::Cascade, true) => {
+                cascaded.insert(reference.to_string());
+            }
+            (_, Policy::Reject, _) | (_, Policy::Cascade, false) => {
+                bail!(error::Conflict::ForeignKey(
+                    child.name.clone(),
+                    parent.name.clone(),
+                    reference.to_string()
+                ))
+            }
+        }
+    }
+
+    if !cascaded.is_empty() {
+        log::info!(
+            "removing rows of {} referring to removed rows of {}: {:?}",
+            child.name,
+            parent.name,
+            cascaded
+        );
+        child
+            .data_mut()
+            .retain(|r| r.reference().is_none_or(|r| !cascaded.contains(r)));
+    }
+
+    let issues: Vec<Issue> = reported
+        .into_iter()
+        .map(|(value, rows)| Issue {
+            table: child.name.clone(),
+            parent: parent.name.clone(),
+            column: fk.column,
+            policy: fk.policy,
+            value,
+            rows,
+        })
+        .collect();
+    if check == Check::Commit && !issues.is_empty() {
+        log::warn!(
+            "{} values of {}.{} refer to missing rows of {}",
+            issues.len(),
+            child.name,
+            fk.column,
+            parent.name
+        );
+    }
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant(pid: &str) -> current::Participant {
+        current::Participant {
+            pid: pid.to_string(),
+            site: current::Site::Perth,
+            email: None,
+            mobile: None,
+            date_screening: None,
+            date_birth: None,
+            age_recruitment: None,
+            height: None,
+            weight: None,
+            bmi: None,
+            gender: None,
+            occupation: None,
+        }
+    }
+
+    fn schedule(pid: &str) -> current::Schedule {
+        current::Schedule {
+            pid: pid.to_string(),
+            year: 2021,
+            day: 0,
+            date: None,
+        }
+    }
+
+    fn user(email: &str) -> current::User {
+        let access_group = current::AccessGroup::Unrestricted;
+        current::User {
+            email: email.to_string(),
+            access_group,
+            kind: current::UserKind::Manual,
+            deidentified_export: false,
+            capabilities: current::Capability::defaults(access_group, false),
+        }
+    }
+
+    fn token(email: &str) -> current::Token {
+        current::Token {
+            id: format!("id of {}", email),
+            user: email.to_string(),
+            hash: format!("hash of {}", email),
+            kind: current::TokenKind::Session,
+            created: None,
+            expires: None,
+            scopes: Vec::new(),
+            used: None,
+        }
+    }
+
+    /// None of the tables reject missing parents, so this one is made up
+    const REJECT_SCHEDULE: &[ForeignKey] = &[ForeignKey {
+        column: "pid",
+        policy: Policy::Reject,
+        check: |db, fk, check| {
+            check_foreign_key(db, fk, check, |db| (&mut db.schedule, &db.participants))
+        },
+    }];
+
+    /// Makes the change as a transaction, rolled back if the policies refuse it
+    fn commit(tables: &mut Tables, change: impl FnOnce(&mut Tables)) -> Result<Vec<Issue>> {
+        commit_with(tables, FOREIGN_KEYS, change)
+    }
+
+    fn commit_with(
+        tables: &mut Tables,
+        foreign_keys: &[ForeignKey],
+        change: impl FnOnce(&mut Tables),
+    ) -> Result<Vec<Issue>> {
+        tables.begin();
+        change(tables);
+        let result = enforce_keys(tables, foreign_keys);
+        if result.is_err() {
+            tables.rollback();
+        }
+        tables.end();
+        result
+    }
+
+    fn code(e: anyhow::Error) -> &'static str {
+        error::from_anyhow(e).code
+    }
+
+    #[test]
+    fn orphan_insert_is_rejected() {
+        let mut tables = Tables::new();
+        let e = commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.schedule.insert(schedule("P1")).unwrap()
+        })
+        .unwrap_err();
+        assert_eq!(code(e), "foreign_key");
+        assert!(tables.schedule.current.data.is_empty());
+
+        // Fine along with the participant
+        let issues = commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.participants.insert(participant("P1")).unwrap();
+            t.schedule.insert(schedule("P1")).unwrap();
+        })
+        .unwrap();
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn removing_referred_parent_is_rejected() {
+        let mut tables = Tables::new();
+        commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.participants.insert(participant("P1")).unwrap();
+            t.schedule.insert(schedule("P1")).unwrap();
+        })
+        .unwrap();
+        let e = commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.participants.data_mut().clear()
+        })
+        .unwrap_err();
+        assert_eq!(code(e), "foreign_key");
+        assert_eq!(tables.participants.current.data.len(), 1);
+
+        // The rows go first
+        commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.schedule.data_mut().clear()
+        })
+        .unwrap();
+        commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.participants.data_mut().clear()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn tokens_cascade_with_their_user() {
+        let mut tables = Tables::new();
+        commit(&mut tables, |t| {
+            for email in &["a@example.com", "b@example.com"] {
+                t.users.insert(user(email)).unwrap();
+                t.tokens.insert(token(email)).unwrap();
+            }
+        })
+        .unwrap();
+        commit(&mut tables, |t| {
+            t.users.data_mut().retain(|u| u.email != "a@example.com")
+        })
+        .unwrap();
+        let users: Vec<&str> = tables
+            .tokens
+            .current
+            .data
+            .iter()
+            .map(|t| t.user.as_str())
+            .collect();
+        assert_eq!(users, vec!["b@example.com"]);
+
+        // Cascade doesn't let tokens of users that never existed in
+        let e = commit(&mut tables, |t| {
+            t.tokens.insert(token("c@example.com")).unwrap()
+        })
+        .unwrap_err();
+        assert_eq!(code(e), "foreign_key");
+    }
+
+    #[test]
+    fn allowed_orphans_are_reported() {
+        let mut tables = Tables::new();
+        let serology = current::Serology {
+            pid: "P1".to_string(),
+            year: 2021,
+            day: 0,
+            virus: "H1".to_string(),
+            titre: 40,
+        };
+        let issues = commit(&mut tables, |t| t.serology.insert(serology).unwrap()).unwrap();
+        let reported: Vec<(&str, &str, Policy)> = issues
+            .iter()
+            .map(|i| (i.parent.as_str(), i.value.as_str(), i.policy))
+            .collect();
+        assert_eq!(
+            reported,
+            vec![
+                ("Participant", "P1", Policy::AllowAndReport),
+                ("Virus", "H1", Policy::AllowAndReport)
+            ]
+        );
+        assert_eq!(tables.serology.current.data.len(), 1);
+        assert_eq!(find_issues(&mut tables).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn existing_orphans_are_kept() {
+        let mut tables = Tables::new();
+        tables.schedule.replace(vec![schedule("P1")]);
+        assert_eq!(find_issues(&mut tables).unwrap().len(), 1);
+        // Changes to other rows still go through
+        commit_with(&mut tables, REJECT_SCHEDULE, |t| {
+            t.participants.insert(participant("P2")).unwrap();
+            t.schedule.insert(schedule("P2")).unwrap();
+        })
+        .unwrap();
+        assert_eq!(tables.schedule.current.data.len(), 2);
+    }
+}
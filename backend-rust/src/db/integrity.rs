@@ -0,0 +1,299 @@
+use super::{foreign_key, registry::AnyTable, storage::Storage, DbDirs, Tables};
+use crate::{error, Result};
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use std::fs;
+
+/// Name of the report file inside the root directory
+const REPORT_FILE_NAME: &str = "integrity.json";
+
+/// What the integrity check found when the db was opened
+#[derive(serde_derive::Serialize, schemars::JsonSchema, Debug, Clone)]
+pub struct Report {
+    pub checked: DateTime<Utc>,
+    pub tables: Vec<TableReport>,
+    /// Rows referring to missing parent rows, see `foreign_key::FOREIGN_KEYS`
+    pub foreign_keys: Vec<foreign_key::Issue>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema, Debug, Clone)]
+pub struct TableReport {
+    pub table: String,
+    pub rows: usize,
+    /// Quarantined tables aren't served or written until restored from a snapshot
+    pub quarantined: bool,
+    pub problems: Vec<String>,
+}
+
+impl Report {
+    /// Whether any table is quarantined
+    pub fn is_degraded(&self) -> bool {
+        self.tables.iter().any(|t| t.quarantined)
+    }
+    pub fn quarantined(&self) -> Vec<&str> {
+        self.tables
+            .iter()
+            .filter(|t| t.quarantined)
+            .map(|t| t.table.as_str())
+            .collect()
+    }
+    /// Fails if the table is quarantined
+    pub fn check_available(&self, table: &str) -> Result<()> {
+        if self.quarantined().contains(&table) {
+            bail!(error::Degraded::Quarantined(table.to_string()));
+        }
+        Ok(())
+    }
+    /// Fails if the table is quarantined, writing it would replace the rows
+    /// that failed the check with the ones that didn't
+    pub fn check_writable(&self, table: &str) -> Result<()> {
+        if self.quarantined().contains(&table) {
+            bail!(error::Degraded::ReadOnly(table.to_string()));
+        }
+        Ok(())
+    }
+    /// Fails if any table is quarantined
+    pub fn check_all_writable(&self) -> Result<()> {
+        if self.is_degraded() {
+            bail!(error::Degraded::ReadOnly(self.quarantined().join(", ")));
+        }
+        Ok(())
+    }
+    /// Lifts the table's quarantine once its rows were replaced
+    pub fn release(&mut self, table: &str, rows: usize) {
+        for report in self.tables.iter_mut().filter(|t| t.table == table) {
+            log::info!("table {} is no longer quarantined", table);
+            report.quarantined = false;
+            report.rows = rows;
+        }
+    }
+    /// Writes the report into the root directory, replacing the previous one
+    pub fn write(&self, dirs: &DbDirs) -> Result<()> {
+        let path = dirs.root.join(REPORT_FILE_NAME);
+        fs::write(path.as_path(), serde_json::to_string_pretty(self)?)
+            .context(format!("failed to write {:?}", path))
+    }
+}
+
+/// Reads every table from storage and checks it. Rows have to parse into the
+/// table's row type and primary keys have to be unique. Rows referring to missing
+/// parents are reported, they're kept whatever the relationship's policy since
+/// the policy only applies to changes. A table that fails is quarantined instead of
+/// failing the whole read, it's left empty if its rows didn't parse.
+pub fn check(tables: &mut Tables, storage: &mut dyn Storage) -> Report {
+    log::debug!("checking db integrity");
+    let mut report = Report {
+        checked: Utc::now(),
+        tables: tables
+            .all_mut()
+            .into_iter()
+            .map(|t| read(t, storage))
+            .collect(),
+        foreign_keys: Vec::new(),
+    };
+
+    let issues = match foreign_key::find_issues(tables) {
+        Ok(issues) => issues,
+        Err(e) => {
+            log::error!("foreign key check failed: {:#}", e);
+            Vec::new()
+        }
+    };
+    for issue in issues {
+        // Quarantined parents may be missing rows that are actually there
+        if report.quarantined().contains(&issue.parent.as_str()) {
+            continue;
+        }
+        report.foreign_keys.push(issue);
+    }
+
+    if report.is_degraded() {
+        log::error!(
+            "integrity check failed, quarantined tables: {}",
+            report.quarantined().join(", ")
+        );
+    }
+    report
+}
+
+/// Reads the table if all of its rows parse, leaves it empty otherwise.
+/// Also checks that the primary keys are unique.
+fn read(table: &mut dyn AnyTable, storage: &mut dyn Storage) -> TableReport {
+    let mut report = TableReport {
+        table: table.name().to_string(),
+        rows: 0,
+        quarantined: false,
+        problems: Vec::new(),
+    };
+    if let Err(problems) = table.read_rows(storage) {
+        log::error!("table {} failed to read: {:?}", table.name(), problems);
+        report.quarantined = true;
+        report.problems = problems;
+        return report;
+    }
+    report.rows = table.row_count();
+    if let Err(e) = table.verify_pk() {
+        log::error!("table {} failed primary key check: {}", table.name(), e);
+        report.quarantined = true;
+        report.problems.push(e.to_string());
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, db::storage::StorageKind, db::Db};
+    use serde_json::json;
+    use std::path::Path;
+
+    fn open(root: &Path) -> Db {
+        Db::new(
+            root,
+            StorageKind::Json.into(),
+            "admin@example.com",
+            auth::TokenHasher::new(None).unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Opens the db with the table's file replaced by `rows`
+    fn open_with(table: &str, rows: serde_json::Value) -> (tempfile::TempDir, Db) {
+        let root = tempfile::tempdir().unwrap();
+        let current = open(root.path()).dirs.current.clone();
+        fs::write(current.join(format!("{}.json", table)), rows.to_string()).unwrap();
+        let db = open(root.path());
+        (root, db)
+    }
+
+    fn schedule(pid: &str, day: u32) -> serde_json::Value {
+        json!({"pid": pid, "year": 2021, "day": day, "date": null})
+    }
+
+    fn code(e: anyhow::Error) -> &'static str {
+        error::from_anyhow(e).code
+    }
+
+    #[test]
+    fn clean_db_is_writable() {
+        let root = tempfile::tempdir().unwrap();
+        let mut db = open(root.path());
+        assert!(!db.integrity.is_degraded());
+        db.begin().unwrap();
+        db.commit().unwrap();
+        assert!(root.path().join(REPORT_FILE_NAME).is_file());
+    }
+
+    #[test]
+    fn unparseable_table_is_quarantined() {
+        let (root, mut db) = open_with(
+            "Schedule",
+            json!([schedule("P1", 0), {"pid": "P1", "year": "not a number"}]),
+        );
+        assert_eq!(db.integrity.quarantined(), vec!["Schedule"]);
+        let report = db
+            .integrity
+            .tables
+            .iter()
+            .find(|t| t.table == "Schedule")
+            .unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(db.tables.schedule.current.data.is_empty());
+        // The other tables are still served and written
+        let e = db.integrity.check_available("Schedule").unwrap_err();
+        assert_eq!(code(e), "quarantined");
+        db.integrity.check_available("User").unwrap();
+        assert_eq!(db.tables.users.current.data.len(), 1);
+        db.transaction(|db| {
+            db.tables.users.data_mut()[0].deidentified_export = true;
+            Ok(())
+        })
+        .unwrap();
+        let e = db
+            .transaction(|db| {
+                db.tables.schedule.data_mut().clear();
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(code(e), "read_only");
+        assert_eq!(code(db.write().unwrap_err()), "read_only");
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(root.path().join(REPORT_FILE_NAME)).unwrap())
+                .unwrap();
+        assert_eq!(
+            written["tables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|t| t["quarantined"] == json!(true))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn duplicate_primary_keys_are_quarantined() {
+        let (_root, mut db) = open_with("Schedule", json!([schedule("P1", 0), schedule("P1", 0)]));
+        assert_eq!(db.integrity.quarantined(), vec!["Schedule"]);
+        let report = db
+            .integrity
+            .tables
+            .iter()
+            .find(|t| t.table == "Schedule")
+            .unwrap();
+        assert_eq!(report.rows, 2);
+        let e = db
+            .transaction(|db| {
+                db.tables.schedule.data_mut().pop();
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(code(e), "read_only");
+        // The rollback put the duplicate back
+        assert_eq!(db.tables.schedule.current.data.len(), 2);
+    }
+
+    #[test]
+    fn restoring_a_quarantined_table_releases_it() {
+        let root = tempfile::tempdir().unwrap();
+        let db = open(root.path());
+        let path = db.dirs.current.join("Schedule.json");
+        drop(db);
+        fs::write(path.as_path(), json!([schedule("P1", 0)]).to_string()).unwrap();
+        let db = open(root.path());
+        let id = db.snapshot("good").unwrap().id;
+        drop(db);
+        fs::write(path.as_path(), json!([{"pid": "P1"}]).to_string()).unwrap();
+
+        let mut db = open(root.path());
+        assert_eq!(db.integrity.quarantined(), vec!["Schedule"]);
+        // A snapshot of the broken table doesn't release it
+        let broken = db.snapshot("broken").unwrap().id;
+        db.restore_snapshot("admin@example.com", broken.as_str(), Some("Schedule"))
+            .unwrap_err();
+        assert_eq!(db.integrity.quarantined(), vec!["Schedule"]);
+
+        db.restore_snapshot("admin@example.com", id.as_str(), Some("Schedule"))
+            .unwrap();
+        assert!(!db.integrity.is_degraded());
+        assert_eq!(db.tables.schedule.current.data.len(), 1);
+        db.write().unwrap();
+        drop(db);
+        let db = open(root.path());
+        assert!(!db.integrity.is_degraded());
+        assert_eq!(db.tables.schedule.current.data.len(), 1);
+    }
+
+    #[test]
+    fn orphan_rows_are_reported() {
+        let (_root, db) = open_with("Schedule", json!([schedule("P1", 0), schedule("P1", 1)]));
+        assert!(!db.integrity.is_degraded());
+        let issue = &db.integrity.foreign_keys[0];
+        assert_eq!(
+            (issue.table.as_str(), issue.value.as_str()),
+            ("Schedule", "P1")
+        );
+        assert_eq!(issue.rows, 2);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::Result;
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the journal file inside the data directory
+const JOURNAL_FILE_NAME: &str = "journal.json";
+
+/// Extension given to files that are not yet in place
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Serialized table contents waiting to be put in place
+pub struct PendingWrite {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// What's recorded on disk once all temporary files are ready
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    files: Vec<PathBuf>,
+}
+
+/// Writes all files so that either all of them or none of them land.
+///
+/// Every file is first written (and synced) next to its destination with a
+/// temporary extension. The journal listing the destinations is then written
+/// and renamed into place - that's the commit point. After that the temporary
+/// files are renamed over the destinations and the journal is removed.
+/// A crash before the commit point leaves only temporary files behind,
+/// a crash after it leaves a journal that `recover` replays.
+pub fn write_atomic(dir: &Path, writes: &[PendingWrite]) -> Result<()> {
+    if writes.is_empty() {
+        return Ok(());
+    }
+
+    for write in writes {
+        write_synced(temp_path(&write.path).as_path(), write.contents.as_str())?;
+    }
+
+    let entry = Entry {
+        files: writes.iter().map(|w| w.path.clone()).collect(),
+    };
+    let journal = dir.join(JOURNAL_FILE_NAME);
+    let journal_temp = temp_path(&journal);
+    write_synced(
+        journal_temp.as_path(),
+        serde_json::to_string(&entry)?.as_str(),
+    )?;
+    fs::rename(journal_temp.as_path(), journal.as_path())
+        .context(format!("failed to commit journal {:?}", journal))?;
+    sync_dir(dir)?;
+
+    replay(dir, &entry)?;
+    fs::remove_file(journal.as_path())
+        .context(format!("failed to remove journal {:?}", journal))?;
+    sync_dir(dir)?;
+
+    Ok(())
+}
+
+/// Whether a committed write is waiting for `recover` to put it in place
+pub fn is_pending(dir: &Path) -> bool {
+    dir.join(JOURNAL_FILE_NAME).is_file()
+}
+
+/// Brings the directory to a consistent state after a possible crash.
+/// A committed journal is replayed, any other temporary files are discarded.
+pub fn recover(dir: &Path) -> Result<()> {
+    let journal = dir.join(JOURNAL_FILE_NAME);
+    if journal.is_file() {
+        let contents = fs::read_to_string(journal.as_path())
+            .context(format!("failed to read journal {:?}", journal))?;
+        match serde_json::from_str::<Entry>(contents.as_str()) {
+            Ok(entry) => {
+                log::warn!("found committed journal, replaying {:?}", entry.files);
+                replay(dir, &entry)?;
+            }
+            Err(e) => log::warn!("discarding unreadable journal {:?}: {}", journal, e),
+        }
+        fs::remove_file(journal.as_path())
+            .context(format!("failed to remove journal {:?}", journal))?;
+    }
+
+    for dir_entry in fs::read_dir(dir).context(format!("failed to read directory {:?}", dir))? {
+        let path = dir_entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(TEMP_EXTENSION) {
+            log::warn!("discarding incomplete write {:?}", path);
+            fs::remove_file(path.as_path())
+                .context(format!("failed to remove file {:?}", path))?;
+        }
+    }
+
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Moves temporary files over their destinations. Missing temporary files
+/// are assumed to have been moved already.
+fn replay(dir: &Path, entry: &Entry) -> Result<()> {
+    for path in &entry.files {
+        let temp = temp_path(path);
+        if temp.is_file() {
+            fs::rename(temp.as_path(), path.as_path())
+                .context(format!("failed to move {:?} to {:?}", temp, path))?;
+        }
+    }
+    sync_dir(dir)?;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".");
+    temp.push(TEMP_EXTENSION);
+    PathBuf::from(temp)
+}
+
+fn write_synced(path: &Path, contents: &str) -> Result<()> {
+    let mut file = File::create(path).context(format!("failed to create file {:?}", path))?;
+    file.write_all(contents.as_bytes())
+        .context(format!("failed to write file {:?}", path))?;
+    file.sync_all()
+        .context(format!("failed to sync file {:?}", path))?;
+    Ok(())
+}
+
+/// Makes renames and removals in the directory durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context(format!("failed to sync directory {:?}", dir))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(dir: &Path, name: &str, contents: &str) -> PendingWrite {
+        PendingWrite {
+            path: dir.join(name),
+            contents: contents.to_string(),
+        }
+    }
+
+    /// Every file in the directory with its contents, sorted by name
+    fn files(dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read_to_string(path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn writes_every_file_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("A.json"), "old").unwrap();
+        let writes = [
+            pending(dir.path(), "A.json", "a"),
+            pending(dir.path(), "B.json", "b"),
+        ];
+        write_atomic(dir.path(), &writes).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![
+                ("A.json".to_string(), "a".to_string()),
+                ("B.json".to_string(), "b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn recover_replays_committed_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("A.json"), "old a").unwrap();
+        fs::write(dir.path().join("B.json"), "old b").unwrap();
+        // Interrupted after the commit point with only A moved into place
+        fs::write(dir.path().join("A.json"), "new a").unwrap();
+        fs::write(temp_path(&dir.path().join("B.json")), "new b").unwrap();
+        let entry = Entry {
+            files: vec![dir.path().join("A.json"), dir.path().join("B.json")],
+        };
+        fs::write(
+            dir.path().join(JOURNAL_FILE_NAME),
+            serde_json::to_string(&entry).unwrap(),
+        )
+        .unwrap();
+
+        recover(dir.path()).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![
+                ("A.json".to_string(), "new a".to_string()),
+                ("B.json".to_string(), "new b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn recover_discards_uncommitted_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("A.json"), "old a").unwrap();
+        // Interrupted before the journal was renamed into place
+        fs::write(temp_path(&dir.path().join("A.json")), "new a").unwrap();
+        fs::write(temp_path(&dir.path().join(JOURNAL_FILE_NAME)), "{\"fil").unwrap();
+
+        recover(dir.path()).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![("A.json".to_string(), "old a".to_string())]
+        );
+    }
+
+    #[test]
+    fn recover_discards_unreadable_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("A.json"), "old a").unwrap();
+        fs::write(temp_path(&dir.path().join("A.json")), "new a").unwrap();
+        fs::write(dir.path().join(JOURNAL_FILE_NAME), "not json").unwrap();
+
+        recover(dir.path()).unwrap();
+        assert_eq!(
+            files(dir.path()),
+            vec![("A.json".to_string(), "old a".to_string())]
+        );
+    }
+}
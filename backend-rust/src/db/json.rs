@@ -0,0 +1,174 @@
+use super::{
+    encrypted::{self, Key},
+    journal::{self, PendingWrite},
+    storage::{Storage, TableWrite},
+};
+use crate::Result;
+use anyhow::{bail, Context};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file holding the schema version
+const VERSION_FILE_NAME: &str = "version.json";
+
+/// Keeps every table in `<dir>/<Name>.json`, rewritten whole on every write.
+/// With a key the tables are encrypted and kept in `<dir>/<Name>.json.enc` instead.
+pub struct JsonStorage {
+    dir: PathBuf,
+    key: Option<Key>,
+    read_only: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VersionFile {
+    version: u32,
+}
+
+impl JsonStorage {
+    /// Finishes or discards whatever write was interrupted last time.
+    /// With a key, tables still in plain files are encrypted right away.
+    pub fn open(dir: &Path, key: Option<Key>) -> Result<Self> {
+        journal::recover(dir)?;
+        if let Some(key) = &key {
+            encrypt_plain(dir, key)?;
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+            read_only: false,
+        })
+    }
+    /// Leaves the directory as it is. Refuses to open it if a write was
+    /// interrupted, the files would be read as they were before the write.
+    pub fn open_read_only(dir: &Path, key: Option<Key>) -> Result<Self> {
+        if journal::is_pending(dir) {
+            bail!(
+                "interrupted write in {:?}, start the server once to finish it",
+                dir
+            );
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+            read_only: true,
+        })
+    }
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!("storage at {:?} is opened read-only", self.dir);
+        }
+        Ok(())
+    }
+    fn table_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+    fn encrypted_table_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json.enc", name))
+    }
+    fn table_writes(&self, writes: &[TableWrite]) -> Result<Vec<PendingWrite>> {
+        let mut pending = Vec::with_capacity(writes.len());
+        for write in writes {
+            let name = write.name.as_str();
+            let contents = format!(
+                "[{}]",
+                write
+                    .rows
+                    .iter()
+                    .map(|r| r.data.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            );
+            pending.push(match &self.key {
+                Some(key) => PendingWrite {
+                    path: self.encrypted_table_path(name),
+                    contents: encrypted::encrypt(key, name, contents.as_str())?,
+                },
+                None => PendingWrite {
+                    path: self.table_path(name),
+                    contents,
+                },
+            });
+        }
+        Ok(pending)
+    }
+}
+
+/// Encrypts the plain files of every table in `dir` and removes them, they are
+/// left from before encryption was switched on. A plain file next to an encrypted
+/// one is what's left of an interrupted run and is only removed.
+pub fn encrypt_plain(dir: &Path, key: &Key) -> Result<()> {
+    let mut pending = Vec::new();
+    let mut plain = Vec::new();
+    for spec in super::table_specs() {
+        let name = spec.name.as_str();
+        let path = dir.join(format!("{}.json", name));
+        if !path.is_file() {
+            continue;
+        }
+        let encrypted_path = dir.join(format!("{}.json.enc", name));
+        if !encrypted_path.is_file() {
+            let contents = fs::read_to_string(path.as_path())
+                .context(format!("file {:?} failed to read", path))?;
+            pending.push(PendingWrite {
+                path: encrypted_path,
+                contents: encrypted::encrypt(key, name, contents.as_str())?,
+            });
+        }
+        plain.push(path);
+    }
+    if plain.is_empty() {
+        return Ok(());
+    }
+    log::info!("encrypting {} tables in {:?}", pending.len(), dir);
+    journal::write_atomic(dir, &pending)?;
+    for path in plain {
+        fs::remove_file(path.as_path()).context(format!("failed to remove {:?}", path))?;
+    }
+    Ok(())
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self, name: &str, _key_columns: &[&str]) -> Result<String> {
+        let encrypted_path = self.encrypted_table_path(name);
+        if encrypted_path.is_file() {
+            let key = match &self.key {
+                Some(key) => key,
+                None => bail!("table {} is encrypted but no storage key was given", name),
+            };
+            let contents = fs::read_to_string(encrypted_path.as_path())
+                .context(format!("file {:?} failed to read", encrypted_path))?;
+            return encrypted::decrypt(key, name, contents.as_str());
+        }
+        // Only left from before encryption was switched on if opened read-only
+        let path = self.table_path(name);
+        if !path.is_file() {
+            return Ok("[]".to_string());
+        }
+        fs::read_to_string(path.as_path()).context(format!("file {:?} failed to read", path))
+    }
+    fn store(&mut self, writes: &[TableWrite]) -> Result<()> {
+        self.check_writable()?;
+        journal::write_atomic(self.dir.as_path(), &self.table_writes(writes)?)
+    }
+    fn version(&mut self) -> Result<Option<u32>> {
+        let path = self.dir.join(VERSION_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path.as_path())
+            .context(format!("file {:?} failed to read", path))?;
+        let version_file: VersionFile = serde_json::from_str(contents.as_str())
+            .context(format!("file {:?} failed to parse", path))?;
+        Ok(Some(version_file.version))
+    }
+    fn store_version(&mut self, writes: &[TableWrite], version: u32) -> Result<()> {
+        self.check_writable()?;
+        let mut pending = self.table_writes(writes)?;
+        pending.push(PendingWrite {
+            path: self.dir.join(VERSION_FILE_NAME),
+            contents: serde_json::to_string(&VersionFile { version })?,
+        });
+        journal::write_atomic(self.dir.as_path(), &pending)
+    }
+}
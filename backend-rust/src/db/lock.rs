@@ -0,0 +1,56 @@
+use crate::Result;
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use std::fs::{self, File};
+use std::path::Path;
+
+/// Name of the lock file inside the root directory
+const LOCK_FILE_NAME: &str = "db.lock";
+
+/// Exclusive hold on the root directory. Only one process at a time can write
+/// to the data, or an offline tool and the server would overwrite each other's
+/// changes. Released when dropped, or by the system if the process dies.
+#[derive(Debug)]
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Fails right away if another process holds the lock
+    pub fn acquire(root: &Path) -> Result<Self> {
+        if !root.is_dir() {
+            fs::create_dir_all(root).context(format!("failed to create {:?}", root))?;
+        }
+        let path = root.join(LOCK_FILE_NAME);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.as_path())
+            .context(format!("failed to open {:?}", path))?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                bail!(
+                    "db at {:?} is in use by another process, stop the server first",
+                    root
+                );
+            }
+            return Err(e).context(format!("failed to lock {:?}", path));
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        let root = tempfile::tempdir().unwrap();
+        let lock = DbLock::acquire(root.path()).unwrap();
+        assert!(DbLock::acquire(root.path()).is_err());
+        drop(lock);
+        DbLock::acquire(root.path()).unwrap();
+    }
+}
@@ -0,0 +1,578 @@
+use super::{
+    diff,
+    lock::DbLock,
+    storage::{self, RowWrite, Storage, StorageConfig, TableWrite},
+    DbDirs, ToCurrent, SECRET_TABLES, UNRECORDED_TABLES,
+};
+use crate::{
+    auth,
+    data::{current, previous, v2, v3, v4, v5, v6},
+    Result,
+};
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Version of the data found in the legacy `previous` directory
+pub const PREVIOUS_DIR_VERSION: u32 = 1;
+
+/// Version of the data in a `current` directory written before versions were recorded
+pub const UNMARKED_VERSION: u32 = 2;
+
+/// Every table as plain JSON rows, by table name
+pub type RawTables = BTreeMap<String, Vec<serde_json::Value>>;
+
+/// Upgrades the data from one version to the next
+pub struct Migration {
+    /// Version this migrates from, the result is one version up
+    pub from: u32,
+    pub description: &'static str,
+    pub apply: fn(&mut RawTables) -> Result<()>,
+}
+
+/// All migrations in the order they are applied. To change the schema, add
+/// a migration here that brings the data from the latest version to the next one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "Lowercase emails, compute age at recruitment, set withdrawal year",
+        apply: v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        description: "Give tokens ids and creation times",
+        apply: v2_to_v3,
+    },
+    Migration {
+        from: 3,
+        description: "Give existing tokens every scope",
+        apply: v3_to_v4,
+    },
+    Migration {
+        from: 4,
+        description: "Give users the default capabilities of their access group",
+        apply: v4_to_v5,
+    },
+    Migration {
+        from: 5,
+        description: "Record when tokens were used as login codes",
+        apply: v5_to_v6,
+    },
+    Migration {
+        from: 6,
+        description: "Remove token rows from row history",
+        apply: v6_to_v7,
+    },
+];
+
+/// What storage needs to know about a table to move it around
+pub struct TableSpec {
+    pub name: String,
+    pub key_columns: &'static [&'static str],
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    pub description: String,
+    /// Only the tables the migration changes
+    pub tables: Vec<TableMigrationReport>,
+}
+
+#[derive(serde_derive::Serialize, Debug)]
+pub struct TableMigrationReport {
+    pub table: String,
+    pub rows_before: usize,
+    pub rows_after: usize,
+    pub rows_changed: usize,
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS
+        .last()
+        .map(|m| m.from + 1)
+        .unwrap_or(PREVIOUS_DIR_VERSION)
+}
+
+/// Brings the data in storage to the latest version in one atomic write.
+/// Records the version if it wasn't recorded yet.
+pub fn upgrade(storage: &mut dyn Storage, specs: &[TableSpec]) -> Result<()> {
+    let recorded = storage.version()?;
+    let version = recorded.unwrap_or(UNMARKED_VERSION);
+    if version == latest_version() {
+        if recorded.is_none() {
+            log::debug!("recording data version {}", version);
+            storage.store_version(&[], version)?;
+        }
+        return Ok(());
+    }
+    let mut tables = load(storage, specs)?;
+    apply(&mut tables, version)?;
+    store(storage, specs, tables)
+}
+
+/// Reads every table as plain JSON rows
+pub fn load(storage: &mut dyn Storage, specs: &[TableSpec]) -> Result<RawTables> {
+    let mut tables = RawTables::new();
+    for spec in specs {
+        let contents = storage.load(spec.name.as_str(), spec.key_columns)?;
+        let rows = serde_json::from_str(contents.as_str())
+            .context(format!("table {} failed to parse", spec.name))?;
+        tables.insert(spec.name.clone(), rows);
+    }
+    Ok(tables)
+}
+
+/// Writes every table together with the latest version
+pub fn store(storage: &mut dyn Storage, specs: &[TableSpec], tables: RawTables) -> Result<()> {
+    storage.store_version(&table_writes(specs, tables), latest_version())
+}
+
+/// Every table ready to be written as it is
+pub fn table_writes(specs: &[TableSpec], mut tables: RawTables) -> Vec<TableWrite> {
+    let mut writes = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let rows = tables.remove(&spec.name).unwrap_or_default();
+        writes.push(TableWrite {
+            name: spec.name.clone(),
+            key_columns: spec.key_columns,
+            rows: rows
+                .iter()
+                .map(|row| RowWrite {
+                    key: spec
+                        .key_columns
+                        .iter()
+                        .map(|c| row.get(c).cloned().unwrap_or(serde_json::Value::Null))
+                        .collect(),
+                    data: row.to_string(),
+                })
+                .collect(),
+        });
+    }
+    writes
+}
+
+/// Runs all migrations from the given version up to the latest one
+pub fn apply(tables: &mut RawTables, from: u32) -> Result<Vec<MigrationReport>> {
+    let latest = latest_version();
+    if from > latest {
+        bail!(
+            "data version {} is newer than the latest known version {}",
+            from,
+            latest
+        );
+    }
+    let mut reports = Vec::new();
+    let mut version = from;
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= from) {
+        if migration.from != version {
+            bail!(
+                "no migration from version {}, next one is from {}",
+                version,
+                migration.from
+            );
+        }
+        log::info!(
+            "migrating data from version {} to {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+        let before = tables.clone();
+        (migration.apply)(tables)
+            .context(format!("migration from version {} failed", migration.from))?;
+        reports.push(report(migration, &before, tables));
+        record_history(&before, tables, migration.from + 1)?;
+        version = migration.from + 1;
+    }
+    Ok(reports)
+}
+
+/// Reports what the migrations would do to the data under `root`, writes nothing.
+/// Holds the lock while reading so the server can't be writing at the same time.
+pub fn dry_run(root: &Path, storage_config: StorageConfig) -> Result<Vec<MigrationReport>> {
+    if !root.is_dir() {
+        log::info!("no data found under {:?}", root);
+        return Ok(Vec::new());
+    }
+    let _lock = DbLock::acquire(root)?;
+    let dirs = DbDirs::locate(root);
+    let (mut storage, version) = if dirs.current.is_dir() {
+        let mut storage = storage::open_read_only(storage_config, dirs.current.as_path())?;
+        let version = storage.version()?.unwrap_or(UNMARKED_VERSION);
+        (storage, version)
+    } else if dirs.previous.is_dir() {
+        let storage = storage::open_read_only(storage_config, dirs.previous.as_path())?;
+        (storage, PREVIOUS_DIR_VERSION)
+    } else {
+        log::info!("no data found under {:?}", root);
+        return Ok(Vec::new());
+    };
+    let mut tables = load(storage.as_mut(), &super::table_specs())?;
+    apply(&mut tables, version)
+}
+
+fn report(migration: &Migration, before: &RawTables, after: &RawTables) -> MigrationReport {
+    let empty = Vec::new();
+    let mut tables = Vec::new();
+    for (name, rows_after) in after {
+        let rows_before = before.get(name).unwrap_or(&empty);
+        let rows_changed = rows_before
+            .iter()
+            .zip(rows_after.iter())
+            .filter(|(b, a)| b != a)
+            .count();
+        if rows_changed > 0 || rows_before.len() != rows_after.len() {
+            tables.push(TableMigrationReport {
+                table: name.clone(),
+                rows_before: rows_before.len(),
+                rows_after: rows_after.len(),
+                rows_changed,
+            });
+        }
+    }
+    MigrationReport {
+        from: migration.from,
+        to: migration.from + 1,
+        description: migration.description.to_string(),
+        tables,
+    }
+}
+
+/// Appends every row the migration changed to row history
+fn record_history(before: &RawTables, after: &mut RawTables, version: u32) -> Result<()> {
+    let timestamp = chrono::Utc::now();
+    let empty = Vec::new();
+    let mut entries = Vec::new();
+    for spec in super::table_specs() {
+        let name = spec.name.as_str();
+        if UNRECORDED_TABLES.contains(&name) || SECRET_TABLES.contains(&name) {
+            continue;
+        }
+        let rows_before = before.get(&spec.name).unwrap_or(&empty);
+        let rows_after = after.get(&spec.name).unwrap_or(&empty);
+        for change in diff::changed_rows(spec.key_columns, rows_before, rows_after) {
+            entries.push(serde_json::to_value(current::HistoryEntry {
+                timestamp,
+                actor: current::Actor::Migration(version),
+                table: spec.name.clone(),
+                pk: change.key,
+                before: change.before,
+                after: change.after,
+            })?);
+        }
+    }
+    after
+        .entry("History".to_string())
+        .or_default()
+        .append(&mut entries);
+    Ok(())
+}
+
+/// Converts every row of a table from one version's type to the next one's
+fn convert<P, C>(tables: &mut RawTables, name: &str, step: impl Fn(P) -> C) -> Result<()>
+where
+    P: DeserializeOwned,
+    C: Serialize,
+{
+    let rows = match tables.get_mut(name) {
+        Some(rows) => rows,
+        None => return Ok(()),
+    };
+    for row in rows.iter_mut() {
+        let old: P = serde_json::from_value(row.take())
+            .context(format!("table {} row failed to parse", name))?;
+        *row = serde_json::to_value(step(old))?;
+    }
+    Ok(())
+}
+
+/// Converts every row of a table from the previous directory to version 2
+fn from_previous<P, C>(tables: &mut RawTables, name: &str) -> Result<()>
+where
+    P: DeserializeOwned + ToCurrent<C>,
+    C: Serialize,
+{
+    convert(tables, name, |row: P| row.to_current())
+}
+
+fn v1_to_v2(tables: &mut RawTables) -> Result<()> {
+    from_previous::<previous::User, v2::User>(tables, "User")?;
+    from_previous::<previous::Token, v2::Token>(tables, "Token")?;
+    from_previous::<previous::Participant, v2::Participant>(tables, "Participant")?;
+    from_previous::<previous::VaccinationHistory, v2::VaccinationHistory>(
+        tables,
+        "VaccinationHistory",
+    )?;
+    from_previous::<previous::Schedule, v2::Schedule>(tables, "Schedule")?;
+    from_previous::<previous::WeeklySurvey, v2::WeeklySurvey>(tables, "WeeklySurvey")?;
+    from_previous::<previous::Withdrawn, v2::Withdrawn>(tables, "Withdrawn")?;
+    from_previous::<previous::Virus, v2::Virus>(tables, "Virus")?;
+    from_previous::<previous::Serology, v2::Serology>(tables, "Serology")?;
+    from_previous::<previous::Consent, v2::Consent>(tables, "Consent")?;
+    from_previous::<previous::YearChange, v2::YearChange>(tables, "YearChange")?;
+    from_previous::<previous::Bleed, v2::Bleed>(tables, "Bleed")?;
+    Ok(())
+}
+
+fn v2_to_v3(tables: &mut RawTables) -> Result<()> {
+    convert(tables, "Token", |t: v2::Token| v3::Token {
+        id: auth::random_string(v3::Token::ID_LENGTH),
+        user: t.user,
+        hash: t.hash,
+        kind: t.kind,
+        created: None,
+        expires: t.expires,
+    })
+}
+
+/// Tokens made so far could do anything
+fn v3_to_v4(tables: &mut RawTables) -> Result<()> {
+    convert(tables, "Token", |t: v3::Token| v4::Token {
+        id: t.id,
+        user: t.user,
+        hash: t.hash,
+        kind: t.kind,
+        created: t.created,
+        expires: t.expires,
+        scopes: vec![v4::Scope::ReadTables, v4::Scope::Sync, v4::Scope::Admin],
+    })
+}
+
+/// Admins can do everything, others can only see identifiable data unless
+/// they are restricted to de-identified exports
+fn v4_to_v5(tables: &mut RawTables) -> Result<()> {
+    convert(tables, "User", |u: v2::User| v5::User {
+        capabilities: match u.access_group {
+            v2::AccessGroup::Admin => vec![
+                v5::Capability::Sync,
+                v5::Capability::ViewIdentifiable,
+                v5::Capability::UploadLabResults,
+                v5::Capability::ManageUsers,
+            ],
+            _ if u.deidentified_export => Vec::new(),
+            _ => vec![v5::Capability::ViewIdentifiable],
+        },
+        email: u.email,
+        access_group: u.access_group,
+        kind: u.kind,
+        deidentified_export: u.deidentified_export,
+    })
+}
+
+fn v5_to_v6(tables: &mut RawTables) -> Result<()> {
+    convert(tables, "Token", |t: v4::Token| v6::Token {
+        id: t.id,
+        user: t.user,
+        hash: t.hash,
+        kind: match t.kind {
+            v2::TokenKind::Session => v6::TokenKind::Session,
+            v2::TokenKind::Api => v6::TokenKind::Api,
+        },
+        created: t.created,
+        expires: t.expires,
+        scopes: t.scopes,
+        used: None,
+    })
+}
+
+fn v6_to_v7(tables: &mut RawTables) -> Result<()> {
+    if let Some(history) = tables.get_mut("History") {
+        history.retain(|entry| {
+            entry
+                .get("table")
+                .and_then(|t| t.as_str())
+                .is_none_or(|t| !SECRET_TABLES.contains(&t))
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::table_specs;
+    use serde_json::json;
+    use std::fs;
+
+    /// Tables as they were in the previous directory
+    fn previous_tables() -> RawTables {
+        let mut tables = RawTables::new();
+        tables.insert(
+            "User".to_string(),
+            vec![json!({
+                "email": "Admin@Example.com",
+                "access_group": "Admin",
+                "kind": "Manual",
+                "deidentified_export": false,
+            })],
+        );
+        tables.insert(
+            "Token".to_string(),
+            vec![json!({
+                "user": "Admin@Example.com",
+                "hash": "abc",
+                "kind": "Session",
+                "expires": null,
+            })],
+        );
+        tables
+    }
+
+    /// Tables as they were written to the current directory before versions were recorded
+    fn unmarked_tables() -> RawTables {
+        let mut tables = previous_tables();
+        tables.get_mut("User").unwrap()[0]["email"] = json!("admin@example.com");
+        tables.get_mut("Token").unwrap()[0]["user"] = json!("admin@example.com");
+        tables
+    }
+
+    fn check_latest(tables: &RawTables) {
+        let users: Vec<current::User> =
+            serde_json::from_value(serde_json::Value::from(tables["User"].clone())).unwrap();
+        assert_eq!(users[0].email, "admin@example.com");
+        assert_eq!(
+            users[0].capabilities,
+            current::Capability::defaults(current::AccessGroup::Admin, false)
+        );
+        let tokens: Vec<current::Token> =
+            serde_json::from_value(serde_json::Value::from(tables["Token"].clone())).unwrap();
+        assert_eq!(tokens[0].user, "admin@example.com");
+        assert_eq!(tokens[0].id.len(), current::Token::ID_LENGTH);
+        assert_eq!(tokens[0].scopes, current::Scope::ALL.to_vec());
+        assert!(tokens[0].created.is_none() && tokens[0].used.is_none());
+    }
+
+    fn versions(reports: &[MigrationReport]) -> Vec<(u32, u32)> {
+        reports.iter().map(|r| (r.from, r.to)).collect()
+    }
+
+    #[test]
+    fn migrations_form_a_chain() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from, PREVIOUS_DIR_VERSION + i as u32);
+        }
+        assert_eq!(latest_version(), 7);
+    }
+
+    #[test]
+    fn applies_every_migration_from_previous_dir() {
+        let mut tables = previous_tables();
+        let reports = apply(&mut tables, PREVIOUS_DIR_VERSION).unwrap();
+        assert_eq!(
+            versions(&reports),
+            vec![(1, 2), (2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]
+        );
+        check_latest(&tables);
+        let history: Vec<current::HistoryEntry> =
+            serde_json::from_value(serde_json::Value::from(tables["History"].clone())).unwrap();
+        assert!(history
+            .iter()
+            .any(|e| e.table == "User" && matches!(e.actor, current::Actor::Migration(2))));
+        // Tokens changed too but their hashes stay out of history
+        assert!(history.iter().all(|e| e.table != "Token"));
+    }
+
+    #[test]
+    fn token_rows_are_removed_from_history() {
+        let mut tables = RawTables::new();
+        let entry = |table: &str| {
+            json!({
+                "timestamp": "2021-01-01T00:00:00Z",
+                "actor": {"Migration": 2},
+                "table": table,
+                "pk": "id",
+                "before": null,
+                "after": {"hash": "hash"},
+            })
+        };
+        tables.insert("History".to_string(), vec![entry("Token"), entry("User")]);
+        v6_to_v7(&mut tables).unwrap();
+        assert_eq!(tables["History"], vec![entry("User")]);
+    }
+
+    #[test]
+    fn applies_remaining_migrations_from_unmarked_version() {
+        let mut tables = unmarked_tables();
+        let reports = apply(&mut tables, UNMARKED_VERSION).unwrap();
+        assert_eq!(
+            versions(&reports),
+            vec![(2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]
+        );
+        check_latest(&tables);
+        assert!(apply(&mut tables, latest_version()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_newer_data() {
+        assert!(apply(&mut unmarked_tables(), latest_version() + 1).is_err());
+    }
+
+    fn write_unmarked(root: &Path) -> std::path::PathBuf {
+        let current = root.join("current");
+        fs::create_dir(current.as_path()).unwrap();
+        for (name, rows) in unmarked_tables() {
+            let contents = serde_json::to_string(&rows).unwrap();
+            fs::write(current.join(format!("{}.json", name)), contents).unwrap();
+        }
+        current
+    }
+
+    /// Every file in the directory with its contents
+    fn files(dir: &Path) -> BTreeMap<String, String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read_to_string(path).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn upgrade_records_latest_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = write_unmarked(dir.path());
+        let mut storage = storage::open(storage::StorageKind::Json.into(), &current).unwrap();
+        upgrade(storage.as_mut(), &table_specs()).unwrap();
+        assert_eq!(storage.version().unwrap(), Some(latest_version()));
+        check_latest(&load(storage.as_mut(), &table_specs()).unwrap());
+    }
+
+    #[test]
+    fn dry_run_leaves_files_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = write_unmarked(dir.path());
+        let before = files(&current);
+        let reports = dry_run(dir.path(), storage::StorageKind::Json.into()).unwrap();
+        assert_eq!(
+            versions(&reports),
+            vec![(2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]
+        );
+        assert_eq!(files(&current), before);
+    }
+
+    #[test]
+    fn dry_run_leaves_interrupted_writes_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = write_unmarked(dir.path());
+        fs::write(current.join("journal.json"), r#"{"files":[]}"#).unwrap();
+        let before = files(&current);
+        assert!(dry_run(dir.path(), storage::StorageKind::Json.into()).is_err());
+        assert!(dry_run(dir.path(), storage::StorageKind::Sqlite.into()).is_err());
+        assert_eq!(files(&current), before);
+    }
+
+    #[test]
+    fn dry_run_waits_for_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        write_unmarked(dir.path());
+        let lock = DbLock::acquire(dir.path()).unwrap();
+        assert!(dry_run(dir.path(), storage::StorageKind::Json.into()).is_err());
+        drop(lock);
+        dry_run(dir.path(), storage::StorageKind::Json.into()).unwrap();
+    }
+}
@@ -0,0 +1,2782 @@
+use crate::{auth, data::current, deidentify, error, Result};
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub mod diff;
+pub mod encrypted;
+pub mod foreign_key;
+pub mod integrity;
+pub mod journal;
+pub mod json;
+pub mod lock;
+pub mod migration;
+#[macro_use]
+pub mod registry;
+pub mod shared;
+pub mod snapshot;
+pub mod sqlite;
+pub mod storage;
+
+use registry::AnyTable;
+use storage::{RowWrite, Storage, StorageConfig, TableWrite};
+
+pub struct Db {
+    pub dirs: DbDirs,
+    pub storage_config: StorageConfig,
+    storage: Box<dyn Storage>,
+    pub snapshot_retention: snapshot::Retention,
+    /// Tables quarantined by the integrity check at startup are read-only
+    pub integrity: integrity::Report,
+    pub pseudonyms: deidentify::Pseudonyms,
+    /// What token hashes are made with
+    pub token_hasher: auth::TokenHasher,
+    pub tables: Tables,
+    transaction_open: bool,
+    /// Held for as long as the db is open, see `lock::DbLock`
+    _lock: lock::DbLock,
+}
+
+tables! {
+    users: current::User = Table::keyed("User"),
+    tokens: current::Token = Table::keyed("Token"),
+    participants: current::Participant = Table::keyed("Participant"),
+    vaccination_history: current::VaccinationHistory =
+        Table::keyed("VaccinationHistory").with_pid_index(),
+    schedule: current::Schedule = Table::keyed("Schedule").with_pid_index(),
+    weekly_survey: current::WeeklySurvey = Table::keyed("WeeklySurvey").with_pid_index(),
+    withdrawn: current::Withdrawn = Table::keyed("Withdrawn").with_pid_index(),
+    virus: current::Virus = Table::keyed("Virus"),
+    serology: current::Serology = Table::keyed("Serology").with_pid_index(),
+    consent: current::Consent = Table::new("Consent").with_pid_index(),
+    year_change: current::YearChange = Table::new("YearChange").with_pid_index(),
+    bleed: current::Bleed = Table::new("Bleed").with_pid_index(),
+    sync_history: current::SyncRecord = Table::new("SyncHistory"),
+    history: current::HistoryEntry = Table::new("History"),
+}
+
+/// Tables that record what happened to the other tables. They aren't restored
+/// from snapshots and changes to them aren't recorded in row history.
+pub const UNRECORDED_TABLES: &[&str] = &["SyncHistory", "History"];
+
+/// Tables holding credentials. Their rows are never copied into row history,
+/// where they would outlive the credentials themselves, and never restored
+/// from snapshots, which would bring back revoked tokens.
+pub const SECRET_TABLES: &[&str] = &["Token"];
+
+/// Tables only restored from snapshots when asked for by name. Restoring every
+/// table would otherwise bring back removed users along with the data.
+pub const NAMED_RESTORE_TABLES: &[&str] = &["User"];
+
+pub struct DbDirs {
+    pub init_state: DbDirsInitState,
+    pub root: PathBuf,
+    pub previous: PathBuf,
+    pub current: PathBuf,
+    pub snapshots: PathBuf,
+}
+
+#[derive(PartialEq)]
+pub enum DbDirsInitState {
+    /// No directories present at init
+    None,
+    /// Only the previous directory present at init, its data is at
+    /// `migration::PREVIOUS_DIR_VERSION`
+    Previous,
+    /// Current directory present at init
+    Current,
+}
+
+pub struct Table<C> {
+    pub name: String,
+    /// Shared with copies of the table made by `share` until it's modified.
+    /// Modify only through `data_mut`, `replace` and the like so that
+    /// the indexes stay up to date
+    pub current: Arc<TableData<C>>,
+    key: Option<TableKey<C>>,
+    indexing: Indexing<C>,
+    backup: Backup<C>,
+}
+
+/// How storage finds the primary key of a row
+struct TableKey<C> {
+    columns: &'static [&'static str],
+    get: fn(&C) -> Result<Vec<serde_json::Value>>,
+    verify: fn(&Table<C>) -> Result<()>,
+}
+
+/// How rows are placed in the indexes
+struct Indexing<C> {
+    /// Hash of the primary key, if the table has one
+    pk_hash: Option<fn(&C) -> u64>,
+    /// How to find the participant a row belongs to, if rows belong to participants
+    pid: Option<fn(&C) -> Option<&str>>,
+}
+
+/// Table data borrowed for modification, the indexes are rebuilt when it's dropped
+pub struct DataMut<'a, C> {
+    data: &'a mut TableData<C>,
+    indexing: Indexing<C>,
+}
+
+/// Row borrowed for modification, the indexes follow changes to its key
+/// and participant when it's dropped
+pub struct RowMut<'a, C> {
+    data: &'a mut TableData<C>,
+    indexing: Indexing<C>,
+    position: usize,
+    pk_hash: Option<u64>,
+    pid: Option<String>,
+}
+
+/// What a table needs to restore itself if a transaction is rolled back
+enum Backup<C> {
+    /// No transaction open
+    Inactive,
+    /// Transaction open but the table hasn't been modified yet
+    Untouched,
+    /// Table modified in the open transaction, this is the data before the modification
+    Modified(Arc<TableData<C>>),
+}
+
+#[derive(Clone)]
+pub struct TableData<T> {
+    pub data: Vec<T>,
+    /// Row positions by hash of the primary key
+    pk_index: HashMap<u64, Vec<usize>>,
+    /// Row positions by participant, in table order
+    pid_index: HashMap<String, Vec<usize>>,
+}
+
+/// Token as its user sees it, without the hash
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct TokenInfo {
+    pub id: String,
+    pub kind: current::TokenKind,
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+    pub scopes: Vec<current::Scope>,
+}
+
+/// What `Db::purge_tokens` removed
+#[derive(serde_derive::Serialize, schemars::JsonSchema, Debug, Default)]
+pub struct TokenPurge {
+    pub expired: usize,
+    /// Tokens of users that don't exist anymore
+    pub orphaned: usize,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct TableIssues {
+    participant: ParticipantTableIssues,
+    schedule: ScheduleTableIssues,
+    weekly_survey: WeeklySurveyTableIssues,
+    virus: VirusTableIssues,
+    serology: SerologyTableIssues,
+    consent: ConsentTableIssues,
+    year_changes: YearChangeTableIssues,
+    foreign_keys: Vec<foreign_key::Issue>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct ParticipantTableIssues {
+    duplicate_email: Vec<Duplicate<String, String>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct YearChangeTableIssues {
+    duplicate_pid: Vec<KeyIssue<(String, u32), String>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct ConsentTableIssues {
+    conflicting_groups: Vec<(String, u32, current::ConsentDisease)>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct ScheduleTableIssues {
+    pk: Vec<KeyIssue<<current::Schedule as PrimaryKey>::K, current::Schedule>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct WeeklySurveyTableIssues {
+    pk: Vec<KeyIssue<<current::WeeklySurvey as PrimaryKey>::K, current::WeeklySurvey>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct VirusTableIssues {
+    pk: Vec<KeyIssue<<current::Virus as PrimaryKey>::K, current::Virus>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct SerologyTableIssues {
+    pk: Vec<KeyIssue<<current::Serology as PrimaryKey>::K, current::Serology>>,
+    fk_participant: Vec<KeyIssue<<current::Participant as PrimaryKey>::K, current::Serology>>,
+    fk_virus: Vec<KeyIssue<<current::Virus as PrimaryKey>::K, current::Serology>>,
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema)]
+pub struct KeyIssue<K, T> {
+    value: K,
+    rows: Vec<T>,
+}
+
+// ================================================================================================
+
+impl Db {
+    /// Will migrate and read in the data depending on the initial state of the directories
+    /// By the time it's done, the root directory and the current directory
+    /// inside it should be created. The previous directory isn't used post-creation.
+    pub fn new(
+        dir: &Path,
+        storage_config: StorageConfig,
+        default_admin_email: &str,
+        token_hasher: auth::TokenHasher,
+    ) -> Result<Self> {
+        log::debug!("initializing db at root directory {:?}", dir);
+
+        let lock = lock::DbLock::acquire(dir)?;
+        let dirs = DbDirs::new(dir)?;
+        let specs = table_specs();
+
+        let mut storage = match dirs.init_state {
+            DbDirsInitState::Previous => {
+                log::debug!("only previous data found, will attempt to migrate");
+                let mut previous = storage::open(storage_config, dirs.previous.as_path())?;
+                let mut tables = migration::load(previous.as_mut(), &specs)?;
+                migration::apply(&mut tables, migration::PREVIOUS_DIR_VERSION)?;
+                // Create directory right before writing so that there isn't anything to
+                // clean up if any step before this fails
+                fs::create_dir(dirs.current.as_path())?;
+                let mut storage = storage::open(storage_config, dirs.current.as_path())?;
+                migration::store(storage.as_mut(), &specs, tables)?;
+                storage
+            }
+            DbDirsInitState::Current => {
+                log::debug!("current data found, checking version");
+                let mut storage = storage::open(storage_config, dirs.current.as_path())?;
+                migration::upgrade(storage.as_mut(), &specs)?;
+                storage
+            }
+            DbDirsInitState::None => {
+                log::debug!("no data found, starting at the latest version");
+                let mut storage = storage::open(storage_config, dirs.current.as_path())?;
+                storage.store_version(&[], migration::latest_version())?;
+                storage
+            }
+        };
+
+        // Snapshots taken before encryption was switched on are plain too
+        if let Some(key) = &storage_config.key {
+            for info in snapshot::list(&dirs)? {
+                json::encrypt_plain(snapshot::find(&dirs, info.id.as_str())?.as_path(), key)?;
+            }
+        }
+
+        let mut tables = Tables::new();
+        let integrity = integrity::check(&mut tables, storage.as_mut());
+        integrity.write(&dirs)?;
+
+        let mut db = Self {
+            tables,
+            integrity,
+            pseudonyms: deidentify::Pseudonyms::open(&dirs)?,
+            token_hasher,
+            dirs,
+            storage_config,
+            storage,
+            snapshot_retention: snapshot::Retention::default(),
+            transaction_open: false,
+            _lock: lock,
+        };
+
+        // Make sure one admin exists
+        if db.integrity.is_degraded() {
+            log::warn!("quarantined tables are read-only until restored from a snapshot");
+        }
+        if db.integrity.check_writable("User").is_ok() && db.tables.users.current.data.is_empty() {
+            log::debug!("users empty, inserting default admin");
+            db.insert_user(current::User {
+                email: default_admin_email.to_lowercase(),
+                access_group: current::AccessGroup::Admin,
+                kind: current::UserKind::Manual,
+                deidentified_export: false,
+                capabilities: current::Capability::ALL.to_vec(),
+            })?;
+        }
+        if db.integrity.check_writable("Token").is_ok() {
+            db.pepper_token_hashes()?;
+        }
+
+        Ok(db)
+    }
+    /// Peppers the token hashes stored before there was a pepper, see `auth::TokenHasher`
+    fn pepper_token_hashes(&mut self) -> Result<()> {
+        let tokens = &self.tables.tokens.current.data;
+        if !self.token_hasher.has_pepper() {
+            let peppered = tokens.iter().filter(|t| auth::is_peppered(&t.hash)).count();
+            if peppered > 0 {
+                log::warn!("no token pepper, {} peppered tokens won't work", peppered);
+            }
+            return Ok(());
+        }
+        let unpeppered = tokens
+            .iter()
+            .filter(|t| !auth::is_peppered(&t.hash))
+            .count();
+        if unpeppered == 0 {
+            return Ok(());
+        }
+        log::info!("peppering {} token hashes", unpeppered);
+        self.transaction(|db| {
+            let hasher = &db.token_hasher;
+            for token in db.tables.tokens.data_mut().iter_mut() {
+                if let Some(hash) = hasher.pepper_hash(token.hash.as_str()) {
+                    token.hash = hash;
+                }
+            }
+            Ok(())
+        })
+    }
+    pub fn read(&mut self) -> Result<()> {
+        log::debug!("reading db from disk");
+        self.tables.read(self.storage.as_mut())
+    }
+    pub fn write(&mut self) -> Result<()> {
+        log::debug!("writing db to disk");
+        self.integrity.check_all_writable()?;
+        let writes = self.prepare_write()?;
+        self.write_tables(writes)
+    }
+    /// Serializes all tables without touching the disk
+    pub fn prepare_write(&self) -> Result<Vec<TableWrite>> {
+        self.tables.prepare_write()
+    }
+    /// Writes the given tables so that either all or none of them end up on disk
+    pub fn write_tables(&mut self, writes: Vec<TableWrite>) -> Result<()> {
+        self.storage.store(&writes)
+    }
+    /// Starts a transaction. Tables modified until `commit` or `rollback`
+    /// are not written to disk individually.
+    pub fn begin(&mut self) -> Result<()> {
+        if self.transaction_open {
+            bail!(error::Conflict::TransactionAlreadyOpen);
+        }
+        log::debug!("beginning transaction");
+        self.tables.begin();
+        self.transaction_open = true;
+        Ok(())
+    }
+    /// Writes all tables modified in the transaction together.
+    /// Rolls the transaction back if the write fails or touches a quarantined table.
+    pub fn commit(&mut self) -> Result<()> {
+        if !self.transaction_open {
+            bail!(error::Conflict::NoTransactionOpen);
+        }
+        log::debug!("committing transaction");
+        let written = foreign_key::enforce(&mut self.tables)
+            .and_then(|_| self.tables.prepare_commit())
+            .and_then(|writes| {
+                for write in &writes {
+                    self.integrity.check_writable(write.name.as_str())?;
+                }
+                Ok(writes)
+            });
+        let written = written.and_then(|writes| self.write_tables(writes));
+        if let Err(e) = written {
+            self.rollback()?;
+            return Err(e);
+        }
+        self.tables.end();
+        self.transaction_open = false;
+        Ok(())
+    }
+    /// Restores all tables modified in the transaction to their state at `begin`
+    pub fn rollback(&mut self) -> Result<()> {
+        if !self.transaction_open {
+            bail!(error::Conflict::NoTransactionOpen);
+        }
+        log::debug!("rolling back transaction");
+        self.tables.rollback();
+        self.transaction_open = false;
+        Ok(())
+    }
+    /// Runs `f` in a transaction that is committed if `f` succeeds and rolled back
+    /// otherwise. Joins the enclosing transaction if there is one.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.transaction_open {
+            return f(self);
+        }
+        self.begin()?;
+        match f(self) {
+            Ok(v) => {
+                self.commit()?;
+                Ok(v)
+            }
+            Err(e) => {
+                self.rollback()?;
+                Err(e)
+            }
+        }
+    }
+    /// Copies the data as it is on disk into a new snapshot
+    pub fn snapshot(&self, reason: &str) -> Result<snapshot::SnapshotInfo> {
+        snapshot::take(&self.dirs, reason, self.snapshot_retention)
+    }
+    pub fn list_snapshots(&self) -> Result<Vec<snapshot::SnapshotInfo>> {
+        snapshot::list(&self.dirs)
+    }
+    /// What changed in every table between the snapshot and now
+    pub fn diff_snapshot(&self, id: &str) -> Result<Vec<diff::TableDiff>> {
+        self.tables
+            .diff_snapshot(&self.dirs, self.storage_config, id)
+    }
+    /// Replaces the data with that of the snapshot, either every table or just the
+    /// named one. Takes a snapshot of the current data first.
+    /// Sync history and row history are left alone since they record what happened
+    /// to the data, tokens are never restored and users only when named.
+    /// Restored rows are recorded in row history as edits by `user`.
+    pub fn restore_snapshot(&mut self, user: &str, id: &str, table: Option<&str>) -> Result<()> {
+        if let Some(name) = table {
+            if UNRECORDED_TABLES.contains(&name)
+                || SECRET_TABLES.contains(&name)
+                || !table_specs().iter().any(|s| s.name == name)
+            {
+                bail!(error::NotFound::NoSuchTable(name.to_string()));
+            }
+        }
+        let mut tables = load_snapshot(&self.dirs, self.storage_config, id)?;
+        self.snapshot(format!("restore of snapshot {}", id).as_str())?;
+        let actor = current::Actor::Manual(user.to_string());
+        // Restoring is how quarantined tables are repaired
+        let integrity = self.integrity.clone();
+        let result = self.transaction(|db| {
+            let mut restored = Vec::new();
+            for t in db.tables.all_mut() {
+                let name = t.name().to_string();
+                let restored_table = match table {
+                    Some(table) => table == name,
+                    None => !NAMED_RESTORE_TABLES.contains(&name.as_str()),
+                };
+                if !restored_table
+                    || UNRECORDED_TABLES.contains(&name.as_str())
+                    || SECRET_TABLES.contains(&name.as_str())
+                {
+                    continue;
+                }
+                let changes = t.replace_rows(tables.remove(&name).unwrap_or_default())?;
+                t.verify_pk()?;
+                db.integrity.release(name.as_str(), t.row_count());
+                restored.push((name, changes));
+            }
+            for (name, changes) in restored {
+                db.record_history(&actor, name.as_str(), &changes);
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            self.integrity = integrity;
+        } else if self.integrity.quarantined() != integrity.quarantined() {
+            self.integrity.write(&self.dirs)?;
+        }
+        result
+    }
+    pub fn insert_user(&mut self, user: current::User) -> Result<()> {
+        self.transaction(|db| db.tables.users.insert(user))
+    }
+    pub fn insert_token(&mut self, token: current::Token) -> Result<()> {
+        self.transaction(|db| db.tables.tokens.insert(token))
+    }
+    /// Inserts the emailed login code, see `insert_capped_row`
+    pub fn insert_login_code(&mut self, code: current::Token, max_codes: usize) -> Result<()> {
+        self.transaction(|db| db.insert_capped_row(code, max_codes))
+    }
+    /// Inserts the session token or login code, removing the oldest outstanding
+    /// ones of the same kind of the user so that there are at most `max` of them.
+    /// Ones that can't be used anymore, expired or used, are removed as well.
+    fn insert_capped_row(&mut self, token: current::Token, max: usize) -> Result<()> {
+        let mut rows = self
+            .tables
+            .tokens
+            .current
+            .data
+            .iter()
+            .filter(|t| t.user == token.user && t.kind == token.kind)
+            .map(|t| {
+                (
+                    !t.is_expired() && t.used.is_none(),
+                    t.expires,
+                    t.hash.clone(),
+                )
+            })
+            .collect::<Vec<(bool, Option<chrono::DateTime<chrono::Utc>>, String)>>();
+        // Ones that can't be used first, then the ones that expire soonest
+        rows.sort();
+        let outstanding = rows.iter().filter(|(outstanding, ..)| *outstanding).count();
+        let remove = rows
+            .len()
+            .saturating_sub(outstanding)
+            .max(rows.len().saturating_sub(max.saturating_sub(1)));
+        let removed = rows
+            .into_iter()
+            .take(remove)
+            .map(|(.., hash)| hash)
+            .collect::<Vec<String>>();
+        if !removed.is_empty() {
+            log::info!(
+                "removing {} {:?} tokens of {}",
+                removed.len(),
+                token.kind,
+                token.user
+            );
+            self.tables
+                .tokens
+                .data_mut()
+                .retain(|t| !removed.contains(&t.hash));
+        }
+        self.tables.tokens.insert(token)
+    }
+    /// Marks the login code used and starts a session for its user, see
+    /// `insert_capped_row`. Returns the session token.
+    pub fn login_code_exchange(
+        &mut self,
+        code: &str,
+        len: usize,
+        dtl: i64,
+        max_sessions: usize,
+    ) -> Result<String> {
+        self.transaction(|db| {
+            let user = {
+                let position = match db.tables.tokens.token_position(&db.token_hasher, code) {
+                    Some(i) => i,
+                    None => bail!(error::Unauthorized::NoSuchToken(code.to_string())),
+                };
+                let mut code_row = db.tables.tokens.row_mut(position);
+                if code_row.kind != current::TokenKind::Login {
+                    bail!(error::Conflict::WrongTokenKind(code_row.kind));
+                }
+                if code_row.used.is_some() || code_row.is_expired() {
+                    bail!(error::Unauthorized::LoginCodeUsedOrExpired(
+                        code.to_string()
+                    ));
+                }
+                code_row.used = Some(chrono::Utc::now());
+                code_row.user.clone()
+            };
+            let (before_hash, session) = current::Token::new(
+                user.as_str(),
+                current::TokenKind::Session,
+                len,
+                Some(dtl),
+                &[],
+                &db.token_hasher,
+            );
+            db.insert_capped_row(session, max_sessions)?;
+            Ok(before_hash)
+        })
+    }
+    /// Adds manual users with no more access than `by` has.
+    /// Recorded in row history as edits by `by`.
+    pub fn add_users(&mut self, by: &current::User, new_users: Vec<current::User>) -> Result<()> {
+        let mut users = self.tables.users.current.data.clone();
+        for new_user in new_users {
+            let new_user = current::User {
+                email: new_user.email.to_lowercase(),
+                kind: current::UserKind::Manual,
+                ..new_user
+            };
+            by.check_covers(&new_user)?;
+            if users.iter().any(|u| u.email == new_user.email) {
+                bail!(error::Conflict::PrimaryKey(
+                    self.tables.users.name.clone(),
+                    new_user.email
+                ));
+            }
+            users.push(new_user);
+        }
+        self.replace_users(by, users)
+    }
+    /// Changes the access and capabilities of the user with the same email as
+    /// `update`. Only the capabilities of REDCap users can change, the rest comes
+    /// from REDCap. `by` has to have all the access the user has before and after.
+    /// Recorded in row history as an edit by `by`.
+    pub fn update_user(&mut self, by: &current::User, update: current::User) -> Result<()> {
+        let mut users = self.tables.users.current.data.clone();
+        let row = find_user(&mut users, update.email.as_str())?;
+        by.check_covers(row)?;
+        by.check_covers(&update)?;
+        if row.kind == current::UserKind::Redcap
+            && (row.access_group != update.access_group
+                || row.deidentified_export != update.deidentified_export)
+        {
+            bail!(error::Conflict::RedcapUser(row.email.clone()));
+        }
+        row.access_group = update.access_group;
+        row.deidentified_export = update.deidentified_export;
+        row.capabilities = update.capabilities;
+        self.replace_users(by, users)
+    }
+    /// Removes the manual user if `by` has all the access the user has, their
+    /// tokens go with them. Recorded in row history as an edit by `by`.
+    pub fn remove_user(&mut self, by: &current::User, email: &str) -> Result<()> {
+        let mut users = self.tables.users.current.data.clone();
+        let row = manual_user(&mut users, email)?;
+        by.check_covers(row)?;
+        let email = row.email.clone();
+        users.retain(|u| u.email != email);
+        self.replace_users(by, users)
+    }
+    /// Refuses to leave the db without an admin who can manage users
+    fn replace_users(&mut self, by: &current::User, users: Vec<current::User>) -> Result<()> {
+        if !users.iter().any(|u| {
+            u.access_group == current::AccessGroup::Admin && u.can(current::Capability::ManageUsers)
+        }) {
+            bail!(error::Conflict::LastAdmin);
+        }
+        // Tokens of removed users go with them, see `foreign_key::FOREIGN_KEYS`
+        let actor = current::Actor::Manual(by.email.clone());
+        self.replace_recorded(&actor, users, |t| &mut t.users)?;
+        Ok(())
+    }
+    /// Removes expired tokens, used login codes among them, and tokens of users
+    /// that don't exist anymore
+    pub fn purge_tokens(&mut self) -> Result<TokenPurge> {
+        self.transaction(|db| {
+            let mut purge = TokenPurge::default();
+            for token in db.tables.tokens.current.data.iter() {
+                if db.tables.users.lookup(&token.user).is_none() {
+                    purge.orphaned += 1;
+                } else if token.is_expired() {
+                    purge.expired += 1;
+                }
+            }
+            if purge.expired + purge.orphaned > 0 {
+                let users = &db.tables.users;
+                db.tables
+                    .tokens
+                    .data_mut()
+                    .retain(|t| !t.is_expired() && users.lookup(&t.user).is_some());
+            }
+            Ok(purge)
+        })
+    }
+    /// Removes the tokens of the user, either every one of them or just the ones
+    /// of the kind. Returns how many there were.
+    pub fn remove_tokens(
+        &mut self,
+        email: &str,
+        kind: Option<current::TokenKind>,
+    ) -> Result<usize> {
+        self.transaction(|db| {
+            let email = email.to_lowercase();
+            if db.tables.users.lookup(&email).is_none() {
+                bail!(error::NotFound::NoSuchUser(email));
+            }
+            let mut tokens = db.tables.tokens.data_mut();
+            let before = tokens.len();
+            tokens.retain(|t| t.user != email || kind.is_some_and(|kind| t.kind != kind));
+            Ok(before - tokens.len())
+        })
+    }
+    /// Removes the token itself, the way to log out
+    pub fn remove_token(&mut self, token: &str) -> Result<()> {
+        self.transaction(|db| {
+            let position = match db.tables.tokens.token_position(&db.token_hasher, token) {
+                Some(i) => i,
+                None => bail!(error::Unauthorized::NoSuchToken(token.to_string())),
+            };
+            db.tables.tokens.data_mut().remove(position);
+            Ok(())
+        })
+    }
+    /// Removes the token with the id. Users can remove their own tokens,
+    /// admins can remove anyone's.
+    pub fn revoke_token(&mut self, user: &current::User, id: &str) -> Result<()> {
+        self.transaction(|db| {
+            let token = match db.tables.tokens.current.data.iter().find(|t| t.id == id) {
+                Some(t) => t,
+                None => bail!(error::NotFound::NoSuchTokenId(id.to_string())),
+            };
+            if token.user != user.email && user.access_group != current::AccessGroup::Admin {
+                bail!(error::Unauthorized::InsufficientAccess(
+                    user.access_group,
+                    current::AccessGroup::Admin
+                ));
+            }
+            db.tables.tokens.data_mut().retain(|t| t.id != id);
+            Ok(())
+        })
+    }
+
+    pub fn token_refresh(&mut self, token: &str, len: usize, dtl: i64) -> Result<String> {
+        self.transaction(|db| db.token_refresh_row(token, len, dtl))
+    }
+
+    fn token_refresh_row(&mut self, token: &str, len: usize, dtl: i64) -> Result<String> {
+        let position = match self.tables.tokens.token_position(&self.token_hasher, token) {
+            Some(i) => i,
+            None => {
+                return Err(anyhow::Error::new(error::Unauthorized::NoSuchToken(
+                    token.to_string(),
+                )))
+            }
+        };
+        let mut token_row = self.tables.tokens.row_mut(position);
+        if token_row.kind != current::TokenKind::Session {
+            return Err(anyhow::Error::new(error::Conflict::WrongTokenKind(
+                token_row.kind,
+            )));
+        }
+        // Refreshing would bring expired sessions back
+        if token_row.is_expired() {
+            return Err(anyhow::Error::new(error::Unauthorized::TokenExpired(
+                token.to_string(),
+            )));
+        }
+        let before_hash = auth::random_string(len);
+        token_row.hash = self.token_hasher.hash(before_hash.as_str());
+        token_row.expires = Some(chrono::Utc::now() + chrono::Duration::days(dtl));
+        Ok(before_hash)
+    }
+
+    /// REDCap users can be admins, so only admins sync them
+    pub fn sync_redcap_users(
+        &mut self,
+        by: &current::User,
+        mut redcap_users: Vec<current::User>,
+    ) -> Result<current::SyncRecord> {
+        if by.access_group != current::AccessGroup::Admin {
+            bail!(error::Unauthorized::InsufficientAccess(
+                by.access_group,
+                current::AccessGroup::Admin
+            ));
+        }
+        let user = by.email.as_str();
+        self.snapshot("redcap users sync")?;
+        self.transaction(|db| {
+            let mut users = db.tables.users.current.data.clone();
+
+            // Capabilities are granted here rather than in REDCap, they stay
+            // for as long as REDCap doesn't change the user's access
+            for redcap_user in redcap_users.iter_mut() {
+                if let Some(existing) = users.iter().find(|u| {
+                    u.kind == current::UserKind::Redcap
+                        && u.email == redcap_user.email
+                        && u.access_group == redcap_user.access_group
+                        && u.deidentified_export == redcap_user.deidentified_export
+                }) {
+                    redcap_user.capabilities = existing.capabilities.clone();
+                }
+            }
+            users.retain(|u| u.kind == current::UserKind::Manual);
+            redcap_users.retain(|redcap_user| {
+                users
+                    .iter()
+                    .all(|manual_user| redcap_user.email != manual_user.email)
+            });
+            users.append(&mut redcap_users);
+
+            // Tokens of removed users go with them, see `foreign_key::FOREIGN_KEYS`
+            let actor = current::Actor::RedcapSync(user.to_string());
+            let changes = db.replace_recorded(&actor, users, |t| &mut t.users)?;
+            let diff = diff::TableDiff::from_changes(db.tables.users.name.as_str(), &changes);
+            Ok(db.record_sync(user, diff))
+        })
+    }
+
+    pub fn sync_redcap_participants(
+        &mut self,
+        user: &str,
+        redcap_participants: Vec<current::Participant>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap participants sync")?;
+        self.sync_table(user, redcap_participants, |t| &mut t.participants)
+    }
+
+    pub fn sync_redcap_vaccination_history(
+        &mut self,
+        user: &str,
+        redcap_vaccination_history: Vec<current::VaccinationHistory>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap vaccination history sync")?;
+        self.sync_table(user, redcap_vaccination_history, |t| {
+            &mut t.vaccination_history
+        })
+    }
+
+    pub fn sync_redcap_schedule(
+        &mut self,
+        user: &str,
+        redcap_schedule: Vec<current::Schedule>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap schedule sync")?;
+        self.sync_table(user, redcap_schedule, |t| &mut t.schedule)
+    }
+
+    pub fn sync_redcap_weekly_survey(
+        &mut self,
+        user: &str,
+        redcap_weekly_survey: Vec<current::WeeklySurvey>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap weekly survey sync")?;
+        self.sync_table(user, redcap_weekly_survey, |t| &mut t.weekly_survey)
+    }
+
+    pub fn sync_redcap_withdrawn(
+        &mut self,
+        user: &str,
+        redcap_withdrawn: Vec<current::Withdrawn>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap withdrawn sync")?;
+        self.sync_table(user, redcap_withdrawn, |t| &mut t.withdrawn)
+    }
+
+    pub fn sync_redcap_consent(
+        &mut self,
+        user: &str,
+        redcap_consent: Vec<current::Consent>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap consent sync")?;
+        self.sync_table(user, redcap_consent, |t| &mut t.consent)
+    }
+
+    pub fn sync_redcap_year_change(
+        &mut self,
+        user: &str,
+        redcap_year_change: Vec<current::YearChange>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap year change sync")?;
+        self.sync_table(user, redcap_year_change, |t| &mut t.year_change)
+    }
+
+    pub fn sync_redcap_bleed(
+        &mut self,
+        user: &str,
+        redcap_bleed: Vec<current::Bleed>,
+    ) -> Result<current::SyncRecord> {
+        self.snapshot("redcap bleed sync")?;
+        self.sync_table(user, redcap_bleed, |t| &mut t.bleed)
+    }
+
+    /// Replaces the table's data with the synced data and records what changed
+    fn sync_table<C: Clone + Serialize>(
+        &mut self,
+        user: &str,
+        synced: Vec<C>,
+        table: fn(&mut Tables) -> &mut Table<C>,
+    ) -> Result<current::SyncRecord> {
+        self.transaction(|db| {
+            let actor = current::Actor::RedcapSync(user.to_string());
+            let changes = db.replace_recorded(&actor, synced, table)?;
+            let diff = diff::TableDiff::from_changes(table(&mut db.tables).name.as_str(), &changes);
+            Ok(db.record_sync(user, diff))
+        })
+    }
+
+    /// Replaces the table's data and appends every changed row to row history
+    fn replace_recorded<C: Clone + Serialize>(
+        &mut self,
+        actor: &current::Actor,
+        data: Vec<C>,
+        table: fn(&mut Tables) -> &mut Table<C>,
+    ) -> Result<Vec<diff::RowChange>> {
+        self.transaction(|db| {
+            let table = table(&mut db.tables);
+            let changes = table.changes(&data)?;
+            table.replace(data);
+            let name = table.name.clone();
+            db.record_history(actor, name.as_str(), &changes);
+            Ok(changes)
+        })
+    }
+
+    /// Appends changed rows of the table to row history
+    fn record_history(&mut self, actor: &current::Actor, table: &str, changes: &[diff::RowChange]) {
+        if SECRET_TABLES.contains(&table) {
+            return;
+        }
+        let timestamp = chrono::Utc::now();
+        self.tables
+            .history
+            .data_mut()
+            .extend(changes.iter().map(|change| current::HistoryEntry {
+                timestamp,
+                actor: actor.clone(),
+                table: table.to_string(),
+                pk: change.key.clone(),
+                before: change.before.clone(),
+                after: change.after.clone(),
+            }));
+    }
+
+    fn record_sync(&mut self, user: &str, diff: diff::TableDiff) -> current::SyncRecord {
+        log::info!(
+            "sync of {} by {}: {} added, {} removed, {} changed",
+            diff.table,
+            user,
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        let record = current::SyncRecord {
+            timestamp: chrono::Utc::now(),
+            user: user.to_string(),
+            table: diff.table,
+            added: diff.added,
+            removed: diff.removed,
+            changed: diff.changed,
+        };
+        self.tables.sync_history.data_mut().push(record.clone());
+        record
+    }
+}
+
+/// Snapshot data brought up to the latest version
+fn load_snapshot(
+    dirs: &DbDirs,
+    storage_config: StorageConfig,
+    id: &str,
+) -> Result<migration::RawTables> {
+    let dir = snapshot::find(dirs, id)?;
+    let mut storage = storage::open(storage_config, dir.as_path())?;
+    let version = storage.version()?.unwrap_or(migration::UNMARKED_VERSION);
+    let mut tables = migration::load(storage.as_mut(), &table_specs())?;
+    migration::apply(&mut tables, version)?;
+    Ok(tables)
+}
+
+fn find_user<'a>(users: &'a mut [current::User], email: &str) -> Result<&'a mut current::User> {
+    let email = email.to_lowercase();
+    match users.iter_mut().find(|u| u.email == email) {
+        Some(u) => Ok(u),
+        None => bail!(error::NotFound::NoSuchUser(email)),
+    }
+}
+
+/// User that can be removed through the API, REDCap users are removed in REDCap
+fn manual_user<'a>(users: &'a mut [current::User], email: &str) -> Result<&'a mut current::User> {
+    let user = find_user(users, email)?;
+    if user.kind != current::UserKind::Manual {
+        bail!(error::Conflict::RedcapUser(user.email.clone()));
+    }
+    Ok(user)
+}
+
+impl Tables {
+    fn read(&mut self, storage: &mut dyn Storage) -> Result<()> {
+        for table in self.all_mut() {
+            table.read(storage)?;
+        }
+        Ok(())
+    }
+    /// Serializes all tables without touching the disk
+    fn prepare_write(&self) -> Result<Vec<TableWrite>> {
+        self.all().iter().map(|t| t.prepare_write()).collect()
+    }
+    fn begin(&mut self) {
+        self.all_mut().into_iter().for_each(|t| t.begin());
+    }
+    fn end(&mut self) {
+        self.all_mut().into_iter().for_each(|t| t.end());
+    }
+    /// Serializes the tables modified in the open transaction
+    fn prepare_commit(&self) -> Result<Vec<TableWrite>> {
+        self.all()
+            .iter()
+            .map(|t| t.prepare_commit())
+            .filter_map(|w| w.transpose())
+            .collect()
+    }
+    fn rollback(&mut self) {
+        self.all_mut().into_iter().for_each(|t| t.rollback());
+    }
+    /// What changed in every table between the snapshot and these tables
+    pub fn diff_snapshot(
+        &self,
+        dirs: &DbDirs,
+        storage_config: StorageConfig,
+        id: &str,
+    ) -> Result<Vec<diff::TableDiff>> {
+        let mut snapshot_tables = load_snapshot(dirs, storage_config, id)?;
+        let mut diffs = Vec::new();
+        for write in self.prepare_write()? {
+            let live = write
+                .rows
+                .iter()
+                .map(|r| serde_json::from_str(r.data.as_str()))
+                .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+            let old = snapshot_tables.remove(&write.name).unwrap_or_default();
+            let table_diff = diff::diff_rows(write.name.as_str(), write.key_columns, &old, &live);
+            if !table_diff.is_empty() {
+                diffs.push(table_diff);
+            }
+        }
+        Ok(diffs)
+    }
+    /// Table by its storage name
+    pub fn table(&self, name: &str) -> Result<&dyn AnyTable> {
+        match self.all().into_iter().find(|t| t.name() == name) {
+            Some(t) => Ok(t),
+            None => bail!(error::NotFound::NoSuchTable(name.to_string())),
+        }
+    }
+    /// User the token belongs to. Tokens without the admin scope act as
+    /// unrestricted users even if their user is an admin.
+    pub fn token_verify(&self, hasher: &auth::TokenHasher, token: &str) -> Result<current::User> {
+        let token_row = match self.tokens.find_token(hasher, token) {
+            Some(t) => t,
+            None => {
+                return Err(anyhow::Error::new(error::Unauthorized::NoSuchToken(
+                    token.to_string(),
+                )))
+            }
+        };
+        if token_row.is_expired() {
+            return Err(anyhow::Error::new(error::Unauthorized::TokenExpired(
+                token.to_string(),
+            )));
+        }
+        // Login codes are only good for starting a session
+        if token_row.kind == current::TokenKind::Login {
+            return Err(anyhow::Error::new(error::Unauthorized::NoSuchToken(
+                token.to_string(),
+            )));
+        }
+        let mut user = match self.users.lookup(&token_row.user) {
+            Some(u) => u.clone(),
+            None => {
+                return Err(anyhow::Error::new(error::Unauthorized::NoUserWithToken(
+                    token.to_string(),
+                )))
+            }
+        };
+        if user.access_group == current::AccessGroup::Admin
+            && !token_row.has_scope(current::Scope::Admin)
+        {
+            user.access_group = current::AccessGroup::Unrestricted;
+        }
+        Ok(user)
+    }
+    /// User the token belongs to if the token has the scope
+    pub fn token_verify_scope(
+        &self,
+        hasher: &auth::TokenHasher,
+        token: &str,
+        scope: current::Scope,
+    ) -> Result<current::User> {
+        let user = self.token_verify(hasher, token)?;
+        match self.tokens.find_token(hasher, token) {
+            Some(t) if t.has_scope(scope) => Ok(user),
+            _ => bail!(error::Unauthorized::MissingScope(scope)),
+        }
+    }
+
+    /// Tokens of the user that haven't expired
+    pub fn user_tokens(&self, email: &str) -> Vec<TokenInfo> {
+        let email = email.to_lowercase();
+        self.tokens
+            .current
+            .data
+            .iter()
+            .filter(|t| t.user == email && !t.is_expired() && t.used.is_none())
+            .map(TokenInfo::from)
+            .collect()
+    }
+
+    pub fn get_participants_subset(&self, site: current::Site) -> Vec<&current::Participant> {
+        self.participants.filter_and_collect(|p| p.site == site)
+    }
+
+    /// Rows of the table that belong to participants at the site
+    pub fn get_site_subset<'a, C>(
+        &'a self,
+        table: &'a Table<C>,
+        site: current::Site,
+    ) -> Vec<&'a C> {
+        let participants = self.get_participants_subset(site);
+        table.filter_by_pids(participants.iter().map(|p| p.pid.as_str()))
+    }
+
+    /// Row history of one row, oldest first. `pk` is the primary key as
+    /// `diff::key_text` writes it. Site-restricted users only see the rows of
+    /// their site's participants, users and tokens are only shown to admins.
+    pub fn get_history(
+        &self,
+        table: &str,
+        pk: &str,
+        access_group: current::AccessGroup,
+    ) -> Result<Vec<&current::HistoryEntry>> {
+        if !table_specs().iter().any(|s| s.name == table) {
+            bail!(error::NotFound::NoSuchTable(table.to_string()));
+        }
+        if (table == self.users.name || table == self.tokens.name)
+            && access_group != current::AccessGroup::Admin
+        {
+            bail!(error::Unauthorized::InsufficientAccess(
+                access_group,
+                current::AccessGroup::Admin
+            ));
+        }
+        let visible = |entry: &current::HistoryEntry| match access_group {
+            current::AccessGroup::Site(site) => entry
+                .before
+                .iter()
+                .chain(entry.after.iter())
+                .any(|row| self.row_at_site(row, site)),
+            current::AccessGroup::Unrestricted | current::AccessGroup::Admin => true,
+        };
+        Ok(self
+            .history
+            .filter_and_collect(|e| e.table == table && diff::key_text(&e.pk) == pk && visible(e)))
+    }
+    /// Whether a row belongs to the site, either directly or through its participant
+    fn row_at_site(&self, row: &serde_json::Value, site: current::Site) -> bool {
+        if let Some(row_site) = row.get("site") {
+            return serde_json::from_value::<current::Site>(row_site.clone()).ok() == Some(site);
+        }
+        match row.get("pid").and_then(|pid| pid.as_str()) {
+            Some(pid) => self
+                .participants
+                .lookup(&pid.to_string())
+                .is_some_and(|p| p.site == site),
+            None => false,
+        }
+    }
+    pub fn find_table_issues(&self, access_group: current::AccessGroup) -> Result<TableIssues> {
+        log::debug!("verifying db");
+
+        let mut allowed_pid: Vec<String> = self
+            .participants
+            .current
+            .data
+            .iter()
+            .filter(|p| match access_group {
+                current::AccessGroup::Unrestricted | current::AccessGroup::Admin => true,
+                current::AccessGroup::Site(site) => p.site == site,
+            })
+            .map(|p| p.pid.clone())
+            .collect();
+
+        allowed_pid.sort();
+
+        // Rows of missing participants don't belong to any site
+        let foreign_keys = match access_group {
+            current::AccessGroup::Site(_) => Vec::new(),
+            current::AccessGroup::Unrestricted => foreign_key::find_issues(&mut self.share())?
+                .into_iter()
+                .filter(|issue| issue.parent != self.users.name)
+                .collect(),
+            current::AccessGroup::Admin => foreign_key::find_issues(&mut self.share())?,
+        };
+
+        Ok(TableIssues {
+            participant: ParticipantTableIssues {
+                duplicate_email: find_duplicates(
+                    self.participants.filter_and_collect(|p| {
+                        allowed_pid.binary_search(&p.pid).is_ok() && p.email.is_some()
+                    }),
+                    |p| p.email.as_ref().unwrap().clone(),
+                    |p| p.pid.clone(),
+                ),
+            },
+            schedule: ScheduleTableIssues {
+                pk: self
+                    .schedule
+                    .find_pk_issues(|s| allowed_pid.binary_search(&s.pid).is_ok()),
+            },
+            weekly_survey: WeeklySurveyTableIssues {
+                pk: self
+                    .weekly_survey
+                    .find_pk_issues(|s| allowed_pid.binary_search(&s.pid).is_ok()),
+            },
+            virus: VirusTableIssues {
+                pk: self.virus.find_pk_issues(|_| true),
+            },
+            serology: SerologyTableIssues {
+                pk: self
+                    .serology
+                    .find_pk_issues(|s| allowed_pid.binary_search(&s.pid).is_ok()),
+                fk_participant: self.serology.find_fk_issues(
+                    |s| allowed_pid.binary_search(&s.pid).is_ok(),
+                    &self.participants.get_pks(),
+                    |v| v.pid.clone(),
+                ),
+                fk_virus: self.serology.find_fk_issues(
+                    |s| allowed_pid.binary_search(&s.pid).is_ok(),
+                    &self.virus.get_pks(),
+                    |v| v.virus.clone(),
+                ),
+            },
+            consent: self.find_consent_issues(&allowed_pid),
+            year_changes: self.find_year_change_issues(&allowed_pid),
+            foreign_keys,
+        })
+    }
+
+    fn find_year_change_issues(&self, sorted_allowed_pid: &[String]) -> YearChangeTableIssues {
+        let mut duplicate_pid = Vec::new();
+
+        fn key(x: &current::YearChange) -> (Option<&String>, u32) {
+            (x.pid.as_ref(), x.year)
+        }
+
+        let mut year_change: Vec<&current::YearChange> =
+            self.year_change.filter_and_collect(|x| match &x.pid {
+                Some(pid) => sorted_allowed_pid.binary_search(pid).is_ok(),
+                None => false,
+            });
+
+        if year_change.len() <= 1 {
+            return YearChangeTableIssues { duplicate_pid };
+        }
+
+        year_change.sort_by(|a, b| key(a).cmp(&key(b)));
+
+        let mut last_key = key(year_change[0]);
+        let mut last_record_id = &year_change[0].record_id;
+        let mut issue_rows = Vec::new();
+        for year_change_row in &year_change[1..] {
+            let this_key = key(year_change_row);
+            if this_key == last_key {
+                if issue_rows.is_empty() {
+                    issue_rows.push(last_record_id.clone());
+                }
+                issue_rows.push(year_change_row.record_id.clone());
+            } else {
+                if !issue_rows.is_empty() {
+                    let issue = KeyIssue {
+                        value: (last_key.0.unwrap().clone(), last_key.1),
+                        rows: issue_rows.clone(),
+                    };
+                    duplicate_pid.push(issue);
+                    issue_rows.clear();
+                }
+                last_key = this_key;
+                last_record_id = &year_change_row.record_id;
+            }
+        }
+
+        YearChangeTableIssues { duplicate_pid }
+    }
+
+    fn find_consent_issues(&self, sorted_allowed_pid: &[String]) -> ConsentTableIssues {
+        let mut conflicting_groups = Vec::new();
+
+        fn key(x: &current::Consent) -> (&String, u32, current::ConsentDisease) {
+            (&x.pid, x.year, x.disease)
+        }
+
+        let mut consent: Vec<&current::Consent> = self
+            .consent
+            .filter_and_collect(|x| sorted_allowed_pid.binary_search(&x.pid).is_ok());
+
+        if consent.is_empty() {
+            return ConsentTableIssues { conflicting_groups };
+        }
+
+        consent.sort_by(|a, b| key(a).cmp(&key(b)));
+
+        let mut last_key = key(consent[0]);
+        let mut last_group = consent[0].group;
+        let mut conflict_found = false;
+        for consent_row in consent {
+            let this_key = key(consent_row);
+            if this_key == last_key {
+                if conflict_found {
+                    continue;
+                }
+                if last_group.is_none() {
+                    last_group = consent_row.group;
+                } else if consent_row.group.is_some() && last_group != consent_row.group {
+                    conflict_found = true;
+                }
+            } else {
+                if conflict_found {
+                    conflicting_groups.push((last_key.0.clone(), last_key.1, last_key.2));
+                    conflict_found = false;
+                }
+                last_key = this_key;
+                last_group = consent_row.group;
+            }
+        }
+
+        ConsentTableIssues { conflicting_groups }
+    }
+}
+
+impl<C> Table<C> {
+    /// Creates table with empty data and no primary key in storage
+    pub fn new(name: &str) -> Self {
+        log::debug!("creating table {}", name);
+        Self {
+            name: name.to_string(),
+            current: Arc::new(TableData::default()),
+            key: None,
+            indexing: Indexing {
+                pk_hash: None,
+                pid: None,
+            },
+            backup: Backup::Inactive,
+        }
+    }
+    /// Copy sharing the data, without the state of the open transaction
+    pub fn share(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            current: self.current.clone(),
+            key: self.key,
+            indexing: self.indexing,
+            backup: Backup::Inactive,
+        }
+    }
+    fn key_columns(&self) -> &'static [&'static str] {
+        self.key.as_ref().map(|k| k.columns).unwrap_or(&[])
+    }
+    /// Whether the table was modified in the open transaction
+    fn is_modified(&self) -> bool {
+        matches!(self.backup, Backup::Modified(_))
+    }
+    /// Data as it was when the open transaction started
+    fn data_before(&self) -> &[C] {
+        match &self.backup {
+            Backup::Modified(before) => &before.data,
+            _ => &self.current.data,
+        }
+    }
+    /// Rows that belong to any of the participants, in table order
+    pub fn filter_by_pids<'a>(&self, pids: impl Iterator<Item = &'a str>) -> Vec<&C> {
+        let mut positions: Vec<usize> = pids
+            .filter_map(|pid| self.current.pid_index.get(pid))
+            .flatten()
+            .copied()
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions.iter().map(|&i| &self.current.data[i]).collect()
+    }
+    pub fn map_and_collect<T, F>(&self, f: F) -> Vec<&T>
+    where
+        F: FnMut(&C) -> &T,
+    {
+        self.current.data.iter().map(f).collect::<Vec<&T>>()
+    }
+    pub fn filter_and_collect<F>(&self, f: F) -> Vec<&C>
+    where
+        F: FnMut(&&C) -> bool,
+    {
+        self.current.data.iter().filter(f).collect::<Vec<&C>>()
+    }
+}
+
+impl<C: Clone> Table<C> {
+    /// Current data for modification, backed up first if a transaction
+    /// has just started modifying this table. The data is copied if
+    /// it's shared.
+    pub fn data_mut(&mut self) -> DataMut<'_, C> {
+        self.touch();
+        DataMut {
+            indexing: self.indexing,
+            data: Arc::make_mut(&mut self.current),
+        }
+    }
+    /// Replaces current data, keeping the old data as backup if a transaction
+    /// has just started modifying this table
+    pub fn replace(&mut self, data: Vec<C>) {
+        self.touch();
+        self.current = Arc::new(TableData::indexed(data, self.indexing));
+    }
+    /// Backs up the data if a transaction has just started modifying this table.
+    /// The backup shares the data until it's modified.
+    fn touch(&mut self) {
+        if let Backup::Untouched = self.backup {
+            self.backup = Backup::Modified(self.current.clone());
+        }
+    }
+    fn row_mut(&mut self, position: usize) -> RowMut<'_, C> {
+        self.touch();
+        let data = Arc::make_mut(&mut self.current);
+        RowMut {
+            pk_hash: data.row_pk_hash(self.indexing, position),
+            pid: data.row_pid(self.indexing, position),
+            indexing: self.indexing,
+            position,
+            data,
+        }
+    }
+    fn begin(&mut self) {
+        self.backup = Backup::Untouched;
+    }
+    fn end(&mut self) {
+        self.backup = Backup::Inactive;
+    }
+    fn rollback(&mut self) {
+        if let Backup::Modified(before) = std::mem::replace(&mut self.backup, Backup::Inactive) {
+            self.current = before;
+        }
+    }
+}
+
+impl<C: PrimaryKey + Clone + Serialize> Table<C> {
+    /// Creates table with empty data that storage keys by the primary key
+    pub fn keyed(name: &str) -> Self {
+        let table = Self::new(name);
+        Self {
+            key: Some(TableKey {
+                columns: C::COLUMNS,
+                get: key_values::<C>,
+                verify: Self::verify_pk,
+            }),
+            indexing: Indexing {
+                pk_hash: Some(row_key_hash::<C>),
+                ..table.indexing
+            },
+            ..table
+        }
+    }
+}
+
+impl<C: ParticipantRow> Table<C> {
+    /// Also indexes rows by the participant they belong to
+    pub fn with_pid_index(self) -> Self {
+        Self {
+            indexing: Indexing {
+                pid: Some(C::pid),
+                ..self.indexing
+            },
+            ..self
+        }
+    }
+}
+
+impl<C: DeserializeOwned> Table<C> {
+    pub fn read(&mut self, storage: &mut dyn Storage) -> Result<()> {
+        let contents = storage.load(self.name.as_str(), self.key_columns())?;
+        let data = serde_json::from_str(contents.as_str())
+            .context(format!("table {} failed to parse", self.name))?;
+        self.current = Arc::new(TableData::indexed(data, self.indexing));
+        Ok(())
+    }
+}
+
+impl<C: DeserializeOwned> Table<C> {
+    /// Rows of this table from plain JSON rows
+    pub fn parse_rows(&self, rows: Vec<serde_json::Value>) -> Result<Vec<C>> {
+        let data = rows
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<serde_json::Result<Vec<C>>>()
+            .context(format!("table {} failed to parse", self.name))?;
+        Ok(data)
+    }
+}
+
+impl<C: Serialize> Table<C> {
+    /// Serializes current data without touching the disk
+    pub fn prepare_write(&self) -> Result<TableWrite> {
+        let mut rows = Vec::with_capacity(self.current.data.len());
+        for row in &self.current.data {
+            rows.push(RowWrite {
+                key: match &self.key {
+                    Some(key) => (key.get)(row)?,
+                    None => Vec::new(),
+                },
+                data: serde_json::to_string(row)
+                    .context(format!("table {} failed to serialize", self.name))?,
+            });
+        }
+        Ok(TableWrite {
+            name: self.name.clone(),
+            key_columns: self.key_columns(),
+            rows,
+        })
+    }
+    /// Serializes current data if it was modified in the open transaction
+    fn prepare_commit(&self) -> Result<Option<TableWrite>> {
+        match self.backup {
+            Backup::Modified(_) => Ok(Some(self.prepare_write()?)),
+            _ => Ok(None),
+        }
+    }
+    /// Rows that would change if current data were replaced with `new`
+    pub fn changes(&self, new: &[C]) -> Result<Vec<diff::RowChange>> {
+        let before = self.keyed_values(&self.current.data)?;
+        let after = self.keyed_values(new)?;
+        Ok(diff::changes_keyed(
+            before.iter().map(|(key, row)| (key.clone(), row)),
+            after.iter().map(|(key, row)| (key.clone(), row)),
+        ))
+    }
+    /// Rows as JSON along with their primary key, the whole row is the key
+    /// if the table doesn't have one
+    fn keyed_values(&self, rows: &[C]) -> Result<Vec<(serde_json::Value, serde_json::Value)>> {
+        let mut keyed = Vec::with_capacity(rows.len());
+        for row in rows {
+            let value = serde_json::to_value(row)
+                .context(format!("table {} failed to serialize", self.name))?;
+            let key = match &self.key {
+                Some(key) => {
+                    let mut values = (key.get)(row)?;
+                    match values.len() {
+                        1 => values.remove(0),
+                        _ => serde_json::Value::Array(values),
+                    }
+                }
+                None => value.clone(),
+            };
+            keyed.push((key, value));
+        }
+        Ok(keyed)
+    }
+}
+
+impl<C: PrimaryKey + Clone + serde::Serialize> Table<C> {
+    pub fn get_pks(&self) -> Vec<<C as PrimaryKey>::K> {
+        self.current.data.iter().map(|r| r.get_pk()).collect()
+    }
+    pub fn check_pks_present(&self, pks: &[&<C as PrimaryKey>::K]) -> Result<()> {
+        for pk in pks {
+            self.try_lookup(pk)?;
+        }
+        Ok(())
+    }
+    pub fn check_row_pk_absent(&self, row: &C) -> Result<()> {
+        let row_pk = row.get_pk();
+        if self.position(&row_pk).is_some() {
+            bail!(error::Conflict::PrimaryKey(
+                self.name.clone(),
+                format!("{:?}", row_pk)
+            ));
+        }
+        Ok(())
+    }
+    pub fn verify_pk(&self) -> Result<()> {
+        for positions in self.current.pk_index.values() {
+            for (i, &position) in positions.iter().enumerate() {
+                let pk = self.current.data[position].get_pk();
+                if positions[(i + 1)..]
+                    .iter()
+                    .any(|&other| self.current.data[other].get_pk() == pk)
+                {
+                    bail!(error::Conflict::PrimaryKey(
+                        self.name.clone(),
+                        format!("{:?}", pk)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds a row whose primary key isn't in the table yet
+    pub fn insert(&mut self, row: C) -> Result<()> {
+        self.check_row_pk_absent(&row)?;
+        self.touch();
+        let data = Arc::make_mut(&mut self.current);
+        data.data.push(row);
+        data.index_row(self.indexing, data.data.len() - 1);
+        Ok(())
+    }
+    pub fn find_pk_issues<S: FnMut(&&C) -> bool>(
+        &self,
+        subset: S,
+    ) -> Vec<KeyIssue<<C as PrimaryKey>::K, C>> {
+        log::debug!("Finding PK issues for table {}", self.name);
+        let mut issues = Vec::new();
+
+        let mut data: Vec<&C> = self.current.data.iter().filter(subset).collect();
+
+        if data.len() <= 1 {
+            return issues;
+        }
+
+        data.sort_by_key(|r| r.get_pk());
+
+        let mut previous_index = 0;
+        let mut previous_pk = data[previous_index].get_pk();
+        for this_index in 1..data.len() {
+            let this_pk = data[this_index].get_pk();
+            if this_pk != previous_pk {
+                if this_index - previous_index > 1 {
+                    let issue = KeyIssue {
+                        value: previous_pk,
+                        rows: data[previous_index..this_index]
+                            .iter()
+                            .cloned()
+                            .cloned()
+                            .collect(),
+                    };
+                    issues.push(issue);
+                }
+                previous_index = this_index;
+                previous_pk = this_pk;
+            }
+        }
+
+        issues
+    }
+    pub fn find_fk_issues<F: PartialEq + Ord, G: Fn(&C) -> F, S: FnMut(&&C) -> bool>(
+        &self,
+        subset: S,
+        fks: &[F],
+        get_fk: G,
+    ) -> Vec<KeyIssue<F, C>> {
+        log::debug!("Finding FK issues for table {}", self.name);
+        let mut issues = Vec::new();
+
+        let mut data: Vec<&C> = self.current.data.iter().filter(subset).collect();
+
+        if data.is_empty() {
+            return issues;
+        }
+
+        data.sort_by_key(|r| get_fk(r));
+
+        let mut previous_index = 0;
+        let mut previous_fk = get_fk(data[previous_index]);
+        for this_index in 0..data.len() {
+            let this_fk = get_fk(data[this_index]);
+            if this_fk != previous_fk {
+                if !fks.contains(&previous_fk) {
+                    let issue = KeyIssue {
+                        value: previous_fk,
+                        rows: data[previous_index..this_index]
+                            .iter()
+                            .cloned()
+                            .cloned()
+                            .collect(),
+                    };
+                    issues.push(issue);
+                }
+                previous_index = this_index;
+                previous_fk = this_fk;
+            }
+        }
+
+        issues
+    }
+    /// Position of the first row with the primary key
+    fn position(&self, pk: &<C as PrimaryKey>::K) -> Option<usize> {
+        self.current
+            .pk_index
+            .get(&key_hash(pk))?
+            .iter()
+            .copied()
+            .find(|&i| &self.current.data[i].get_pk() == pk)
+    }
+    pub fn lookup(&self, pk: &<C as PrimaryKey>::K) -> Option<&C> {
+        self.position(pk).map(|i| &self.current.data[i])
+    }
+    pub fn lookup_mut(&mut self, pk: &<C as PrimaryKey>::K) -> Option<RowMut<'_, C>> {
+        let position = self.position(pk)?;
+        Some(self.row_mut(position))
+    }
+    pub fn try_lookup(&self, pk: &<C as PrimaryKey>::K) -> Result<&C> {
+        match self.lookup(pk) {
+            Some(k) => Ok(k),
+            None => bail!(error::Conflict::PrimaryKey(
+                self.name.clone(),
+                format!("{:?}", pk)
+            )),
+        }
+    }
+    pub fn try_lookup_mut(&mut self, pk: &<C as PrimaryKey>::K) -> Result<RowMut<'_, C>> {
+        let own_name = self.name.clone();
+        match self.lookup_mut(pk) {
+            Some(k) => Ok(k),
+            None => bail!(error::Conflict::PrimaryKey(own_name, format!("{:?}", pk))),
+        }
+    }
+}
+
+impl DbDirs {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let mut dirs = Self::locate(root);
+
+        if dirs.current.is_dir() {
+            dirs.init_state = DbDirsInitState::Current;
+        } else if dirs.previous.is_dir() {
+            dirs.init_state = DbDirsInitState::Previous;
+        }
+
+        if !dirs.root.is_dir() {
+            fs::create_dir(dirs.root.as_path())?;
+        }
+
+        if dirs.init_state == DbDirsInitState::None {
+            fs::create_dir(dirs.current.as_path())?;
+        }
+
+        Ok(dirs)
+    }
+    /// Paths only, nothing is checked or created
+    pub fn locate<P: AsRef<Path>>(root: P) -> Self {
+        let root = root.as_ref().to_path_buf();
+        Self {
+            init_state: DbDirsInitState::None,
+            previous: root.join("previous"),
+            current: root.join("current"),
+            snapshots: root.join("snapshots"),
+            root,
+        }
+    }
+}
+
+/// Names and key columns of all tables
+pub fn table_specs() -> Vec<migration::TableSpec> {
+    Tables::new()
+        .all()
+        .iter()
+        .map(|t| migration::TableSpec {
+            name: t.name().to_string(),
+            key_columns: t.key_columns(),
+        })
+        .collect()
+}
+
+impl From<&current::Token> for TokenInfo {
+    fn from(token: &current::Token) -> Self {
+        Self {
+            id: token.id.clone(),
+            kind: token.kind,
+            created: token.created,
+            expires: token.expires,
+            scopes: token.scopes.clone(),
+        }
+    }
+}
+
+impl<C> Clone for TableKey<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C> Copy for TableKey<C> {}
+
+impl<C> Clone for Indexing<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C> Copy for Indexing<C> {}
+
+impl<C> Deref for DataMut<'_, C> {
+    type Target = Vec<C>;
+    fn deref(&self) -> &Vec<C> {
+        &self.data.data
+    }
+}
+
+impl<C> DerefMut for DataMut<'_, C> {
+    fn deref_mut(&mut self) -> &mut Vec<C> {
+        &mut self.data.data
+    }
+}
+
+impl<C> Drop for DataMut<'_, C> {
+    fn drop(&mut self) {
+        self.data.reindex(self.indexing);
+    }
+}
+
+impl<C> Deref for RowMut<'_, C> {
+    type Target = C;
+    fn deref(&self) -> &C {
+        &self.data.data[self.position]
+    }
+}
+
+impl<C> DerefMut for RowMut<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.data.data[self.position]
+    }
+}
+
+impl<C> Drop for RowMut<'_, C> {
+    fn drop(&mut self) {
+        self.data
+            .reindex_row(self.indexing, self.position, self.pk_hash, self.pid.take());
+    }
+}
+
+impl Table<current::Token> {
+    /// Position of the token's row. The index narrows down the rows, their
+    /// hashes are then compared in constant time.
+    fn token_position(&self, hasher: &auth::TokenHasher, token: &str) -> Option<usize> {
+        let hash = hasher.hash(token);
+        self.current
+            .pk_index
+            .get(&key_hash(&hash))?
+            .iter()
+            .copied()
+            .find(|&i| auth::hashes_match(self.current.data[i].hash.as_str(), hash.as_str()))
+    }
+    pub fn find_token(&self, hasher: &auth::TokenHasher, token: &str) -> Option<&current::Token> {
+        self.token_position(hasher, token)
+            .map(|i| &self.current.data[i])
+    }
+}
+
+impl<T> TableData<T> {
+    /// Data with its indexes built
+    fn indexed(data: Vec<T>, indexing: Indexing<T>) -> Self {
+        let mut indexed = Self {
+            data,
+            ..Self::default()
+        };
+        indexed.reindex(indexing);
+        indexed
+    }
+    fn reindex(&mut self, indexing: Indexing<T>) {
+        self.pk_index.clear();
+        self.pid_index.clear();
+        if indexing.pk_hash.is_none() && indexing.pid.is_none() {
+            return;
+        }
+        for position in 0..self.data.len() {
+            self.index_row(indexing, position);
+        }
+    }
+    /// Adds the row to the indexes, it has to be the last row indexed so far
+    fn index_row(&mut self, indexing: Indexing<T>, position: usize) {
+        let row = &self.data[position];
+        if let Some(hash) = indexing.pk_hash {
+            self.pk_index.entry(hash(row)).or_default().push(position);
+        }
+        if let Some(pid) = indexing.pid.and_then(|pid| pid(row)) {
+            self.pid_index
+                .entry(pid.to_string())
+                .or_default()
+                .push(position);
+        }
+    }
+    fn row_pk_hash(&self, indexing: Indexing<T>, position: usize) -> Option<u64> {
+        indexing.pk_hash.map(|hash| hash(&self.data[position]))
+    }
+    fn row_pid(&self, indexing: Indexing<T>, position: usize) -> Option<String> {
+        let pid = indexing.pid?;
+        pid(&self.data[position]).map(|pid| pid.to_string())
+    }
+    /// Moves the row within the indexes if its key or participant changed
+    fn reindex_row(
+        &mut self,
+        indexing: Indexing<T>,
+        position: usize,
+        old_pk_hash: Option<u64>,
+        old_pid: Option<String>,
+    ) {
+        let new_pk_hash = self.row_pk_hash(indexing, position);
+        if new_pk_hash != old_pk_hash {
+            if let Some(old) = old_pk_hash {
+                index_remove(&mut self.pk_index, &old, position);
+            }
+            if let Some(new) = new_pk_hash {
+                index_insert(&mut self.pk_index, new, position);
+            }
+        }
+        let new_pid = self.row_pid(indexing, position);
+        if new_pid != old_pid {
+            if let Some(old) = old_pid {
+                index_remove(&mut self.pid_index, &old, position);
+            }
+            if let Some(new) = new_pid {
+                index_insert(&mut self.pid_index, new, position);
+            }
+        }
+    }
+}
+
+impl<T> Default for TableData<T> {
+    /// Empty data
+    fn default() -> Self {
+        Self {
+            data: Vec::new(),
+            pk_index: HashMap::new(),
+            pid_index: HashMap::new(),
+        }
+    }
+}
+
+pub trait ToCurrent<C> {
+    fn to_current(&self) -> C;
+}
+
+pub trait PrimaryKey {
+    type K: std::fmt::Debug + PartialEq + PartialOrd + Ord + Hash + serde::Serialize;
+    /// Names of the key components, in the order they appear in the key
+    const COLUMNS: &'static [&'static str];
+    fn get_pk(&self) -> Self::K;
+}
+
+/// Rows that belong to a participant
+pub trait ParticipantRow {
+    fn pid(&self) -> Option<&str>;
+}
+
+fn key_hash<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn row_key_hash<C: PrimaryKey>(row: &C) -> u64 {
+    key_hash(&row.get_pk())
+}
+
+fn index_insert<K: Hash + Eq>(index: &mut HashMap<K, Vec<usize>>, key: K, position: usize) {
+    let positions = index.entry(key).or_default();
+    if let Err(i) = positions.binary_search(&position) {
+        positions.insert(i, position);
+    }
+}
+
+fn index_remove<K: Hash + Eq>(index: &mut HashMap<K, Vec<usize>>, key: &K, position: usize) {
+    if let Some(positions) = index.get_mut(key) {
+        positions.retain(|&p| p != position);
+        if positions.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+/// Primary key of the row as separate values, one per key column
+fn key_values<C: PrimaryKey>(row: &C) -> Result<Vec<serde_json::Value>> {
+    match serde_json::to_value(row.get_pk())? {
+        serde_json::Value::Array(values) => Ok(values),
+        value => Ok(vec![value]),
+    }
+}
+
+#[derive(serde_derive::Serialize, schemars::JsonSchema, Clone)]
+pub struct Duplicate<T, A> {
+    value: T,
+    associates: Vec<A>,
+}
+
+fn find_duplicates<V: Sized, T: Ord + Clone, A: Clone, FT: FnMut(&V) -> T, FA: Fn(&V) -> A>(
+    mut values: Vec<V>,
+    mut get_key: FT,
+    get_associates: FA,
+) -> Vec<Duplicate<T, A>> {
+    let mut result = Vec::new();
+
+    if values.len() <= 1 {
+        return result;
+    }
+
+    values.sort_by_key(|v| get_key(v));
+
+    let mut prev_value = &values[0];
+    let mut prev_key = get_key(prev_value);
+    let mut duplicate: Option<Duplicate<T, A>> = None;
+    for value in &values[1..] {
+        let key = get_key(value);
+        if key == prev_key {
+            match &mut duplicate {
+                Some(d) => d.associates.push(get_associates(value)),
+                None => {
+                    duplicate = Some(Duplicate {
+                        value: key.clone(),
+                        associates: vec![get_associates(prev_value), get_associates(value)],
+                    })
+                }
+            }
+        } else {
+            if let Some(duplicate) = duplicate {
+                result.push(duplicate.clone());
+            }
+            duplicate = None;
+        }
+        prev_value = value;
+        prev_key = key;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: &str = "admin@example.com";
+
+    fn open(dir: &Path) -> Db {
+        Db::new(
+            dir,
+            storage::StorageKind::Json.into(),
+            ADMIN,
+            auth::TokenHasher::new(None).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn user(email: &str, access_group: current::AccessGroup) -> current::User {
+        current::User {
+            email: email.to_string(),
+            access_group,
+            kind: current::UserKind::Manual,
+            deidentified_export: false,
+            capabilities: current::Capability::defaults(access_group, false),
+        }
+    }
+
+    fn participant(pid: &str, site: current::Site) -> current::Participant {
+        current::Participant {
+            pid: pid.to_string(),
+            site,
+            email: None,
+            mobile: None,
+            date_screening: None,
+            date_birth: None,
+            age_recruitment: None,
+            height: None,
+            weight: None,
+            bmi: None,
+            gender: None,
+            occupation: None,
+        }
+    }
+
+    fn token(email: &str, id: &str, kind: current::TokenKind) -> current::Token {
+        current::Token {
+            id: id.to_string(),
+            user: email.to_string(),
+            hash: format!("hash of {}", id),
+            kind,
+            created: Some(chrono::Utc::now()),
+            expires: Some(chrono::Utc::now() + chrono::Duration::days(1)),
+            scopes: Vec::new(),
+            used: None,
+        }
+    }
+
+    /// Code the error is replied with
+    fn code(e: anyhow::Error) -> &'static str {
+        error::from_anyhow(e).code
+    }
+
+    fn emails(db: &Db) -> Vec<&str> {
+        db.tables
+            .users
+            .current
+            .data
+            .iter()
+            .map(|u| u.email.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn interrupted_write_is_replayed_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        let mut users = db.tables.users.current.data.clone();
+        users.push(user(
+            "site@example.com",
+            current::AccessGroup::Site(current::Site::Perth),
+        ));
+        let current = db.dirs.current.clone();
+        drop(db);
+
+        // Interrupted right after the commit point
+        let contents = serde_json::to_string(&users).unwrap();
+        fs::write(current.join("User.json.tmp"), contents).unwrap();
+        let journal = serde_json::json!({ "files": [current.join("User.json")] });
+        fs::write(current.join("journal.json"), journal.to_string()).unwrap();
+
+        let db = open(dir.path());
+        assert_eq!(emails(&db), vec![ADMIN, "site@example.com"]);
+        assert!(!current.join("journal.json").exists());
+        assert!(!current.join("User.json.tmp").exists());
+    }
+
+    #[test]
+    fn uncommitted_write_is_discarded_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = open(dir.path()).dirs.current.clone();
+        fs::write(current.join("User.json.tmp"), "[]").unwrap();
+
+        let db = open(dir.path());
+        assert_eq!(emails(&db), vec![ADMIN]);
+        assert!(!current.join("User.json.tmp").exists());
+    }
+
+    #[test]
+    fn rollback_restores_every_touched_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        db.begin().unwrap();
+        db.tables
+            .users
+            .insert(user("site@example.com", current::AccessGroup::Unrestricted))
+            .unwrap();
+        db.tables
+            .participants
+            .insert(participant("P1", current::Site::Perth))
+            .unwrap();
+        db.tables.users.data_mut().retain(|u| u.email != ADMIN);
+        db.rollback().unwrap();
+
+        assert_eq!(emails(&db), vec![ADMIN]);
+        assert!(db
+            .tables
+            .users
+            .lookup(&"site@example.com".to_string())
+            .is_none());
+        assert!(db.tables.users.lookup(&ADMIN.to_string()).is_some());
+        assert!(db.tables.participants.current.data.is_empty());
+        // Nothing reached the disk either
+        drop(db);
+        let db = open(dir.path());
+        assert_eq!(emails(&db), vec![ADMIN]);
+        assert!(db.tables.participants.current.data.is_empty());
+    }
+
+    #[test]
+    fn failed_transaction_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let result: Result<()> = db.transaction(|db| {
+            db.tables
+                .participants
+                .insert(participant("P1", current::Site::Perth))?;
+            db.tables
+                .participants
+                .insert(participant("P1", current::Site::Perth))
+        });
+        assert_eq!(code(result.unwrap_err()), "primary_key");
+        assert!(db.tables.participants.current.data.is_empty());
+        // The transaction is over, a new one can start
+        db.begin().unwrap();
+        db.commit().unwrap();
+    }
+
+    #[test]
+    fn committed_tables_are_written_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        db.transaction(|db| {
+            db.tables
+                .users
+                .insert(user("site@example.com", current::AccessGroup::Unrestricted))?;
+            db.tables
+                .participants
+                .insert(participant("P1", current::Site::Perth))
+        })
+        .unwrap();
+        drop(db);
+        let db = open(dir.path());
+        assert_eq!(emails(&db), vec![ADMIN, "site@example.com"]);
+        assert_eq!(db.tables.participants.current.data.len(), 1);
+    }
+
+    #[test]
+    fn nested_begin_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        db.begin().unwrap();
+        assert_eq!(code(db.begin().unwrap_err()), "transaction_already_open");
+        db.commit().unwrap();
+        assert_eq!(code(db.commit().unwrap_err()), "no_transaction_open");
+        assert_eq!(code(db.rollback().unwrap_err()), "no_transaction_open");
+    }
+
+    #[test]
+    fn restore_snapshot_of_one_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let id = db.snapshot("before").unwrap().id;
+        db.transaction(|db| {
+            db.tables
+                .users
+                .insert(user("site@example.com", current::AccessGroup::Unrestricted))?;
+            db.tables
+                .participants
+                .insert(participant("P1", current::Site::Perth))
+        })
+        .unwrap();
+        let changed: Vec<String> = db
+            .diff_snapshot(id.as_str())
+            .unwrap()
+            .into_iter()
+            .map(|d| d.table)
+            .collect();
+        assert_eq!(changed, vec!["User", "Participant"]);
+
+        db.restore_snapshot(ADMIN, id.as_str(), Some("Participant"))
+            .unwrap();
+        assert!(db.tables.participants.current.data.is_empty());
+        assert_eq!(emails(&db), vec![ADMIN, "site@example.com"]);
+        let last = db.tables.history.current.data.last().unwrap();
+        assert_eq!(last.table, "Participant");
+        assert_eq!(last.actor, current::Actor::Manual(ADMIN.to_string()));
+        assert!(last.after.is_none());
+        // The data being replaced was snapshotted first
+        assert_eq!(db.list_snapshots().unwrap().len(), 2);
+
+        // Users are left alone unless they are named
+        db.restore_snapshot(ADMIN, id.as_str(), None).unwrap();
+        assert_eq!(emails(&db), vec![ADMIN, "site@example.com"]);
+        db.restore_snapshot(ADMIN, id.as_str(), Some("User"))
+            .unwrap();
+        assert_eq!(emails(&db), vec![ADMIN]);
+        // Only the record of the restores differs
+        let changed: Vec<String> = db
+            .diff_snapshot(id.as_str())
+            .unwrap()
+            .into_iter()
+            .map(|d| d.table)
+            .collect();
+        assert_eq!(changed, vec!["History"]);
+        drop(db);
+        let db = open(dir.path());
+        assert_eq!(emails(&db), vec![ADMIN]);
+    }
+
+    #[test]
+    fn restore_snapshot_refuses_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let id = db.snapshot("before").unwrap().id;
+        let e = db.restore_snapshot(ADMIN, "../current", None).unwrap_err();
+        assert_eq!(code(e), "no_such_snapshot");
+        let e = db
+            .restore_snapshot(ADMIN, id.as_str(), Some("History"))
+            .unwrap_err();
+        assert_eq!(code(e), "no_such_table");
+        let e = db
+            .restore_snapshot(ADMIN, id.as_str(), Some("Token"))
+            .unwrap_err();
+        assert_eq!(code(e), "no_such_table");
+        assert_eq!(db.list_snapshots().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sync_records_keyed_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let synced = vec![
+            participant("P1", current::Site::Perth),
+            participant("P2", current::Site::Perth),
+        ];
+        db.sync_redcap_participants(ADMIN, synced).unwrap();
+        let synced = vec![
+            participant("P2", current::Site::Sydney),
+            participant("P3", current::Site::Perth),
+        ];
+        let record = db.sync_redcap_participants(ADMIN, synced).unwrap();
+        assert_eq!(record.table, "Participant");
+        assert_eq!(record.user, ADMIN);
+        assert_eq!(record.added, vec![serde_json::json!("P3")]);
+        assert_eq!(record.removed, vec![serde_json::json!("P1")]);
+        assert_eq!(record.changed, vec![serde_json::json!("P2")]);
+        assert_eq!(db.tables.sync_history.current.data.len(), 2);
+
+        // Nothing changed is still recorded as a sync
+        let synced = db.tables.participants.current.data.clone();
+        let record = db.sync_redcap_participants(ADMIN, synced).unwrap();
+        assert!(record.added.is_empty() && record.removed.is_empty() && record.changed.is_empty());
+        assert_eq!(db.tables.sync_history.current.data.len(), 3);
+    }
+
+    #[test]
+    fn synced_pid_rename_catches_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        db.sync_redcap_participants(ADMIN, vec![participant("P1", current::Site::Perth)])
+            .unwrap();
+        db.sync_redcap_schedule(ADMIN, vec![schedule("P1", 2021, 0)])
+            .unwrap();
+        // REDCap renamed P1, the schedule refers to the old pid until it's synced
+        db.sync_redcap_participants(ADMIN, vec![participant("P2", current::Site::Perth)])
+            .unwrap();
+        let issues = foreign_key::find_issues(&mut db.tables).unwrap();
+        let missing: Vec<(&str, &str)> = issues
+            .iter()
+            .map(|i| (i.table.as_str(), i.value.as_str()))
+            .collect();
+        assert_eq!(missing, vec![("Schedule", "P1")]);
+        db.sync_redcap_schedule(ADMIN, vec![schedule("P2", 2021, 0)])
+            .unwrap();
+        assert!(foreign_key::find_issues(&mut db.tables).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokens_stay_out_of_history_and_restores() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        db.insert_token(token(ADMIN, "revoked", current::TokenKind::Session))
+            .unwrap();
+        let id = db.snapshot("before").unwrap().id;
+        db.transaction(|db| {
+            db.tables.tokens.data_mut().clear();
+            db.tables
+                .tokens
+                .insert(token(ADMIN, "t1", current::TokenKind::Session))
+        })
+        .unwrap();
+        db.restore_snapshot(ADMIN, id.as_str(), None).unwrap();
+        let ids: Vec<&str> = db
+            .tables
+            .tokens
+            .current
+            .data
+            .iter()
+            .map(|t| t.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["t1"]);
+        assert!(db
+            .tables
+            .history
+            .current
+            .data
+            .iter()
+            .all(|e| e.table != "Token"));
+    }
+
+    fn schedule(pid: &str, year: u32, day: u32) -> current::Schedule {
+        current::Schedule {
+            pid: pid.to_string(),
+            year,
+            day,
+            date: None,
+        }
+    }
+
+    /// The indexes are the same as if they were built from scratch
+    fn assert_indexed<C: Clone>(table: &Table<C>) {
+        let rebuilt = TableData::indexed(table.current.data.clone(), table.indexing);
+        assert_eq!(table.current.pk_index, rebuilt.pk_index);
+        assert_eq!(table.current.pid_index, rebuilt.pid_index);
+    }
+
+    fn schedule_table() -> Table<current::Schedule> {
+        let mut table = Table::keyed("Schedule").with_pid_index();
+        for pid in &["P1", "P2", "P3"] {
+            for day in 0..3 {
+                table.insert(schedule(pid, 2021, day)).unwrap();
+            }
+        }
+        assert_indexed(&table);
+        table
+    }
+
+    fn pids(table: &Table<current::Schedule>, pid: &str) -> Vec<(String, u32)> {
+        table
+            .filter_by_pids(std::iter::once(pid))
+            .into_iter()
+            .map(|s| (s.pid.clone(), s.day))
+            .collect()
+    }
+
+    #[test]
+    fn indexes_follow_data_mut() {
+        let mut table = schedule_table();
+        table.data_mut().retain(|s| s.pid != "P2" && s.day != 1);
+        assert_indexed(&table);
+        assert!(table.lookup(&("P2".to_string(), 2021, 0)).is_none());
+        assert!(table.lookup(&("P3".to_string(), 2021, 1)).is_none());
+        assert_eq!(table.lookup(&("P3".to_string(), 2021, 2)).unwrap().day, 2);
+        assert!(pids(&table, "P2").is_empty());
+        assert_eq!(pids(&table, "P3").len(), 2);
+
+        table.data_mut().reverse();
+        assert_indexed(&table);
+        assert_eq!(
+            table.lookup(&("P1".to_string(), 2021, 0)).unwrap().pid,
+            "P1"
+        );
+        assert_eq!(table.get_pks().len(), 4);
+    }
+
+    #[test]
+    fn indexes_follow_row_mut() {
+        let mut table = schedule_table();
+        let mut row = table.try_lookup_mut(&("P1".to_string(), 2021, 2)).unwrap();
+        // Through the row, `RowMut` has a `pid` of its own
+        let moved: &mut current::Schedule = &mut row;
+        moved.pid = "P2".to_string();
+        moved.day = 7;
+        drop(row);
+        assert_indexed(&table);
+        assert!(table.lookup(&("P1".to_string(), 2021, 2)).is_none());
+        assert!(table.lookup(&("P2".to_string(), 2021, 7)).is_some());
+        assert_eq!(pids(&table, "P1").len(), 2);
+        assert!(pids(&table, "P2").contains(&("P2".to_string(), 7)));
+        // The changed key is now taken
+        let e = table.insert(schedule("P2", 2021, 7)).unwrap_err();
+        assert_eq!(code(e), "primary_key");
+
+        // Changing something other than the key leaves the indexes alone
+        table
+            .try_lookup_mut(&("P3".to_string(), 2021, 0))
+            .unwrap()
+            .date = Some(chrono::Utc::now());
+        assert_indexed(&table);
+    }
+
+    #[test]
+    fn indexes_survive_rollback_and_sharing() {
+        let mut table = schedule_table();
+        let shared = table.share();
+        table.begin();
+        table.data_mut().clear();
+        table.insert(schedule("P9", 2022, 0)).unwrap();
+        assert_indexed(&table);
+        // Copies made before the change keep their own data and indexes
+        assert_indexed(&shared);
+        assert!(shared.lookup(&("P9".to_string(), 2022, 0)).is_none());
+        assert_eq!(shared.get_pks().len(), 9);
+
+        table.rollback();
+        assert_indexed(&table);
+        assert!(table.lookup(&("P9".to_string(), 2022, 0)).is_none());
+        assert_eq!(pids(&table, "P1").len(), 3);
+    }
+
+    #[test]
+    fn open_db_is_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        assert!(Db::new(
+            dir.path(),
+            storage::StorageKind::Json.into(),
+            ADMIN,
+            auth::TokenHasher::new(None).unwrap(),
+        )
+        .is_err());
+        assert!(encrypted::reencrypt(
+            dir.path(),
+            None,
+            encrypted::Key::from_hex(&"0".repeat(64)).unwrap()
+        )
+        .is_err());
+        drop(db);
+        open(dir.path());
+    }
+
+    /// Unrestricted user who can manage users and see identifiable data
+    fn manager() -> current::User {
+        current::User {
+            capabilities: vec![
+                current::Capability::ManageUsers,
+                current::Capability::ViewIdentifiable,
+            ],
+            ..user("manager@example.com", current::AccessGroup::Unrestricted)
+        }
+    }
+
+    fn admin(db: &Db) -> current::User {
+        db.tables.users.lookup(&ADMIN.to_string()).unwrap().clone()
+    }
+
+    #[test]
+    fn managers_cannot_grant_more_than_they_have() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let manager = manager();
+        db.add_users(&admin(&db), vec![manager.clone()]).unwrap();
+
+        let new_admin = user("new@example.com", current::AccessGroup::Admin);
+        let e = db.add_users(&manager, vec![new_admin]).unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+        let syncer = current::User {
+            capabilities: vec![current::Capability::Sync],
+            ..user("new@example.com", current::AccessGroup::Unrestricted)
+        };
+        let e = db.add_users(&manager, vec![syncer]).unwrap_err();
+        assert_eq!(code(e), "missing_capability");
+
+        // Not even to themselves
+        let promoted = current::User {
+            access_group: current::AccessGroup::Admin,
+            ..manager.clone()
+        };
+        let e = db.update_user(&manager, promoted).unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+        let e = db
+            .update_user(
+                &manager,
+                current::User {
+                    capabilities: current::Capability::ALL.to_vec(),
+                    ..manager.clone()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(code(e), "missing_capability");
+        assert_eq!(
+            db.tables.users.lookup(&manager.email).unwrap().access_group,
+            current::AccessGroup::Unrestricted
+        );
+
+        // What they have they can give
+        let site = user(
+            "site@example.com",
+            current::AccessGroup::Site(current::Site::Perth),
+        );
+        db.add_users(&manager, vec![site]).unwrap();
+        assert_eq!(db.tables.users.current.data.len(), 3);
+    }
+
+    #[test]
+    fn managers_cannot_change_users_who_have_more() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let manager = manager();
+        let other_admin = user("other@example.com", current::AccessGroup::Admin);
+        db.add_users(&admin(&db), vec![manager.clone(), other_admin.clone()])
+            .unwrap();
+
+        let demoted = current::User {
+            access_group: current::AccessGroup::Unrestricted,
+            capabilities: Vec::new(),
+            ..other_admin.clone()
+        };
+        let e = db.update_user(&manager, demoted).unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+        let e = db
+            .remove_user(&manager, other_admin.email.as_str())
+            .unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+
+        // Site users only manage their own site
+        let perth = current::User {
+            capabilities: vec![current::Capability::ManageUsers],
+            deidentified_export: true,
+            ..user(
+                "perth@example.com",
+                current::AccessGroup::Site(current::Site::Perth),
+            )
+        };
+        db.add_users(&manager, vec![perth.clone()]).unwrap();
+        let sydney = current::User {
+            deidentified_export: true,
+            capabilities: Vec::new(),
+            ..user(
+                "sydney@example.com",
+                current::AccessGroup::Site(current::Site::Sydney),
+            )
+        };
+        let e = db.add_users(&perth, vec![sydney]).unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+        // Nor can they give out identifiable data they can't see
+        let identifiable = current::User {
+            deidentified_export: false,
+            capabilities: Vec::new(),
+            ..user(
+                "perth2@example.com",
+                current::AccessGroup::Site(current::Site::Perth),
+            )
+        };
+        let e = db.add_users(&perth, vec![identifiable]).unwrap_err();
+        assert_eq!(code(e), "deidentified_only");
+        assert_eq!(db.tables.users.current.data.len(), 4);
+    }
+
+    #[test]
+    fn only_admins_sync_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let manager = manager();
+        db.add_users(&admin(&db), vec![manager.clone()]).unwrap();
+        let e = db.sync_redcap_users(&manager, Vec::new()).unwrap_err();
+        assert_eq!(code(e), "insufficient_access");
+        let record = db.sync_redcap_users(&admin(&db), Vec::new()).unwrap();
+        assert!(record.removed.is_empty());
+    }
+
+    fn redcap_user(email: &str, access_group: current::AccessGroup) -> current::User {
+        current::User {
+            kind: current::UserKind::Redcap,
+            ..user(email, access_group)
+        }
+    }
+
+    #[test]
+    fn redcap_user_capabilities_survive_syncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let admin = admin(&db);
+        let site = current::AccessGroup::Site(current::Site::Perth);
+        db.sync_redcap_users(&admin, vec![redcap_user("r@example.com", site)])
+            .unwrap();
+
+        // Only capabilities change for REDCap users
+        let granted = current::User {
+            capabilities: vec![
+                current::Capability::ViewIdentifiable,
+                current::Capability::Sync,
+            ],
+            ..redcap_user("r@example.com", site)
+        };
+        db.update_user(&admin, granted.clone()).unwrap();
+        let moved = current::User {
+            access_group: current::AccessGroup::Unrestricted,
+            ..granted.clone()
+        };
+        let e = db.update_user(&admin, moved).unwrap_err();
+        assert_eq!(code(e), "redcap_user");
+        let e = db.remove_user(&admin, "r@example.com").unwrap_err();
+        assert_eq!(code(e), "redcap_user");
+
+        let capabilities = |db: &Db| {
+            db.tables
+                .users
+                .lookup(&"r@example.com".to_string())
+                .unwrap()
+                .capabilities
+                .clone()
+        };
+        db.sync_redcap_users(&admin, vec![redcap_user("r@example.com", site)])
+            .unwrap();
+        assert_eq!(capabilities(&db), granted.capabilities);
+
+        // New access in REDCap starts from its defaults
+        let unrestricted = current::AccessGroup::Unrestricted;
+        db.sync_redcap_users(&admin, vec![redcap_user("r@example.com", unrestricted)])
+            .unwrap();
+        assert_eq!(
+            capabilities(&db),
+            current::Capability::defaults(unrestricted, false)
+        );
+    }
+
+    #[test]
+    fn login_codes_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let mut expired = token(ADMIN, "expired", current::TokenKind::Login);
+        expired.expires = Some(chrono::Utc::now() - chrono::Duration::minutes(1));
+        db.insert_token(expired).unwrap();
+        for (minutes, id) in [(13, "c1"), (14, "c2"), (15, "c3")] {
+            let mut code = token(ADMIN, id, current::TokenKind::Login);
+            code.expires = Some(chrono::Utc::now() + chrono::Duration::minutes(minutes));
+            db.insert_login_code(code, 2).unwrap();
+        }
+        db.insert_token(token(ADMIN, "session", current::TokenKind::Session))
+            .unwrap();
+        let mut ids = db
+            .tables
+            .tokens
+            .current
+            .data
+            .iter()
+            .map(|t| t.id.as_str())
+            .collect::<Vec<&str>>();
+        ids.sort();
+        // The oldest outstanding code and the expired one make room
+        assert_eq!(ids, vec!["c2", "c3", "session"]);
+    }
+
+    #[test]
+    fn login_code_is_single_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let (login, mut row) = current::Token::new(
+            ADMIN,
+            current::TokenKind::Login,
+            16,
+            None,
+            &[],
+            &db.token_hasher,
+        );
+        row.expires = Some(chrono::Utc::now() + chrono::Duration::minutes(15));
+        db.insert_login_code(row, 5).unwrap();
+        let session = db.login_code_exchange(&login, 16, 1, 5).unwrap();
+        let session = db
+            .tables
+            .tokens
+            .find_token(&db.token_hasher, &session)
+            .unwrap();
+        assert_eq!(session.kind, current::TokenKind::Session);
+        assert_eq!(session.user, ADMIN);
+        assert_eq!(
+            code(db.login_code_exchange(&login, 16, 1, 5).unwrap_err()),
+            "login_code_used_or_expired"
+        );
+    }
+
+    #[test]
+    fn login_code_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let (login, mut row) = current::Token::new(
+            ADMIN,
+            current::TokenKind::Login,
+            16,
+            None,
+            &[],
+            &db.token_hasher,
+        );
+        row.expires = Some(chrono::Utc::now() - chrono::Duration::minutes(1));
+        db.insert_token(row).unwrap();
+        assert_eq!(
+            code(db.login_code_exchange(&login, 16, 1, 5).unwrap_err()),
+            "login_code_used_or_expired"
+        );
+        let (api, row) = current::Token::new(
+            ADMIN,
+            current::TokenKind::Api,
+            16,
+            Some(1),
+            &[],
+            &db.token_hasher,
+        );
+        db.insert_token(row).unwrap();
+        assert_eq!(
+            code(db.login_code_exchange(&api, 16, 1, 5).unwrap_err()),
+            "wrong_token_kind"
+        );
+        assert_eq!(
+            code(db.login_code_exchange("nope", 16, 1, 5).unwrap_err()),
+            "no_such_token"
+        );
+    }
+
+    #[test]
+    fn expired_session_is_not_refreshed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = open(dir.path());
+        let (session, mut row) = current::Token::new(
+            ADMIN,
+            current::TokenKind::Session,
+            16,
+            Some(1),
+            &[],
+            &db.token_hasher,
+        );
+        row.expires = Some(chrono::Utc::now() - chrono::Duration::minutes(1));
+        let id = row.id.clone();
+        db.insert_token(row).unwrap();
+        assert_eq!(
+            code(db.token_refresh(&session, 16, 1).unwrap_err()),
+            "token_expired"
+        );
+        let row = db.tables.tokens.current.data.iter().find(|t| t.id == id);
+        assert!(row.unwrap().is_expired());
+
+        let (session, row) = current::Token::new(
+            ADMIN,
+            current::TokenKind::Session,
+            16,
+            Some(1),
+            &[],
+            &db.token_hasher,
+        );
+        db.insert_token(row).unwrap();
+        let refreshed = db.token_refresh(&session, 16, 1).unwrap();
+        assert!(db
+            .tables
+            .tokens
+            .find_token(&db.token_hasher, &refreshed)
+            .is_some());
+    }
+}
@@ -0,0 +1,144 @@
+use super::{
+    diff,
+    storage::{Storage, TableWrite},
+    Table, TableData,
+};
+use crate::Result;
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+/// Declares every table once. Generates `Tables` with a field per table, how
+/// each table is created, and the registry that everything working on any
+/// table goes through - storage, transactions, integrity checks, snapshots.
+macro_rules! tables {
+    ($($field:ident: $row:ty = $create:expr,)*) => {
+        /// Every table. Copies made with `share` are cheap, they share the data
+        /// of each table until it's modified.
+        pub struct Tables {
+            $(pub $field: Table<$row>,)*
+        }
+
+        impl Tables {
+            fn new() -> Self {
+                Self {
+                    $($field: $create,)*
+                }
+            }
+            /// Copy of every table sharing its data, without the state of the open transaction
+            pub fn share(&self) -> Self {
+                Self {
+                    $($field: self.$field.share(),)*
+                }
+            }
+            /// Every table, in the order they are declared
+            pub fn all(&self) -> Vec<&dyn AnyTable> {
+                vec![$(&self.$field,)*]
+            }
+            pub fn all_mut(&mut self) -> Vec<&mut dyn AnyTable> {
+                vec![$(&mut self.$field,)*]
+            }
+        }
+    };
+}
+
+/// What can be done with a table without knowing the type of its rows
+pub trait AnyTable: Send + Sync {
+    fn name(&self) -> &str;
+    /// Columns storage keys the table by, empty if the table has no primary key
+    fn key_columns(&self) -> &'static [&'static str];
+    fn row_count(&self) -> usize;
+    /// Replaces the data with what's in storage
+    fn read(&mut self, storage: &mut dyn Storage) -> Result<()>;
+    /// Replaces the data with what's in storage if every row parses. Leaves the
+    /// table empty and returns every problem found otherwise.
+    fn read_rows(&mut self, storage: &mut dyn Storage) -> std::result::Result<(), Vec<String>>;
+    /// Fails on the first primary key found more than once
+    fn verify_pk(&self) -> Result<()>;
+    /// Serializes current data without touching the disk
+    fn prepare_write(&self) -> Result<TableWrite>;
+    /// Serializes current data if it was modified in the open transaction
+    fn prepare_commit(&self) -> Result<Option<TableWrite>>;
+    fn begin(&mut self);
+    fn end(&mut self);
+    fn rollback(&mut self);
+    /// Every row as plain JSON
+    fn rows(&self) -> Result<Vec<serde_json::Value>>;
+    /// Replaces the data with plain JSON rows, returns the rows that changed
+    fn replace_rows(&mut self, rows: Vec<serde_json::Value>) -> Result<Vec<diff::RowChange>>;
+}
+
+impl<C> AnyTable for Table<C>
+where
+    C: Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    fn key_columns(&self) -> &'static [&'static str] {
+        Table::key_columns(self)
+    }
+    fn row_count(&self) -> usize {
+        self.current.data.len()
+    }
+    fn read(&mut self, storage: &mut dyn Storage) -> Result<()> {
+        Table::read(self, storage)
+    }
+    fn read_rows(&mut self, storage: &mut dyn Storage) -> std::result::Result<(), Vec<String>> {
+        let contents = storage
+            .load(self.name.as_str(), Table::key_columns(self))
+            .map_err(|e| vec![format!("{:#}", e)])?;
+        let rows: Vec<serde_json::Value> = serde_json::from_str(contents.as_str())
+            .map_err(|e| vec![format!("not a JSON array of rows: {}", e)])?;
+        let mut data = Vec::with_capacity(rows.len());
+        let mut problems = Vec::new();
+        for (i, row) in rows.into_iter().enumerate() {
+            match serde_json::from_value(row) {
+                Ok(row) => data.push(row),
+                Err(e) => problems.push(format!("row {}: {}", i, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        self.current = Arc::new(TableData::indexed(data, self.indexing));
+        Ok(())
+    }
+    fn verify_pk(&self) -> Result<()> {
+        match &self.key {
+            Some(key) => (key.verify)(self),
+            None => Ok(()),
+        }
+    }
+    fn prepare_write(&self) -> Result<TableWrite> {
+        Table::prepare_write(self)
+    }
+    fn prepare_commit(&self) -> Result<Option<TableWrite>> {
+        Table::prepare_commit(self)
+    }
+    fn begin(&mut self) {
+        Table::begin(self)
+    }
+    fn end(&mut self) {
+        Table::end(self)
+    }
+    fn rollback(&mut self) {
+        Table::rollback(self)
+    }
+    fn rows(&self) -> Result<Vec<serde_json::Value>> {
+        self.current
+            .data
+            .iter()
+            .map(|row| {
+                serde_json::to_value(row)
+                    .context(format!("table {} failed to serialize", self.name))
+            })
+            .collect()
+    }
+    fn replace_rows(&mut self, rows: Vec<serde_json::Value>) -> Result<Vec<diff::RowChange>> {
+        let data = self.parse_rows(rows)?;
+        let changes = self.changes(&data)?;
+        self.replace(data);
+        Ok(changes)
+    }
+}
@@ -0,0 +1,101 @@
+use super::{diff, integrity, snapshot, storage::StorageConfig, Db, DbDirs, Tables};
+use crate::{auth, data::current, deidentify, Result};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Db shared between requests. Writers take turns, readers get the tables
+/// as they were after the last write without waiting for the writers.
+pub struct SharedDb {
+    writer: Mutex<Db>,
+    /// Tables as of the last write, replaced whenever a writer is done
+    published: RwLock<Arc<Tables>>,
+    /// Doesn't change after the db is opened
+    integrity: integrity::Report,
+    pseudonyms: deidentify::Pseudonyms,
+    token_hasher: auth::TokenHasher,
+    /// Where the snapshots are, readers list and diff them without the writer
+    dirs: DbDirs,
+    storage_config: StorageConfig,
+}
+
+/// Exclusive access to the db, readers see the changes once it's dropped
+pub struct WriteGuard<'a> {
+    db: MutexGuard<'a, Db>,
+    published: &'a RwLock<Arc<Tables>>,
+}
+
+impl SharedDb {
+    pub fn new(db: Db) -> Self {
+        Self {
+            published: RwLock::new(Arc::new(db.tables.share())),
+            integrity: db.integrity.clone(),
+            pseudonyms: db.pseudonyms.clone(),
+            token_hasher: db.token_hasher.clone(),
+            dirs: DbDirs::locate(db.dirs.root.as_path()),
+            storage_config: db.storage_config,
+            writer: Mutex::new(db),
+        }
+    }
+    /// Every table as of the last write. The tables are consistent with each
+    /// other and stay the same for as long as they are held.
+    pub fn read(&self) -> Arc<Tables> {
+        self.published
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+    /// What the integrity check found when the db was opened
+    pub fn integrity(&self) -> &integrity::Report {
+        &self.integrity
+    }
+    /// What to deidentify participant data with if the user can only see it deidentified
+    pub fn pseudonyms_for(&self, user: &current::User) -> Option<&deidentify::Pseudonyms> {
+        if deidentify::applies(user) {
+            Some(&self.pseudonyms)
+        } else {
+            None
+        }
+    }
+    /// What token hashes are made with, the same as the db's
+    pub fn token_hasher(&self) -> &auth::TokenHasher {
+        &self.token_hasher
+    }
+    /// Snapshots that can be restored, without waiting for the writers
+    pub fn list_snapshots(&self) -> Result<Vec<snapshot::SnapshotInfo>> {
+        snapshot::list(&self.dirs)
+    }
+    /// What changed in every table between the snapshot and the last write,
+    /// without waiting for the writers
+    pub fn diff_snapshot(&self, id: &str) -> Result<Vec<diff::TableDiff>> {
+        self.read()
+            .diff_snapshot(&self.dirs, self.storage_config, id)
+    }
+    /// Waits for the other writers to finish
+    pub async fn write(&self) -> WriteGuard<'_> {
+        WriteGuard {
+            db: self.writer.lock().await,
+            published: &self.published,
+        }
+    }
+}
+
+impl Deref for WriteGuard<'_> {
+    type Target = Db;
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Db {
+        &mut self.db
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        let tables = Arc::new(self.db.tables.share());
+        *self.published.write().unwrap_or_else(|e| e.into_inner()) = tables;
+    }
+}
@@ -0,0 +1,277 @@
+use super::DbDirs;
+use crate::{error, Result};
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file describing the snapshot, written last
+const INFO_FILE_NAME: &str = "snapshot.json";
+
+/// Which snapshots to keep after a new one is taken
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Number of most recent snapshots to keep
+    pub keep_last: usize,
+    /// Snapshots older than this are removed even if within `keep_last`
+    pub max_age_days: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    /// What the snapshot was taken before
+    pub reason: String,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep_last: 20,
+            max_age_days: None,
+        }
+    }
+}
+
+/// Copies everything in the current directory into a new snapshot directory.
+/// Ids are the creation time, snapshots taken within the same millisecond get
+/// a counter after it.
+pub fn take(dirs: &DbDirs, reason: &str, retention: Retention) -> Result<SnapshotInfo> {
+    if !dirs.snapshots.is_dir() {
+        fs::create_dir(dirs.snapshots.as_path())?;
+    }
+    let created = Utc::now();
+    let timestamp = created.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    let mut id = timestamp.clone();
+    let mut dir = dirs.snapshots.join(id.as_str());
+    let mut taken = 0;
+    loop {
+        match fs::create_dir(dir.as_path()) {
+            Ok(()) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                taken += 1;
+                id = format!("{}-{}", timestamp, taken);
+                dir = dirs.snapshots.join(id.as_str());
+            }
+            Err(e) => return Err(e).context(format!("failed to create {:?}", dir)),
+        }
+    }
+    let info = SnapshotInfo {
+        id,
+        created,
+        reason: reason.to_string(),
+    };
+    log::info!("taking snapshot {} ({})", info.id, info.reason);
+
+    for entry in fs::read_dir(dirs.current.as_path())? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_name() {
+            fs::copy(path.as_path(), dir.join(name))
+                .context(format!("failed to copy {:?} into snapshot", path))?;
+        }
+    }
+    fs::write(dir.join(INFO_FILE_NAME), serde_json::to_string(&info)?)?;
+
+    prune(dirs, retention)?;
+    Ok(info)
+}
+
+/// Complete snapshots, oldest first
+pub fn list(dirs: &DbDirs) -> Result<Vec<SnapshotInfo>> {
+    let mut snapshots = Vec::new();
+    if !dirs.snapshots.is_dir() {
+        return Ok(snapshots);
+    }
+    for entry in fs::read_dir(dirs.snapshots.as_path())? {
+        if let Some(info) = read_info(entry?.path().as_path())? {
+            snapshots.push(info);
+        }
+    }
+    // Counters after the time don't sort as text past 9
+    snapshots.sort_by(|a, b| {
+        a.created
+            .cmp(&b.created)
+            .then(a.id.len().cmp(&b.id.len()))
+            .then(a.id.cmp(&b.id))
+    });
+    Ok(snapshots)
+}
+
+/// Info of the snapshot in the directory, `None` if the snapshot was
+/// interrupted while copying or is being pruned
+fn read_info(dir: &Path) -> Result<Option<SnapshotInfo>> {
+    let info_path = dir.join(INFO_FILE_NAME);
+    let contents = match fs::read_to_string(info_path.as_path()) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("failed to read {:?}", info_path)),
+    };
+    let info = serde_json::from_str(contents.as_str())
+        .context(format!("file {:?} failed to parse", info_path))?;
+    Ok(Some(info))
+}
+
+/// Directory of an existing snapshot
+pub fn find(dirs: &DbDirs, id: &str) -> Result<PathBuf> {
+    if list(dirs)?.iter().any(|s| s.id == id) {
+        Ok(dirs.snapshots.join(id))
+    } else {
+        bail!(error::NotFound::NoSuchSnapshot(id.to_string()))
+    }
+}
+
+/// Removes the snapshots the retention doesn't keep and whatever is left of
+/// snapshots that were interrupted
+fn prune(dirs: &DbDirs, retention: Retention) -> Result<()> {
+    for entry in fs::read_dir(dirs.snapshots.as_path())? {
+        let dir = entry?.path();
+        if dir.is_dir() && read_info(dir.as_path())?.is_none() {
+            log::info!("removing interrupted snapshot {:?}", dir);
+            fs::remove_dir_all(dir.as_path()).context(format!("failed to remove {:?}", dir))?;
+        }
+    }
+    let snapshots = list(dirs)?;
+    let oldest_allowed = retention
+        .max_age_days
+        .map(|days| Utc::now() - chrono::Duration::days(days));
+    let keep_from = snapshots.len().saturating_sub(retention.keep_last);
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        // The newest one always stays
+        if i + 1 == snapshots.len() {
+            break;
+        }
+        let too_old = oldest_allowed.is_some_and(|t| snapshot.created < t);
+        if i < keep_from || too_old {
+            log::info!("removing snapshot {}", snapshot.id);
+            let dir = dirs.snapshots.join(snapshot.id.as_str());
+            fs::remove_dir_all(dir.as_path()).context(format!("failed to remove {:?}", dir))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs(root: &std::path::Path) -> DbDirs {
+        let dirs = DbDirs::locate(root);
+        fs::create_dir(dirs.current.as_path()).unwrap();
+        fs::write(dirs.current.join("User.json"), "[]").unwrap();
+        dirs
+    }
+
+    fn reasons(dirs: &DbDirs) -> Vec<String> {
+        list(dirs).unwrap().into_iter().map(|s| s.reason).collect()
+    }
+
+    fn age(dirs: &DbDirs, info: &SnapshotInfo, days: i64) {
+        let aged = SnapshotInfo {
+            created: info.created - chrono::Duration::days(days),
+            ..info.clone()
+        };
+        let path = dirs.snapshots.join(info.id.as_str()).join(INFO_FILE_NAME);
+        fs::write(path, serde_json::to_string(&aged).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn take_copies_current_files() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        let info = take(&dirs, "first", Retention::default()).unwrap();
+        let dir = find(&dirs, info.id.as_str()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("User.json")).unwrap(), "[]");
+        assert_eq!(reasons(&dirs), vec!["first"]);
+    }
+
+    #[test]
+    fn prune_keeps_last() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        let retention = Retention {
+            keep_last: 2,
+            max_age_days: None,
+        };
+        for reason in &["1", "2", "3", "4"] {
+            take(&dirs, reason, retention).unwrap();
+        }
+        assert_eq!(reasons(&dirs), vec!["3", "4"]);
+        assert_eq!(fs::read_dir(dirs.snapshots.as_path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn prune_removes_old() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        let retention = Retention {
+            keep_last: 10,
+            max_age_days: Some(7),
+        };
+        let old = take(&dirs, "old", retention).unwrap();
+        age(&dirs, &old, 8);
+        let recent = take(&dirs, "recent", retention).unwrap();
+        age(&dirs, &recent, 6);
+        take(&dirs, "new", retention).unwrap();
+        assert_eq!(reasons(&dirs), vec!["recent", "new"]);
+    }
+
+    #[test]
+    fn prune_keeps_newest_even_if_old() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        let retention = Retention {
+            keep_last: 10,
+            max_age_days: Some(7),
+        };
+        let only = take(&dirs, "only", retention).unwrap();
+        age(&dirs, &only, 30);
+        prune(&dirs, retention).unwrap();
+        assert_eq!(reasons(&dirs), vec!["only"]);
+    }
+
+    #[test]
+    fn interrupted_snapshots_are_ignored() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        take(&dirs, "complete", Retention::default()).unwrap();
+        let interrupted = dirs.snapshots.join("20200101T000000.000Z");
+        fs::create_dir(interrupted.as_path()).unwrap();
+        fs::write(interrupted.join("User.json"), "[]").unwrap();
+        assert_eq!(reasons(&dirs), vec!["complete"]);
+        assert!(find(&dirs, "20200101T000000.000Z").is_err());
+        // Cleaned up when the next one is taken
+        take(&dirs, "next", Retention::default()).unwrap();
+        assert!(!interrupted.exists());
+        assert_eq!(reasons(&dirs), vec!["complete", "next"]);
+    }
+
+    #[test]
+    fn ids_are_unique_within_a_millisecond() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        let reasons_taken: Vec<String> = (0..12).map(|i| i.to_string()).collect();
+        let ids: Vec<String> = reasons_taken
+            .iter()
+            .map(|reason| take(&dirs, reason, Retention::default()).unwrap().id)
+            .collect();
+        let unique: std::collections::HashSet<&String> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+        assert_eq!(reasons(&dirs), reasons_taken);
+    }
+
+    #[test]
+    fn find_rejects_unknown_and_path_like_ids() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = dirs(root.path());
+        take(&dirs, "first", Retention::default()).unwrap();
+        for id in &["nope", "../current", "..", ""] {
+            let e = find(&dirs, id).unwrap_err();
+            assert_eq!(error::from_anyhow(e).code, "no_such_snapshot", "{}", id);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use super::{
+    json::JsonStorage,
+    storage::{Storage, TableWrite},
+};
+use crate::{error, Result};
+use anyhow::{bail, Context};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OpenFlags, OptionalExtension};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Name of the database file inside the data directory
+const FILE_NAME: &str = "hsf.sqlite";
+
+/// Keeps every table in one SQLite database, one SQL table per table.
+/// Primary key columns are stored alongside the JSON of the row and
+/// declared as the SQL primary key. Only changed rows are written.
+pub struct SqliteStorage {
+    dir: PathBuf,
+    connection: Connection,
+    /// Hashes of what's on disk for tables with a primary key, by table then by key
+    row_hashes: HashMap<String, HashMap<String, u64>>,
+    /// Hashes of what's on disk for tables without a primary key
+    table_hashes: HashMap<String, u64>,
+    read_only: bool,
+}
+
+impl SqliteStorage {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(FILE_NAME);
+        let connection =
+            Connection::open(path.as_path()).context(format!("failed to open {:?}", path))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            connection,
+            row_hashes: HashMap::new(),
+            table_hashes: HashMap::new(),
+            read_only: false,
+        })
+    }
+    /// Leaves the directory as it is, the database file isn't created if it
+    /// doesn't exist yet and tables are read from JSON instead
+    pub fn open_read_only(dir: &Path) -> Result<Self> {
+        let path = dir.join(FILE_NAME);
+        let connection = if path.is_file() {
+            Connection::open_with_flags(path.as_path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .context(format!("failed to open {:?}", path))?
+        } else {
+            Connection::open_in_memory()?
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            connection,
+            row_hashes: HashMap::new(),
+            table_hashes: HashMap::new(),
+            read_only: true,
+        })
+    }
+    /// Tables written by the JSON storage before the switch
+    fn json(&self) -> Result<JsonStorage> {
+        match self.read_only {
+            true => JsonStorage::open_read_only(self.dir.as_path(), None),
+            false => JsonStorage::open(self.dir.as_path(), None),
+        }
+    }
+    fn table_exists(&self, name: &str) -> Result<bool> {
+        let found = self
+            .connection
+            .query_row(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                params![name],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self, name: &str, key_columns: &[&str]) -> Result<String> {
+        if !self.table_exists(name)? {
+            // Tables written by the JSON storage before the switch are picked up
+            // here and land in the database the next time they are written
+            log::debug!("table {} not in sqlite, falling back to json", name);
+            return self.json()?.load(name, key_columns);
+        }
+
+        let mut select = key_columns
+            .iter()
+            .map(|c| quote(c))
+            .collect::<Vec<String>>();
+        select.push("data".to_string());
+        let mut statement = self.connection.prepare(
+            format!(
+                "SELECT {} FROM {} ORDER BY rowid",
+                select.join(", "),
+                quote(name)
+            )
+            .as_str(),
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut data = Vec::new();
+        let mut hashes = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let mut key = Vec::with_capacity(key_columns.len());
+            for i in 0..key_columns.len() {
+                key.push(from_sql_value(row.get(i)?));
+            }
+            let row_data: String = row.get(key_columns.len())?;
+            hashes.insert(serde_json::to_string(&key)?, hash(row_data.as_str()));
+            data.push(row_data);
+        }
+        let data = format!("[{}]", data.join(","));
+
+        if key_columns.is_empty() {
+            self.table_hashes.insert(name.to_string(), hash(data.as_str()));
+        } else {
+            self.row_hashes.insert(name.to_string(), hashes);
+        }
+        Ok(data)
+    }
+
+    fn store(&mut self, writes: &[TableWrite]) -> Result<()> {
+        self.store_tables(writes, None)
+    }
+
+    /// Version is kept in the `user_version` pragma, 0 means it was never set
+    fn version(&mut self) -> Result<Option<u32>> {
+        let version: u32 =
+            self.connection
+                .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version == 0 {
+            // Could be a database that's just been switched from JSON
+            return self.json()?.version();
+        }
+        Ok(Some(version))
+    }
+
+    fn store_version(&mut self, writes: &[TableWrite], version: u32) -> Result<()> {
+        self.store_tables(writes, Some(version))
+    }
+}
+
+impl SqliteStorage {
+    fn store_tables(&mut self, writes: &[TableWrite], version: Option<u32>) -> Result<()> {
+        if self.read_only {
+            bail!("storage at {:?} is opened read-only", self.dir);
+        }
+        let transaction = self.connection.transaction()?;
+        let mut new_row_hashes = Vec::new();
+        let mut new_table_hashes = Vec::new();
+
+        for write in writes {
+            let table = quote(write.name.as_str());
+            let columns = write
+                .key_columns
+                .iter()
+                .map(|c| quote(c))
+                .collect::<Vec<String>>();
+
+            if columns.is_empty() {
+                let contents = write
+                    .rows
+                    .iter()
+                    .map(|r| r.data.as_str())
+                    .collect::<Vec<&str>>();
+                let contents_hash = hash(format!("[{}]", contents.join(",")).as_str());
+                if self.table_hashes.get(&write.name) == Some(&contents_hash) {
+                    continue;
+                }
+                transaction.execute(
+                    format!("CREATE TABLE IF NOT EXISTS {} (data TEXT NOT NULL)", table).as_str(),
+                    [],
+                )?;
+                transaction.execute(format!("DELETE FROM {}", table).as_str(), [])?;
+                let mut insert = transaction
+                    .prepare(format!("INSERT INTO {} (data) VALUES (?1)", table).as_str())?;
+                for data in contents {
+                    insert.execute(params![data])?;
+                }
+                new_table_hashes.push((write.name.clone(), contents_hash));
+                continue;
+            }
+
+            // SQLite lets key columns be NULL unless they're declared NOT NULL
+            let definitions = columns
+                .iter()
+                .map(|c| format!("{} NOT NULL", c))
+                .collect::<Vec<String>>();
+            transaction.execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {0} ({1}, data TEXT NOT NULL, PRIMARY KEY ({2}))",
+                    table,
+                    definitions.join(", "),
+                    columns.join(", ")
+                )
+                .as_str(),
+                [],
+            )?;
+
+            let empty = HashMap::new();
+            let old_hashes = self.row_hashes.get(&write.name).unwrap_or(&empty);
+            let mut new_hashes = HashMap::with_capacity(write.rows.len());
+
+            let placeholders = (1..=columns.len() + 1)
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<String>>();
+            let mut upsert = transaction.prepare(
+                format!(
+                    "INSERT INTO {0} ({1}, data) VALUES ({2}) \
+                    ON CONFLICT ({1}) DO UPDATE SET data = excluded.data",
+                    table,
+                    columns.join(", "),
+                    placeholders.join(", ")
+                )
+                .as_str(),
+            )?;
+            for row in &write.rows {
+                let key = serde_json::to_string(&row.key)?;
+                let row_hash = hash(row.data.as_str());
+                if old_hashes.get(&key) != Some(&row_hash) {
+                    let mut values = row.key.iter().map(to_sql_value).collect::<Vec<Value>>();
+                    values.push(Value::Text(row.data.clone()));
+                    upsert.execute(params_from_iter(values))?;
+                }
+                if new_hashes.insert(key.clone(), row_hash).is_some() {
+                    bail!(error::Conflict::PrimaryKey(write.name.clone(), key));
+                }
+            }
+
+            let condition = columns
+                .iter()
+                .enumerate()
+                .map(|(i, c)| format!("{} = ?{}", c, i + 1))
+                .collect::<Vec<String>>();
+            let mut delete = transaction.prepare(
+                format!("DELETE FROM {} WHERE {}", table, condition.join(" AND ")).as_str(),
+            )?;
+            for key in old_hashes.keys().filter(|k| !new_hashes.contains_key(*k)) {
+                let key_values: Vec<serde_json::Value> = serde_json::from_str(key)?;
+                delete.execute(params_from_iter(key_values.iter().map(to_sql_value)))?;
+            }
+
+            new_row_hashes.push((write.name.clone(), new_hashes));
+        }
+
+        if let Some(version) = version {
+            transaction.execute_batch(format!("PRAGMA user_version = {}", version).as_str())?;
+        }
+
+        transaction.commit()?;
+
+        self.row_hashes.extend(new_row_hashes);
+        self.table_hashes.extend(new_table_hashes);
+        Ok(())
+    }
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn hash(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn to_sql_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+fn from_sql_value(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::Value::from(i),
+        Value::Real(f) => serde_json::Value::from(f),
+        Value::Text(s) => serde_json::Value::String(s),
+        Value::Blob(b) => serde_json::Value::from(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::RowWrite;
+
+    const KEY_COLUMNS: &[&str] = &["id", "year"];
+
+    fn write(
+        name: &str,
+        key_columns: &'static [&'static str],
+        rows: &[serde_json::Value],
+    ) -> TableWrite {
+        TableWrite {
+            name: name.to_string(),
+            key_columns,
+            rows: rows
+                .iter()
+                .map(|row| RowWrite {
+                    key: key_columns.iter().map(|c| row[*c].clone()).collect(),
+                    data: row.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn load(
+        storage: &mut SqliteStorage,
+        name: &str,
+        key_columns: &[&str],
+    ) -> Vec<serde_json::Value> {
+        serde_json::from_str(storage.load(name, key_columns).unwrap().as_str()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = vec![
+            serde_json::json!({"id": "a", "year": 2020, "value": 1.5}),
+            serde_json::json!({"id": "a", "year": 2021, "value": null}),
+        ];
+        let history = vec![serde_json::json!({"any": "thing"})];
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.version().unwrap(), None);
+        assert!(load(&mut storage, "Keyed", KEY_COLUMNS).is_empty());
+        storage
+            .store_version(
+                &[
+                    write("Keyed", KEY_COLUMNS, &rows),
+                    write("Unkeyed", &[], &history),
+                ],
+                3,
+            )
+            .unwrap();
+
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.version().unwrap(), Some(3));
+        assert_eq!(load(&mut storage, "Keyed", KEY_COLUMNS), rows);
+        assert_eq!(load(&mut storage, "Unkeyed", &[]), history);
+    }
+
+    #[test]
+    fn only_changed_rows_are_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = vec![
+            serde_json::json!({"id": "a", "year": 2020, "value": 1}),
+            serde_json::json!({"id": "b", "year": 2020, "value": 2}),
+            serde_json::json!({"id": "c", "year": 2020, "value": 3}),
+        ];
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        storage
+            .store(&[write("Keyed", KEY_COLUMNS, &rows)])
+            .unwrap();
+
+        // Changed behind the storage's back, stays as it is unless rewritten
+        storage
+            .connection
+            .execute(
+                "UPDATE \"Keyed\" SET data = '{\"tampered\":true}' WHERE id = 'a'",
+                [],
+            )
+            .unwrap();
+
+        let new_rows = vec![
+            rows[0].clone(),
+            serde_json::json!({"id": "b", "year": 2020, "value": 20}),
+            serde_json::json!({"id": "d", "year": 2020, "value": 4}),
+        ];
+        storage
+            .store(&[write("Keyed", KEY_COLUMNS, &new_rows)])
+            .unwrap();
+
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert_eq!(
+            load(&mut storage, "Keyed", KEY_COLUMNS),
+            vec![
+                serde_json::json!({"tampered": true}),
+                new_rows[1].clone(),
+                new_rows[2].clone(),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_keys_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let row = serde_json::json!({"id": "a", "year": 2020});
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert!(storage
+            .store(&[write("Keyed", KEY_COLUMNS, &[row.clone(), row])])
+            .is_err());
+        assert!(load(&mut storage, "Keyed", KEY_COLUMNS).is_empty());
+    }
+
+    #[test]
+    fn null_keys_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let row = serde_json::json!({"id": null, "year": 2020});
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert!(storage
+            .store(&[write("Keyed", KEY_COLUMNS, &[row])])
+            .is_err());
+        assert!(load(&mut storage, "Keyed", KEY_COLUMNS).is_empty());
+    }
+
+    #[test]
+    fn json_tables_are_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = vec![serde_json::json!({"id": "a", "year": 2020})];
+        let mut json = JsonStorage::open(dir.path(), None).unwrap();
+        json.store_version(&[write("Keyed", KEY_COLUMNS, &rows)], 5)
+            .unwrap();
+
+        let mut storage = SqliteStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.version().unwrap(), Some(5));
+        assert_eq!(load(&mut storage, "Keyed", KEY_COLUMNS), rows);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::Result;
+use anyhow::bail;
+use serde_derive::Deserialize;
+use std::path::Path;
+
+use super::{encrypted::Key, json::JsonStorage, sqlite::SqliteStorage};
+
+/// Where the tables of one database version are persisted
+pub trait Storage: Send {
+    /// Reads a table as a JSON array, empty if the table has never been written
+    fn load(&mut self, name: &str, key_columns: &[&str]) -> Result<String>;
+    /// Persists all given tables so that either all or none of them land
+    fn store(&mut self, writes: &[TableWrite]) -> Result<()>;
+    /// Schema version of the stored data, `None` if it was never recorded
+    fn version(&mut self) -> Result<Option<u32>>;
+    /// Persists all given tables together with a new schema version
+    fn store_version(&mut self, writes: &[TableWrite], version: u32) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum StorageKind {
+    /// One JSON file per table
+    #[default]
+    Json,
+    /// One embedded SQLite database for all tables
+    Sqlite,
+    /// One JSON file per table, encrypted with the storage key
+    EncryptedJson,
+}
+
+/// Everything needed to open storage
+#[derive(Debug, Clone, Copy)]
+pub struct StorageConfig {
+    pub kind: StorageKind,
+    /// Only for `EncryptedJson`
+    pub key: Option<Key>,
+}
+
+/// Full contents of a table to be persisted
+pub struct TableWrite {
+    pub name: String,
+    /// Columns making up the primary key, empty if the table has none
+    pub key_columns: &'static [&'static str],
+    pub rows: Vec<RowWrite>,
+}
+
+pub struct RowWrite {
+    /// Primary key values in the order of the key columns
+    pub key: Vec<serde_json::Value>,
+    /// The row serialized as JSON
+    pub data: String,
+}
+
+impl StorageConfig {
+    /// Kind and hex encoded key as they are given in the config
+    pub fn new(kind: StorageKind, key: Option<&str>) -> Result<Self> {
+        let key = key.map(Key::from_hex).transpose()?;
+        match (kind, key) {
+            (StorageKind::EncryptedJson, None) => {
+                bail!("EncryptedJson storage needs a storage key")
+            }
+            (StorageKind::Json, Some(_)) | (StorageKind::Sqlite, Some(_)) => {
+                bail!("storage key given but {:?} storage isn't encrypted", kind)
+            }
+            _ => Ok(Self { kind, key }),
+        }
+    }
+}
+
+impl From<StorageKind> for StorageConfig {
+    /// Storage that doesn't need a key
+    fn from(kind: StorageKind) -> Self {
+        Self { kind, key: None }
+    }
+}
+
+/// Opens the storage in an existing directory
+pub fn open(config: StorageConfig, dir: &Path) -> Result<Box<dyn Storage>> {
+    log::debug!("opening {:?} storage at {:?}", config.kind, dir);
+    let storage: Box<dyn Storage> = match config.kind {
+        StorageKind::Json => Box::new(JsonStorage::open(dir, None)?),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(dir)?),
+        StorageKind::EncryptedJson => match config.key {
+            Some(key) => Box::new(JsonStorage::open(dir, Some(key))?),
+            None => bail!("EncryptedJson storage needs a storage key"),
+        },
+    };
+    Ok(storage)
+}
+
+/// Opens the storage without changing anything in the directory, interrupted
+/// writes aren't recovered and every write is refused
+pub fn open_read_only(config: StorageConfig, dir: &Path) -> Result<Box<dyn Storage>> {
+    log::debug!("opening {:?} storage at {:?} read-only", config.kind, dir);
+    let storage: Box<dyn Storage> = match config.kind {
+        StorageKind::Json => Box::new(JsonStorage::open_read_only(dir, None)?),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open_read_only(dir)?),
+        StorageKind::EncryptedJson => match config.key {
+            Some(key) => Box::new(JsonStorage::open_read_only(dir, Some(key))?),
+            None => bail!("EncryptedJson storage needs a storage key"),
+        },
+    };
+    Ok(storage)
+}
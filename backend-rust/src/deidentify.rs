@@ -0,0 +1,265 @@
+use crate::{
+    data::current,
+    db::{self, DbDirs},
+    Result,
+};
+use anyhow::{bail, Context};
+use chrono::{DateTime, Datelike, Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::Serialize;
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::fs;
+
+/// Name of the file inside the root directory holding the pseudonym key
+const KEY_FILE_NAME: &str = "pseudonym.key";
+
+/// Number of hex digits of the HMAC kept in a pseudonym
+const PSEUDONYM_LENGTH: usize = 12;
+
+/// Fields removed from every row
+const STRIPPED: &[&str] = &["email", "mobile", "date_birth", "pid_preformat"];
+
+/// Fields holding participant identifiers, replaced with pseudonyms
+const IDENTIFIERS: &[&str] = &["pid", "record_id"];
+
+/// Dates moved to the start of their week
+const DATES: &[&str] = &["date", "date_screening"];
+
+/// Makes participant data safe to give to users who can only see deidentified data.
+/// Identifiers are replaced with pseudonyms that are the same in every table and
+/// every request so that rows can still be joined.
+#[derive(Clone)]
+pub struct Pseudonyms {
+    key: [u8; 32],
+}
+
+/// Whether the user only gets to see deidentified participant data
+pub fn applies(user: &current::User) -> bool {
+    user.deidentified_export || !user.can(current::Capability::ViewIdentifiable)
+}
+
+impl Pseudonyms {
+    /// Uses the key kept in the root directory, makes one if there isn't any.
+    /// Pseudonyms change if the key file is removed.
+    pub fn open(dirs: &DbDirs) -> Result<Self> {
+        let path = dirs.root.join(KEY_FILE_NAME);
+        if !path.is_file() {
+            log::info!("creating pseudonym key at {:?}", path);
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill(&mut key);
+            fs::write(path.as_path(), hex::encode(key))
+                .context(format!("failed to write {:?}", path))?;
+        }
+        let contents =
+            fs::read_to_string(path.as_path()).context(format!("failed to read {:?}", path))?;
+        let bytes = hex::decode(contents.trim()).context(format!("{:?} is not hex", path))?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => Ok(Self { key }),
+            Err(_) => bail!("pseudonym key must be 32 bytes, got {}", bytes.len()),
+        }
+    }
+    /// HMAC of the identifier, can't be reversed without the key
+    pub fn pseudonym(&self, identifier: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key length works");
+        mac.update(identifier.as_bytes());
+        let mut pseudonym = hex::encode(mac.finalize().into_bytes());
+        pseudonym.truncate(PSEUDONYM_LENGTH);
+        pseudonym
+    }
+    /// Every row as plain JSON with the identifying fields stripped, coarsened
+    /// or replaced with pseudonyms
+    pub fn rows<T: Serialize>(&self, rows: &[T]) -> Result<Vec<serde_json::Value>> {
+        rows.iter()
+            .map(|row| {
+                let mut row = serde_json::to_value(row)?;
+                self.row(&mut row);
+                Ok(row)
+            })
+            .collect()
+    }
+    /// Works on any row, fields are recognized by their names
+    pub fn row(&self, row: &mut serde_json::Value) {
+        let row = match row.as_object_mut() {
+            Some(row) => row,
+            None => return,
+        };
+        for field in STRIPPED {
+            if let Some(value) = row.get_mut(*field) {
+                *value = serde_json::Value::Null;
+            }
+        }
+        for field in IDENTIFIERS {
+            if let Some(value) = row.get_mut(*field) {
+                self.identifier(value);
+            }
+        }
+        for field in DATES {
+            if let Some(value) = row.get_mut(*field) {
+                *value = start_of_week(value);
+            }
+        }
+        // Exact age together with the screening date gives away the date of birth
+        if let Some(age) = row.get_mut("age_recruitment") {
+            if let Some(years) = age.as_f64() {
+                *age = serde_json::Value::from(years.floor());
+            }
+        }
+    }
+    /// Sync record with the keys of the rows replaced with pseudonyms
+    pub fn sync_record(&self, record: &current::SyncRecord) -> current::SyncRecord {
+        let key_columns = db::table_specs()
+            .into_iter()
+            .find(|spec| spec.name == record.table)
+            .map(|spec| spec.key_columns)
+            .unwrap_or_default();
+        let keys = |keys: &[serde_json::Value]| {
+            keys.iter().map(|key| self.key(key_columns, key)).collect()
+        };
+        current::SyncRecord {
+            added: keys(&record.added),
+            removed: keys(&record.removed),
+            changed: keys(&record.changed),
+            ..record.clone()
+        }
+    }
+    /// Key of a row as `diff::row_key` makes it. Keyless tables are keyed by whole rows.
+    fn key(&self, key_columns: &[&str], key: &serde_json::Value) -> serde_json::Value {
+        let mut key = key.clone();
+        match (key_columns, &mut key) {
+            ([], row) => self.row(row),
+            ([column], value) if IDENTIFIERS.contains(column) => self.identifier(value),
+            (columns, serde_json::Value::Array(values)) => {
+                for (column, value) in columns.iter().zip(values.iter_mut()) {
+                    if IDENTIFIERS.contains(column) {
+                        self.identifier(value);
+                    }
+                }
+            }
+            _ => {}
+        }
+        key
+    }
+    fn identifier(&self, value: &mut serde_json::Value) {
+        if let Some(identifier) = value.as_str() {
+            *value = serde_json::Value::from(self.pseudonym(identifier));
+        }
+    }
+}
+
+/// Date moved to midnight of the Monday of its week, anything that isn't a date is left alone
+fn start_of_week(value: &serde_json::Value) -> serde_json::Value {
+    let date: DateTime<Utc> = match serde_json::from_value(value.clone()) {
+        Ok(date) => date,
+        Err(_) => return value.clone(),
+    };
+    let monday = date.date() - Duration::days(date.weekday().num_days_from_monday() as i64);
+    serde_json::to_value(monday.and_hms(0, 0, 0)).unwrap_or_else(|_| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pseudonyms(root: &std::path::Path) -> Pseudonyms {
+        Pseudonyms::open(&DbDirs::locate(root)).unwrap()
+    }
+
+    fn participant() -> current::Participant {
+        serde_json::from_value(json!({
+            "pid": "QJO-001",
+            "site": "Perth",
+            "email": "p1@example.com",
+            "mobile": "0400000000",
+            "date_screening": "2021-03-11T10:30:00Z",
+            "date_birth": "1990-05-02T00:00:00Z",
+            "age_recruitment": 30.85,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pseudonyms_are_stable() {
+        let root = tempfile::tempdir().unwrap();
+        let first = pseudonyms(root.path()).pseudonym("QJO-001");
+        assert_eq!(first.len(), PSEUDONYM_LENGTH);
+        assert_ne!(first, "QJO-001");
+        // Same key, same pseudonym in every call
+        assert_eq!(pseudonyms(root.path()).pseudonym("QJO-001"), first);
+        assert_ne!(pseudonyms(root.path()).pseudonym("QJO-002"), first);
+        // A different key gives different pseudonyms
+        let other = tempfile::tempdir().unwrap();
+        assert_ne!(pseudonyms(other.path()).pseudonym("QJO-001"), first);
+    }
+
+    #[test]
+    fn rows_are_stripped_and_coarsened() {
+        let root = tempfile::tempdir().unwrap();
+        let pseudonyms = pseudonyms(root.path());
+        let rows = pseudonyms.rows(&[participant()]).unwrap();
+        let row = &rows[0];
+        assert_eq!(row["pid"], json!(pseudonyms.pseudonym("QJO-001")));
+        assert_eq!(row["site"], json!("Perth"));
+        for field in &["email", "mobile", "date_birth"] {
+            assert!(row[field].is_null(), "{}", field);
+        }
+        // Thursday goes back to Monday
+        assert_eq!(row["date_screening"], json!("2021-03-08T00:00:00Z"));
+        assert_eq!(row["age_recruitment"], json!(30.0));
+    }
+
+    #[test]
+    fn identifiers_match_across_tables() {
+        let root = tempfile::tempdir().unwrap();
+        let pseudonyms = pseudonyms(root.path());
+        let schedule = current::Schedule {
+            pid: "QJO-001".to_string(),
+            year: 2021,
+            day: 7,
+            // Already a Monday
+            date: Some("2021-03-15T08:00:00Z".parse().unwrap()),
+        };
+        let schedule = &pseudonyms.rows(&[schedule]).unwrap()[0];
+        let participant = &pseudonyms.rows(&[participant()]).unwrap()[0];
+        assert_eq!(schedule["pid"], participant["pid"]);
+        assert_eq!(schedule["date"], json!("2021-03-15T00:00:00Z"));
+    }
+
+    #[test]
+    fn sync_record_keys_are_pseudonyms() {
+        let root = tempfile::tempdir().unwrap();
+        let pseudonyms = pseudonyms(root.path());
+        let record = current::SyncRecord {
+            timestamp: Utc::now(),
+            user: "admin@example.com".to_string(),
+            table: "Schedule".to_string(),
+            added: vec![json!(["QJO-001", 2021, 7])],
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        let record = pseudonyms.sync_record(&record);
+        assert_eq!(
+            record.added,
+            vec![json!([pseudonyms.pseudonym("QJO-001"), 2021, 7])]
+        );
+    }
+
+    #[test]
+    fn applies_without_identifiable_access() {
+        let mut user = current::User {
+            email: "site@example.com".to_string(),
+            access_group: current::AccessGroup::Unrestricted,
+            kind: current::UserKind::Manual,
+            deidentified_export: false,
+            capabilities: vec![current::Capability::ViewIdentifiable],
+        };
+        assert!(!applies(&user));
+        user.deidentified_export = true;
+        assert!(applies(&user));
+        user.deidentified_export = false;
+        user.capabilities.clear();
+        assert!(applies(&user));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{data::current, redcap};
+use serde_derive::Serialize;
 use thiserror::Error;
 use warp::http::StatusCode;
 
@@ -12,6 +13,27 @@ pub enum Conflict {
     WrongTokenKind(current::TokenKind),
     #[error("Unexpected redcap data: {0:#?}, expected: {1}")]
     UnexpectedRedcapData(serde_json::Value, String),
+    #[error("Transaction already open")]
+    TransactionAlreadyOpen,
+    #[error("No transaction open")]
+    NoTransactionOpen,
+    #[error("User {0} comes from REDCap, change their access there")]
+    RedcapUser(String),
+    #[error("Can't remove or demote the last admin")]
+    LastAdmin,
+}
+
+/// What the request refers to isn't there
+#[derive(Error, Debug)]
+pub enum NotFound {
+    #[error("No such snapshot: {0}")]
+    NoSuchSnapshot(String),
+    #[error("No such table: {0}")]
+    NoSuchTable(String),
+    #[error("No such user: {0}")]
+    NoSuchUser(String),
+    #[error("No token with id {0}")]
+    NoSuchTokenId(String),
 }
 
 #[derive(Error, Debug)]
@@ -26,6 +48,49 @@ pub enum Unauthorized {
     NoUserWithToken(String),
     #[error("Insufficient access: {0:?}, expected at least: {1:?}")]
     InsufficientAccess(current::AccessGroup, current::AccessGroup),
+    #[error("Token doesn't have the {0:?} scope")]
+    MissingScope(current::Scope),
+    #[error("User doesn't have the {0:?} capability")]
+    MissingCapability(current::Capability),
+    #[error("Only deidentified data is available to this user")]
+    DeidentifiedOnly,
+    #[error("Login code was already used or has expired")]
+    LoginCodeUsedOrExpired(String),
+    #[error("No token in the Authorization header")]
+    MissingToken,
+}
+
+/// The integrity check at startup quarantined some tables
+#[derive(Error, Debug)]
+pub enum Degraded {
+    #[error("Quarantined tables can't be written until restored from a snapshot: {0}")]
+    ReadOnly(String),
+    #[error("Table {0} is quarantined")]
+    Quarantined(String),
+}
+
+/// Someone is asking for too much too quickly
+#[derive(Error, Debug)]
+pub enum TooManyRequests {
+    #[error("Too many tokens sent to {0}, try again later")]
+    Email(String),
+    #[error("Too many token requests from {0}, try again later")]
+    Address(String),
+}
+
+/// The request can't be made sense of, whatever is in the db
+#[derive(Error, Debug)]
+pub enum BadRequest {
+    #[error("Invalid query string")]
+    Query,
+    #[error("{0}")]
+    Body(String),
+    #[error("Missing header: {0}")]
+    MissingHeader(String),
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+    #[error("Unrecognized token scope: {0}")]
+    UnknownScope(String),
 }
 
 #[derive(Error, Debug)]
@@ -38,22 +103,234 @@ pub enum RedcapExtraction {
     UnexpectedJsonValue(redcap::ExpectedJson, serde_json::Value),
 }
 
-#[derive(Debug)]
+impl Conflict {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::PrimaryKey(..) => "primary_key",
+            Self::ForeignKey(..) => "foreign_key",
+            Self::WrongTokenKind(_) => "wrong_token_kind",
+            Self::UnexpectedRedcapData(..) => "unexpected_redcap_data",
+            Self::TransactionAlreadyOpen => "transaction_already_open",
+            Self::NoTransactionOpen => "no_transaction_open",
+            Self::RedcapUser(_) => "redcap_user",
+            Self::LastAdmin => "last_admin",
+        }
+    }
+}
+
+impl NotFound {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoSuchSnapshot(_) => "no_such_snapshot",
+            Self::NoSuchTable(_) => "no_such_table",
+            Self::NoSuchUser(_) => "no_such_user",
+            Self::NoSuchTokenId(_) => "no_such_token_id",
+        }
+    }
+}
+
+impl Unauthorized {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::WrongAuthType(_) => "wrong_auth_type",
+            Self::NoSuchToken(_) => "no_such_token",
+            Self::TokenExpired(_) => "token_expired",
+            Self::NoUserWithToken(_) => "no_user_with_token",
+            Self::InsufficientAccess(..) => "insufficient_access",
+            Self::MissingScope(_) => "missing_scope",
+            Self::MissingCapability(_) => "missing_capability",
+            Self::DeidentifiedOnly => "deidentified_only",
+            Self::LoginCodeUsedOrExpired(_) => "login_code_used_or_expired",
+            Self::MissingToken => "missing_token",
+        }
+    }
+}
+
+impl Degraded {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ReadOnly(_) => "read_only",
+            Self::Quarantined(_) => "quarantined",
+        }
+    }
+}
+
+impl TooManyRequests {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Email(_) => "too_many_requests_for_email",
+            Self::Address(_) => "too_many_requests_from_address",
+        }
+    }
+}
+
+impl BadRequest {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Query => "bad_query",
+            Self::Body(_) => "bad_body",
+            Self::MissingHeader(_) => "missing_header",
+            Self::InvalidHeader(_) => "invalid_header",
+            Self::UnknownScope(_) => "unknown_scope",
+        }
+    }
+}
+
+impl RedcapExtraction {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::FieldNotFound(_) => "redcap_field_not_found",
+            Self::ExtractionFailed(..) => "redcap_extraction_failed",
+            Self::UnexpectedJsonValue(..) => "redcap_unexpected_json_value",
+        }
+    }
+}
+
+/// RFC 7807 problem details, what every error is replied with
+#[derive(Debug, Serialize, schemars::JsonSchema)]
 pub struct ApiProblem {
+    /// Always `about:blank`, problems are told apart by `code`
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    /// Reason phrase of the status
+    pub title: &'static str,
+    #[serde(with = "status_code")]
+    #[schemars(with = "u16")]
     pub status: StatusCode,
     pub detail: String,
+    /// Machine-readable, one for each error variant
+    pub code: &'static str,
 }
 
 impl warp::reject::Reject for ApiProblem {}
 
+impl ApiProblem {
+    pub fn new(status: StatusCode, code: &'static str, detail: String) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown"),
+            status,
+            detail,
+            code,
+        }
+    }
+}
+
+mod status_code {
+    use warp::http::StatusCode;
+    pub fn serialize<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u16(status.as_u16())
+    }
+}
+
 pub fn from_anyhow(err: anyhow::Error) -> ApiProblem {
-    let status = match &err {
-        _ if err.is::<Unauthorized>() => StatusCode::UNAUTHORIZED,
-        _ if err.is::<Conflict>() => StatusCode::CONFLICT,
-        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    let (status, code) = if let Some(e) = err.downcast_ref::<Unauthorized>() {
+        (StatusCode::UNAUTHORIZED, e.code())
+    } else if let Some(e) = err.downcast_ref::<Conflict>() {
+        (StatusCode::CONFLICT, e.code())
+    } else if let Some(e) = err.downcast_ref::<NotFound>() {
+        (StatusCode::NOT_FOUND, e.code())
+    } else if let Some(e) = err.downcast_ref::<Degraded>() {
+        (StatusCode::SERVICE_UNAVAILABLE, e.code())
+    } else if let Some(e) = err.downcast_ref::<TooManyRequests>() {
+        (StatusCode::TOO_MANY_REQUESTS, e.code())
+    } else if let Some(e) = err.downcast_ref::<BadRequest>() {
+        (StatusCode::BAD_REQUEST, e.code())
+    } else if let Some(e) = err.downcast_ref::<RedcapExtraction>() {
+        (StatusCode::INTERNAL_SERVER_ERROR, e.code())
+    } else {
+        // Could be anything from file paths to parser errors, only logged
+        log::error!("internal error: {:#}", err);
+        return ApiProblem::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal error".to_string(),
+        );
     };
-    ApiProblem {
-        status,
-        detail: format!("{}", err),
+    ApiProblem::new(status, code, format!("{}", err))
+}
+
+/// Problem for rejections made by warp itself rather than by the handlers
+pub fn from_rejection(err: &warp::Rejection) -> ApiProblem {
+    use warp::reject;
+    if err.find::<reject::InvalidQuery>().is_some() {
+        from_anyhow(BadRequest::Query.into())
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        from_anyhow(BadRequest::Body(e.to_string()).into())
+    } else if let Some(e) = err.find::<reject::MissingHeader>() {
+        match e.name() {
+            "Authorization" => from_anyhow(Unauthorized::MissingToken.into()),
+            name => from_anyhow(BadRequest::MissingHeader(name.to_string()).into()),
+        }
+    } else if let Some(e) = err.find::<reject::InvalidHeader>() {
+        from_anyhow(BadRequest::InvalidHeader(e.name().to_string()).into())
+    } else if let Some(e) = err.find::<reject::UnsupportedMediaType>() {
+        ApiProblem::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            e.to_string(),
+        )
+    } else if let Some(e) = err.find::<reject::PayloadTooLarge>() {
+        ApiProblem::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            e.to_string(),
+        )
+    } else if let Some(e) = err.find::<reject::MethodNotAllowed>() {
+        ApiProblem::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            e.to_string(),
+        )
+    } else if err.is_not_found() {
+        ApiProblem::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "No such route".to_string(),
+        )
+    } else {
+        // Only logged, the rejection may tell more about the server than
+        // clients should know
+        log::error!("unhandled rejection: {:?}", err);
+        ApiProblem::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal error".to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Secret;
+
+    impl warp::reject::Reject for Secret {}
+
+    #[test]
+    fn unknown_error_is_not_detailed() {
+        let e = anyhow::Error::new(std::io::Error::other("/root/current/User.json"))
+            .context("failed to read");
+        let problem = from_anyhow(e);
+        assert_eq!(problem.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(problem.code, "internal");
+        assert_eq!(problem.detail, "Internal error");
+    }
+
+    #[test]
+    fn missing_things_are_not_found() {
+        let problem = from_anyhow(NotFound::NoSuchSnapshot("nope".to_string()).into());
+        assert_eq!(problem.status, StatusCode::NOT_FOUND);
+        assert_eq!(problem.code, "no_such_snapshot");
+        assert_eq!(problem.detail, "No such snapshot: nope");
+    }
+
+    #[test]
+    fn unhandled_rejection_is_not_detailed() {
+        let problem = from_rejection(&warp::reject::custom(Secret));
+        assert_eq!(problem.code, "internal");
+        assert_eq!(problem.detail, "Internal error");
     }
 }
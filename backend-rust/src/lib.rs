@@ -7,8 +7,11 @@ pub mod api;
 pub mod auth;
 pub mod data;
 pub mod db;
+pub mod deidentify;
 pub mod email;
 pub mod error;
+pub mod openapi;
+pub mod ratelimit;
 pub mod redcap;
 
 pub type Result<T> = anyhow::Result<T>;
@@ -17,12 +20,41 @@ pub type Result<T> = anyhow::Result<T>;
 pub struct Opt {
     /// Database root directory
     pub root_dir: PathBuf,
+    /// How the database is kept on disk
+    #[serde(default)]
+    pub storage: db::storage::StorageKind,
+    /// Key for the `EncryptedJson` storage, 64 hex digits
+    pub storage_key: Option<String>,
+    /// Key the data was encrypted with before `storage_key`, only used by `--reencrypt`.
+    /// Leave out to encrypt data that isn't encrypted yet.
+    pub storage_previous_key: Option<String>,
     /// Port to listen to
     pub port: u16,
+    /// Secret the token hashes are keyed with, 64 or more hex digits. Tokens are
+    /// hashed without it if it's missing. Changing it invalidates every token.
+    pub auth_pepper: Option<String>,
     /// Auth token length
     pub auth_token_length: usize,
     /// Auth token days to live
     pub auth_token_days_to_live: i64,
+    /// How many tokens can be sent to one email address in an hour
+    #[serde(default = "default_auth_token_send_per_email")]
+    pub auth_token_send_per_email: usize,
+    /// How many tokens can be requested from one IP address in an hour
+    #[serde(default = "default_auth_token_send_per_address")]
+    pub auth_token_send_per_address: usize,
+    /// Addresses of the reverse proxies whose `X-Forwarded-For` header is
+    /// believed, requests from anywhere else are limited by their own address
+    #[serde(default)]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+    /// Minutes the emailed login codes can be exchanged for a session within
+    #[serde(default = "default_auth_login_code_minutes")]
+    pub auth_login_code_minutes: i64,
+    /// How many unexpired session tokens a user can have, the oldest ones are
+    /// removed to make room for new ones. The same goes for login codes that
+    /// haven't been used yet.
+    #[serde(default = "default_auth_max_sessions")]
+    pub auth_max_sessions: usize,
     /// Default admin email
     pub default_admin_email: String,
     /// Email host
@@ -39,6 +71,38 @@ pub struct Opt {
     pub redcap_token_2021: String,
     /// Redcap API URL
     pub redcap_api_url: String,
+    /// Hours between removals of expired tokens, 0 turns them off
+    #[serde(default = "default_token_purge_interval_hours")]
+    pub token_purge_interval_hours: u64,
+    /// Number of most recent snapshots to keep
+    #[serde(default = "default_snapshot_keep_last")]
+    pub snapshot_keep_last: usize,
+    /// Remove snapshots older than this many days
+    pub snapshot_max_age_days: Option<i64>,
+}
+
+fn default_auth_token_send_per_email() -> usize {
+    5
+}
+
+fn default_auth_token_send_per_address() -> usize {
+    30
+}
+
+fn default_auth_login_code_minutes() -> i64 {
+    15
+}
+
+fn default_auth_max_sessions() -> usize {
+    5
+}
+
+fn default_token_purge_interval_hours() -> u64 {
+    24
+}
+
+fn default_snapshot_keep_last() -> usize {
+    db::snapshot::Retention::default().keep_last
 }
 
 impl Opt {
@@ -51,4 +115,10 @@ impl Opt {
         ))?;
         Ok(config_opts)
     }
+    pub fn token_hasher(&self) -> Result<auth::TokenHasher> {
+        auth::TokenHasher::new(self.auth_pepper.as_deref())
+    }
+    pub fn storage_config(&self) -> Result<db::storage::StorageConfig> {
+        db::storage::StorageConfig::new(self.storage, self.storage_key.as_deref())
+    }
 }
@@ -1,16 +1,59 @@
-use backend_rust::{api, db::Db, email::Mailer, Opt, Result};
+use anyhow::bail;
+use backend_rust::{
+    api,
+    db::{self, encrypted, migration, shared::SharedDb, Db},
+    email::Mailer,
+    Opt, Result,
+};
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init();
 
     let opt = Opt::new()?;
+    let storage_config = opt.storage_config()?;
 
-    let db = Db::new(opt.root_dir.as_path(), opt.default_admin_email.as_str())?;
+    // Rewrite the data with the current storage key, the previous key is only needed
+    // while the data is encrypted with it
+    if std::env::args().any(|a| a == "--reencrypt") {
+        let to = match storage_config.key {
+            Some(key) => key,
+            None => bail!("--reencrypt needs EncryptedJson storage with a storage key"),
+        };
+        let from = opt
+            .storage_previous_key
+            .as_deref()
+            .map(encrypted::Key::from_hex)
+            .transpose()?;
+        encrypted::reencrypt(opt.root_dir.as_path(), from, to)?;
+        return Ok(());
+    }
+
+    // Report what the pending migrations would change and leave the data alone
+    if std::env::args().any(|a| a == "--migrate-dry-run") {
+        let reports = migration::dry_run(opt.root_dir.as_path(), storage_config)?;
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+
+    if opt.auth_pepper.is_none() {
+        log::warn!("no auth_pepper in the config, token hashes aren't peppered");
+    }
+
+    let mut db = Db::new(
+        opt.root_dir.as_path(),
+        storage_config,
+        opt.default_admin_email.as_str(),
+        opt.token_hasher()?,
+    )?;
+    db.snapshot_retention = db::snapshot::Retention {
+        keep_last: opt.snapshot_keep_last,
+        max_age_days: opt.snapshot_max_age_days,
+    };
     let email_cred = Credentials::new(opt.email_username.clone(), opt.email_password.clone());
     let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(opt.email_host.as_str())?
         .credentials(email_cred)
@@ -20,10 +63,17 @@ async fn main() -> Result<()> {
         transport,
     };
 
-    let db_ref = Arc::new(Mutex::new(db));
+    let db_ref = Arc::new(SharedDb::new(db));
     let opt_ref = Arc::new(opt);
     let mailer_ref = Arc::new(mailer);
 
+    if opt_ref.token_purge_interval_hours > 0 {
+        tokio::spawn(purge_tokens(
+            db_ref.clone(),
+            Duration::from_secs(opt_ref.token_purge_interval_hours * 60 * 60),
+        ));
+    }
+
     let routes = api::routes(db_ref.clone(), opt_ref.clone(), mailer_ref);
 
     warp::serve(routes)
@@ -32,3 +82,19 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Removes expired tokens every `period`, starting right away
+async fn purge_tokens(db: Arc<SharedDb>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match db.write().await.purge_tokens() {
+            Ok(purge) => log::info!(
+                "purged {} expired and {} orphaned tokens",
+                purge.expired,
+                purge.orphaned
+            ),
+            Err(e) => log::error!("token purge failed: {:#}", e),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::api::ROUTES;
+use crate::error::ApiProblem;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// Makes a schema once there's a generator to make it with
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// What a route replies with when it succeeds
+enum Success {
+    NoContent,
+    Json(SchemaFn),
+    /// A new token
+    Text,
+}
+
+/// One method of one route, see `api::ROUTES`
+pub struct Route {
+    pub method: &'static str,
+    /// Relative to `/api`, parameters are in braces
+    pub path: &'static str,
+    summary: &'static str,
+    /// Doesn't need a token
+    public: bool,
+    query: Option<SchemaFn>,
+    body: Option<SchemaFn>,
+    success: Success,
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    T::json_schema(gen)
+}
+
+fn subschema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+impl Route {
+    pub const fn new(method: &'static str, path: &'static str, summary: &'static str) -> Self {
+        Self {
+            method,
+            path,
+            summary,
+            public: false,
+            query: None,
+            body: None,
+            success: Success::NoContent,
+        }
+    }
+    pub const fn public(mut self) -> Self {
+        self.public = true;
+        self
+    }
+    /// Every field of `T` is a query parameter
+    pub const fn query<T: JsonSchema>(mut self) -> Self {
+        self.query = Some(schema::<T>);
+        self
+    }
+    pub const fn body<T: JsonSchema>(mut self) -> Self {
+        self.body = Some(subschema::<T>);
+        self
+    }
+    pub const fn json<T: JsonSchema>(mut self) -> Self {
+        self.success = Success::Json(subschema::<T>);
+        self
+    }
+    pub const fn text(mut self) -> Self {
+        self.success = Success::Text;
+        self
+    }
+    fn parameters(&self, gen: &mut SchemaGenerator) -> Vec<Value> {
+        let mut parameters: Vec<Value> = self
+            .path
+            .split('/')
+            .filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": {"type": "string"},
+                })
+            })
+            .collect();
+        let query = match self.query.map(|query| query(gen)) {
+            Some(Schema::Object(query)) => query,
+            _ => return parameters,
+        };
+        if let Some(object) = &query.object {
+            for (name, schema) in &object.properties {
+                let description = match schema {
+                    Schema::Object(o) => o.metadata.as_ref().and_then(|m| m.description.clone()),
+                    Schema::Bool(_) => None,
+                };
+                let mut parameter = json!({
+                    "name": name,
+                    "in": "query",
+                    "required": object.required.contains(name),
+                    "schema": schema,
+                });
+                if let Some(description) = description {
+                    parameter["description"] = Value::from(description);
+                }
+                parameters.push(parameter);
+            }
+        }
+        parameters
+    }
+    fn to_json(&self, gen: &mut SchemaGenerator, problem: &Schema) -> Value {
+        let success = match &self.success {
+            Success::NoContent => json!({"204": {"description": "Done"}}),
+            Success::Json(schema) => json!({"200": {
+                "description": "Done",
+                "content": {"application/json": {"schema": schema(gen)}},
+            }}),
+            Success::Text => json!({"200": {
+                "description": "Token",
+                "content": {"text/plain": {"schema": {"type": "string"}}},
+            }}),
+        };
+        let mut responses = success;
+        responses["default"] = json!({
+            "description": "Problem",
+            "content": {"application/problem+json": {"schema": problem}},
+        });
+        let mut operation = json!({
+            "summary": self.summary,
+            "responses": responses,
+        });
+        let parameters = self.parameters(gen);
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::from(parameters);
+        }
+        if let Some(body) = self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": body(gen)}},
+            });
+        }
+        if self.public {
+            operation["security"] = json!([]);
+        }
+        operation
+    }
+}
+
+/// OpenAPI 3 document describing every route, served at `/api/openapi.json`
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let problem = gen.subschema_for::<ApiProblem>();
+    let mut paths = Map::new();
+    for route in ROUTES {
+        paths.entry(route.path).or_insert_with(|| json!({}))[route.method] =
+            route.to_json(&mut gen, &problem);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "HCW study",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": "/api"}],
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {"token": {"type": "http", "scheme": "bearer"}},
+        },
+        "security": [{"token": []}],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_route_is_described() {
+        let spec = spec();
+        for route in ROUTES {
+            assert!(spec["paths"][route.path][route.method].is_object());
+        }
+        // Only public routes leave out the bearer token
+        let exchange = &spec["paths"]["/auth/token/exchange"]["post"];
+        assert!(exchange.get("security").is_none());
+        assert_eq!(
+            spec["paths"]["/auth/token/send"]["post"]["security"],
+            json!([])
+        );
+    }
+
+    #[test]
+    fn every_reference_resolves() {
+        fn check(value: &Value, schemas: &Map<String, Value>) {
+            match value {
+                Value::Object(o) => {
+                    if let Some(Value::String(r)) = o.get("$ref") {
+                        let name = r.strip_prefix("#/components/schemas/").unwrap();
+                        assert!(schemas.contains_key(name), "unresolved {}", r);
+                    }
+                    o.values().for_each(|v| check(v, schemas));
+                }
+                Value::Array(a) => a.iter().for_each(|v| check(v, schemas)),
+                _ => {}
+            }
+        }
+        let spec = spec();
+        check(&spec, spec["components"]["schemas"].as_object().unwrap());
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Allows up to `max` hits per key within any `window`. Only kept in memory,
+/// starts over when the server restarts.
+pub struct Limiter {
+    max: usize,
+    window: Duration,
+    /// When each key was hit within the last window, oldest first
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Limiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Records the hit if the key is under the limit, returns whether it was
+    pub fn hit(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        // Keys that haven't been hit in a while would otherwise pile up
+        hits.retain(|_, key_hits| {
+            while key_hits
+                .front()
+                .is_some_and(|hit| now.duration_since(*hit) >= self.window)
+            {
+                key_hits.pop_front();
+            }
+            !key_hits.is_empty()
+        });
+        let key_hits = hits.entry(key.to_string()).or_default();
+        if key_hits.len() >= self.max {
+            return false;
+        }
+        key_hits.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_key() {
+        let limiter = Limiter::new(2, Duration::from_secs(3600));
+        assert!(limiter.hit("a"));
+        assert!(limiter.hit("a"));
+        assert!(!limiter.hit("a"));
+        assert!(limiter.hit("b"));
+    }
+
+    #[test]
+    fn window_expires() {
+        let limiter = Limiter::new(1, Duration::from_millis(50));
+        assert!(limiter.hit("a"));
+        assert!(!limiter.hit("a"));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.hit("a"));
+    }
+
+    #[test]
+    fn prunes_idle_keys() {
+        let limiter = Limiter::new(1, Duration::from_millis(50));
+        assert!(limiter.hit("a"));
+        assert!(limiter.hit("b"));
+        assert_eq!(limiter.hits.lock().unwrap().len(), 2);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.hit("c"));
+        assert_eq!(
+            limiter.hits.lock().unwrap().keys().collect::<Vec<_>>(),
+            vec!["c"]
+        );
+    }
+}
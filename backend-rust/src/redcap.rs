@@ -128,7 +128,6 @@ impl TryGet for serde_json::Map<String, serde_json::Value> {
 trait TryAs {
     fn error(&self, expected: ExpectedJson) -> anyhow::Error;
     fn try_as_object(&self) -> Result<&serde_json::Map<String, serde_json::Value>>;
-    fn try_as_array(&self) -> Result<Vec<serde_json::Value>>;
     fn try_as_str(&self) -> Result<&str>;
     fn try_as_str_or_null(&self) -> Result<Option<&str>>;
     fn try_as_i64(&self) -> Result<i64>;
@@ -159,7 +158,6 @@ trait TryAs {
         var_name: &str,
     ) -> Result<current::VaccinationHistory>;
     fn try_as_schedule(&self, year: u32, day: u32, var_name: &str) -> Result<current::Schedule>;
-    fn try_as_swab_result(&self) -> Result<current::SwabResult>;
     fn try_as_weekly_survey(&self, pid: &str, year: u32) -> Result<current::WeeklySurvey>;
     fn try_as_withdrawn(&self, pid: &str, year: u32) -> Result<current::Withdrawn>;
     fn try_as_study_group_or_null_flu_paper(&self) -> Result<Option<current::StudyGroup>>;
@@ -189,12 +187,6 @@ impl TryAs for serde_json::Value {
             None => Err(self.error(ExpectedJson::Object)),
         }
     }
-    fn try_as_array(&self) -> Result<Vec<serde_json::Value>> {
-        match self.as_array() {
-            Some(v) => Ok(v.to_vec()),
-            None => Err(self.error(ExpectedJson::Array)),
-        }
-    }
     fn try_as_str(&self) -> Result<&str> {
         match self.as_str() {
             Some(v) => Ok(v),
@@ -203,7 +195,7 @@ impl TryAs for serde_json::Value {
     }
     fn try_as_str_or_null(&self) -> Result<Option<&str>> {
         match self.as_str() {
-            Some(s) if s.is_empty() => Ok(None),
+            Some("") => Ok(None),
             Some(v) => Ok(Some(v)),
             None => match self.as_null() {
                 Some(()) => Ok(None),
@@ -235,7 +227,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::RealOrNull)),
                 },
             },
@@ -263,7 +255,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::BooleanOrNull)),
                 },
             },
@@ -281,7 +273,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(s) if s.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::DateOrNull)),
                 },
             },
@@ -310,11 +302,14 @@ impl TryAs for serde_json::Value {
     }
     fn try_as_user(&self) -> Result<current::User> {
         let v = self.try_as_object()?;
+        let access_group = v.try_get("data_access_group")?.try_as_access_group()?;
+        let deidentified_export = v.try_get("data_export")?.try_as_i64()? == 2;
         let user = current::User {
             email: v.try_get("email")?.try_as_str()?.to_lowercase(),
-            access_group: v.try_get("data_access_group")?.try_as_access_group()?,
+            access_group,
             kind: current::UserKind::Redcap,
-            deidentified_export: v.try_get("data_export")?.try_as_i64()? == 2,
+            deidentified_export,
+            capabilities: current::Capability::defaults(access_group, deidentified_export),
         };
         Ok(user)
     }
@@ -337,14 +332,14 @@ impl TryAs for serde_json::Value {
                     if c.is_alphabetic() {
                         site_part.push(c);
                     } else {
-                        if c.is_digit(10) {
+                        if c.is_ascii_digit() {
                             number_part.push(c);
                         }
                         state = State::Transition;
                     }
                 }
                 State::Transition => {
-                    if c.is_digit(10) {
+                    if c.is_ascii_digit() {
                         number_part.push(c);
                         state = State::Second
                     } else {
@@ -352,7 +347,7 @@ impl TryAs for serde_json::Value {
                     }
                 }
                 State::Second => {
-                    if c.is_digit(10) {
+                    if c.is_ascii_digit() {
                         number_part.push(c);
                     } else {
                         break;
@@ -372,7 +367,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::PidOrNull)),
                 },
             },
@@ -395,7 +390,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::GenderOrNull)),
                 },
             },
@@ -430,7 +425,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::OccupationOrNull)),
                 },
             },
@@ -456,17 +451,15 @@ impl TryAs for serde_json::Value {
             date_screening,
             date_birth,
             age_recruitment: date_birth
-                .map(|date_birth| {
+                .and_then(|date_birth| {
                     date_screening.map(|date_screening| {
                         (date_screening - date_birth).num_days() as f64 / 365.25
                     })
-                })
-                .flatten(),
+                }),
             height,
             weight,
             bmi: height
-                .map(|height| weight.map(|weight| weight / (height * height / 10000f64)))
-                .flatten(),
+                .and_then(|height| weight.map(|weight| weight / (height * height / 10000f64))),
             gender: v.try_get("a1_gender")?.try_as_gender_or_null()?,
             occupation: v
                 .try_get("c3_occupation")?
@@ -491,7 +484,7 @@ impl TryAs for serde_json::Value {
             Err(_) => match self.as_null() {
                 Some(()) => Ok(None),
                 None => match self.as_str() {
-                    Some(v) if v.is_empty() => Ok(None),
+                    Some("") => Ok(None),
                     _ => Err(self.error(ExpectedJson::VaccinationStatusOrNull)),
                 },
             },
@@ -520,13 +513,6 @@ impl TryAs for serde_json::Value {
         };
         Ok(schedule)
     }
-    fn try_as_swab_result(&self) -> Result<current::SwabResult> {
-        let res = match self.try_as_str()? {
-            "a" => current::SwabResult::Adenovirus,
-            _ => return Err(self.error(ExpectedJson::SwabResult)),
-        };
-        Ok(res)
-    }
     fn try_as_weekly_survey(&self, pid: &str, year: u32) -> Result<current::WeeklySurvey> {
         let v = self.try_as_object()?;
         let weekly_survey = current::WeeklySurvey {
@@ -873,7 +859,7 @@ pub async fn export_participants(opt: &Opt) -> Result<Vec<current::Participant>>
                 log_full_error(
                     "Failed parse participant",
                     e.to_string(),
-                    &redcap_participant,
+                    redcap_participant,
                 );
                 return;
             }
@@ -934,12 +920,12 @@ pub async fn export_record_id_pid_map(opt: &Opt) -> Result<HashMap<String, Strin
     let mut parsed = 0;
     let mut added = 0;
     for redcap_vaccination in map2020.iter().chain(map2021.iter()) {
-        match add_to_pid_map(&mut pid_map, &redcap_vaccination) {
+        match add_to_pid_map(&mut pid_map, redcap_vaccination) {
             Ok(i) => {
                 added += i;
                 parsed += 1;
             }
-            Err(e) => handle_pid_map_error(e, &redcap_vaccination),
+            Err(e) => handle_pid_map_error(e, redcap_vaccination),
         }
     }
 
@@ -956,7 +942,7 @@ pub async fn export_vaccination_history(
     opt: &Opt,
     pid_map: &HashMap<String, String>,
 ) -> Result<Vec<current::VaccinationHistory>> {
-    let years = (2015u32..=2020u32).into_iter().collect::<Vec<u32>>();
+    let years = (2015u32..=2020u32).collect::<Vec<u32>>();
     let years_var_names = years
         .iter()
         .map(|y| format!("vac_{}", y))
@@ -1385,7 +1371,7 @@ pub async fn export_withdrawn(
     let mut counts = ExtractionCounts::new(&["parsed", "added", "no matching pid"]);
 
     let mut add = |v: &serde_json::Value, year: u32| {
-        let record_id = match pull_record_id(&v) {
+        let record_id = match pull_record_id(v) {
             Ok(s) => s,
             Err(e) => {
                 log_full_error(
@@ -54,6 +54,8 @@ export type DecodeError = {
 export type BackendError = {
   type: "backend"
   status: number
+  /** Machine-readable code from the problem+json body */
+  code: string | null
   message: string
 }
 
@@ -85,6 +87,24 @@ export type ApiResult<T> = {
   result: ApiReturn<T> | null
 }
 
+/** Errors come as RFC 7807 problem+json */
+export async function backendError(res: any): Promise<BackendError> {
+  const status = res.status
+  const text = await res.text()
+  if (res.headers.get("Content-Type") === "application/problem+json") {
+    try {
+      const problem = JSON.parse(text)
+      return {
+        status,
+        type: "backend",
+        code: problem.code,
+        message: problem.detail,
+      }
+    } catch (e) {}
+  }
+  return { status, type: "backend", code: null, message: text }
+}
+
 export async function apiReq<T>({
   url,
   token = null,
@@ -111,10 +131,7 @@ export async function apiReq<T>({
 
   const status = res.status
   if (status !== successCode) {
-    return {
-      data,
-      error: { status, type: "backend", message: await res.text() },
-    }
+    return { data, error: await backendError(res) }
   }
 
   switch (expectContent) {